@@ -0,0 +1,13 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+// Puts memory.x where the linker finds it ahead of any other, so the flash
+// region stops short of the settings sector
+fn main() {
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::copy("memory.x", out.join("memory.x")).unwrap();
+    println!("cargo:rustc-link-search={}", out.display());
+    println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -0,0 +1,16 @@
+/* STM32F733ZE */
+MEMORY
+{
+  /* The last 128K sector is kept for settings, see src/flash.rs */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 384K
+  SETTINGS : ORIGIN = 0x08060000, LENGTH = 128K
+  /* DTCM, SRAM1 and SRAM2 are contiguous */
+  RAM : ORIGIN = 0x20000000, LENGTH = 256K
+}
+
+/* src/flash.rs erases sector 7 by number, so it has to stay where it is and
+   the image has to stay out of it */
+ASSERT(ORIGIN(SETTINGS) == 0x08060000 && LENGTH(SETTINGS) == 128K,
+       "settings must be flash sector 7");
+ASSERT(ORIGIN(FLASH) + LENGTH(FLASH) <= ORIGIN(SETTINGS),
+       "firmware overlaps the settings sector");
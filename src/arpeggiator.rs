@@ -0,0 +1,380 @@
+use crate::clock::{ClockSync, Division, PPQN};
+use crate::control::CONTROL_HZ;
+use crate::instrument::VOICES;
+use crate::lfo::Random;
+
+// Notes the arpeggiator can hold at once
+const HELD: usize = 16;
+const MAX_OCTAVES: u8 = 4;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ArpMode {
+    Off,
+    Up,
+    Down,
+    UpDown,
+    AsPlayed,
+    Random,
+}
+
+impl ArpMode {
+    fn step(self, steps: i32) -> Self {
+        const MODES: [ArpMode; 6] = [
+            ArpMode::Off,
+            ArpMode::Up,
+            ArpMode::Down,
+            ArpMode::UpDown,
+            ArpMode::AsPlayed,
+            ArpMode::Random,
+        ];
+        let index = MODES.iter().position(|mode| *mode == self).unwrap_or(0) as i32;
+        MODES[(index + steps).rem_euclid(MODES.len() as i32) as usize]
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ArpParam {
+    Mode,
+    Octaves,
+    Division,
+    Gate,
+    Latch,
+    Spread,
+}
+
+impl ArpParam {
+    pub fn next(self) -> Self {
+        match self {
+            ArpParam::Mode => ArpParam::Octaves,
+            ArpParam::Octaves => ArpParam::Division,
+            ArpParam::Division => ArpParam::Gate,
+            ArpParam::Gate => ArpParam::Latch,
+            ArpParam::Latch => ArpParam::Spread,
+            ArpParam::Spread => ArpParam::Mode,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ArpParams {
+    pub mode: ArpMode,
+    pub octaves: u8,
+    pub division: Division,
+    // percent of the step the gate stays up
+    pub gate: u8,
+    // notes carry on after the keys come up, until the next chord
+    pub latch: bool,
+    // steps go round the voices in turn instead of through the allocator
+    pub spread: bool,
+}
+
+impl ArpParams {
+    pub fn new() -> Self {
+        Self {
+            mode: ArpMode::Off,
+            octaves: 1,
+            division: Division::PPQN_4,
+            gate: 50,
+            latch: false,
+            spread: false,
+        }
+    }
+
+    // Encoder turns, switches flip on any turn
+    pub fn adjust(&mut self, param: ArpParam, steps: i32) {
+        match param {
+            ArpParam::Mode => self.mode = self.mode.step(steps),
+            ArpParam::Octaves => {
+                self.octaves = (self.octaves as i32 + steps).clamp(1, MAX_OCTAVES as i32) as u8
+            }
+            ArpParam::Division => self.division = self.division.step(steps),
+            ArpParam::Gate => self.gate = (self.gate as i32 + steps * 5).clamp(5, 100) as u8,
+            ArpParam::Latch => self.latch = !self.latch,
+            ArpParam::Spread => self.spread = !self.spread,
+        }
+    }
+}
+
+impl Default for ArpParams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ArpNote {
+    pub note: u8,
+    pub velocity: u8,
+    // the voice to play it on when spreading
+    pub voice: Option<usize>,
+}
+
+// What a control tick asks of the voices, the last note ends before the
+// next starts
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct ArpStep {
+    pub off: Option<u8>,
+    pub on: Option<ArpNote>,
+}
+
+// Plays the held notes one at a time on the divisions of the followed
+// clock, MIDI or internal. It sits in front of the voice allocator, taking
+// the notes while it's on and handing back one step at a time.
+pub struct Arpeggiator {
+    params: ArpParams,
+    channel: u8,
+    // in the order they were played
+    notes: [u8; HELD],
+    velocities: [u8; HELD],
+    len: usize,
+    // keys actually down, a latched chord is replaced once this reaches zero
+    down: usize,
+    position: u32,
+    sounding: Option<u8>,
+    // control ticks since the last step
+    since_step: u32,
+    random: Random,
+}
+
+impl Arpeggiator {
+    pub fn new() -> Self {
+        Self {
+            params: ArpParams::new(),
+            channel: 0,
+            notes: [0; HELD],
+            velocities: [0; HELD],
+            len: 0,
+            down: 0,
+            position: 0,
+            sounding: None,
+            since_step: 0,
+            random: Random::new(6),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.params.mode != ArpMode::Off
+    }
+
+    pub fn channel(&self) -> u8 {
+        self.channel
+    }
+
+    pub fn params(&self) -> ArpParams {
+        self.params
+    }
+
+    // Returns the note to stop if the change silences the arpeggiator
+    pub fn set_params(&mut self, params: ArpParams) -> Option<u8> {
+        self.params = params;
+        if !params.latch && self.down == 0 {
+            self.len = 0;
+        }
+        if self.is_enabled() {
+            None
+        } else {
+            self.len = 0;
+            self.down = 0;
+            self.sounding.take()
+        }
+    }
+
+    pub fn note_on(&mut self, channel: u8, note: u8, velocity: u8) {
+        if self.params.latch && self.down == 0 {
+            self.len = 0;
+        }
+        self.down += 1;
+        self.channel = channel;
+        if self.notes[..self.len].contains(&note) {
+            return;
+        }
+        if self.len == HELD {
+            self.remove(0);
+        }
+        self.notes[self.len] = note;
+        self.velocities[self.len] = velocity;
+        self.len += 1;
+    }
+
+    pub fn note_off(&mut self, note: u8) {
+        self.down = self.down.saturating_sub(1);
+        if !self.params.latch {
+            if let Some(index) = self.notes[..self.len].iter().position(|n| *n == note) {
+                self.remove(index);
+            }
+        }
+    }
+
+    // Panic and all notes off
+    pub fn clear(&mut self) -> Option<u8> {
+        self.len = 0;
+        self.down = 0;
+        self.sounding.take()
+    }
+
+    fn remove(&mut self, index: usize) {
+        self.notes.copy_within(index + 1..self.len, index);
+        self.velocities.copy_within(index + 1..self.len, index);
+        self.len -= 1;
+    }
+
+    // The held notes sorted, spread over the octaves
+    fn ascending(&self, index: usize) -> (u8, u8) {
+        let mut order = [0usize; HELD];
+        for (position, slot) in order[..self.len].iter_mut().enumerate() {
+            *slot = position;
+        }
+        order[..self.len].sort_unstable_by_key(|index| self.notes[*index]);
+        let held = order[index % self.len];
+        let octave = (index / self.len) as u8;
+        (
+            self.notes[held].saturating_add(octave * 12),
+            self.velocities[held],
+        )
+    }
+
+    fn as_played(&self, index: usize) -> (u8, u8) {
+        let octave = (index / self.len) as u8;
+        let held = index % self.len;
+        (
+            self.notes[held].saturating_add(octave * 12),
+            self.velocities[held],
+        )
+    }
+
+    // The note for a step of the song, so the pattern stays in place when
+    // the host relocates or loops
+    fn note(&mut self, step: usize) -> (u8, u8) {
+        let count = self.len * self.params.octaves.clamp(1, MAX_OCTAVES) as usize;
+        let (note, velocity) = match self.params.mode {
+            ArpMode::Down => self.ascending(count - 1 - step % count),
+            ArpMode::UpDown if count > 1 => {
+                // the top and bottom notes aren't repeated on the turn
+                let index = step % (2 * count - 2);
+                self.ascending(if index < count {
+                    index
+                } else {
+                    2 * count - 2 - index
+                })
+            }
+            ArpMode::AsPlayed => self.as_played(step % count),
+            ArpMode::Random => {
+                let index = self.random.next_u32() as usize % count;
+                self.ascending(index)
+            }
+            _ => self.ascending(step % count),
+        };
+        (note.min(127), velocity)
+    }
+
+    // One control tick
+    pub fn update(&mut self, sync: &ClockSync) -> ArpStep {
+        let mut step = ArpStep::default();
+        if !self.is_enabled() {
+            return step;
+        }
+        self.since_step = self.since_step.saturating_add(1);
+
+        let ticked = sync.step(self.position, self.params.division);
+        self.position = sync.position;
+
+        let gate_done = match sync.quarter_us {
+            Some(quarter_us) => {
+                let step_us = quarter_us as u64 * self.params.division.0 as u64 / PPQN as u64;
+                let gate_us = step_us * self.params.gate as u64 / 100;
+                self.since_step as u64 * 1_000_000 / CONTROL_HZ as u64 >= gate_us
+            }
+            None => false,
+        };
+        if ticked.is_some() || gate_done || self.len == 0 {
+            step.off = self.sounding.take();
+        }
+        if let (Some(index), true) = (ticked, self.len > 0) {
+            let (note, velocity) = self.note(index as usize);
+            let voice = if self.params.spread {
+                Some(index as usize % VOICES)
+            } else {
+                None
+            };
+            step.on = Some(ArpNote {
+                note,
+                velocity,
+                voice,
+            });
+            self.sounding = Some(note);
+            self.since_step = 0;
+        }
+        step
+    }
+}
+
+impl Default for Arpeggiator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arpeggiator(mode: ArpMode) -> Arpeggiator {
+        let mut arp = Arpeggiator::new();
+        arp.set_params(ArpParams {
+            mode,
+            ..ArpParams::new()
+        });
+        for note in [64, 60, 67].iter() {
+            arp.note_on(0, *note, 100);
+        }
+        arp
+    }
+
+    fn note_at(arp: &mut Arpeggiator, position: u32) -> Option<u8> {
+        let sync = ClockSync {
+            position,
+            quarter_us: None,
+        };
+        arp.update(&sync).on.map(|on| on.note)
+    }
+
+    #[test]
+    fn plays_up_on_the_division() {
+        let mut arp = arpeggiator(ArpMode::Up);
+        let notes: Vec<_> = [1, 7, 13, 19]
+            .iter()
+            .map(|position| note_at(&mut arp, *position))
+            .collect();
+        assert_eq!(notes, [Some(60), Some(64), Some(67), Some(60)]);
+        assert_eq!(note_at(&mut arp, 20), None);
+    }
+
+    #[test]
+    fn relocating_realigns() {
+        let mut arp = arpeggiator(ArpMode::AsPlayed);
+        note_at(&mut arp, 1);
+        note_at(&mut arp, 7);
+        // the eighth sixteenth of the song is the third note round
+        assert_eq!(note_at(&mut arp, 49), Some(67));
+        // and a loop back to the top starts the pattern over
+        assert_eq!(note_at(&mut arp, 1), Some(64));
+    }
+
+    #[test]
+    fn spread_follows_the_step() {
+        let mut arp = arpeggiator(ArpMode::Up);
+        let mut params = arp.params();
+        params.spread = true;
+        arp.set_params(params);
+        let voice = |arp: &mut Arpeggiator, position| {
+            let sync = ClockSync {
+                position,
+                quarter_us: None,
+            };
+            arp.update(&sync).on.and_then(|on| on.voice)
+        };
+        assert_eq!(voice(&mut arp, 1), Some(0));
+        assert_eq!(voice(&mut arp, 7), Some(1));
+        assert_eq!(voice(&mut arp, 37), Some(6 % VOICES));
+    }
+}
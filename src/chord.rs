@@ -0,0 +1,267 @@
+use crate::instrument::VOICES;
+use crate::scale::Scale;
+
+// Undefined controllers, each spread evenly over its choices
+const CHORD_SHAPE: u8 = 102;
+const CHORD_INVERSION: u8 = 103;
+const CHORD_SPREAD: u8 = 104;
+// on from 64 like a pedal
+const CHORD_QUANTIZE: u8 = 105;
+
+const MAX_SPREAD: u8 = 2;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ChordShape {
+    Off,
+    Major,
+    Minor,
+    Diminished,
+    Augmented,
+    Sus2,
+    Sus4,
+    Major7,
+    Minor7,
+    Dominant7,
+    HalfDiminished7,
+    Diminished7,
+    Custom,
+}
+
+const SHAPES: [ChordShape; 13] = [
+    ChordShape::Off,
+    ChordShape::Major,
+    ChordShape::Minor,
+    ChordShape::Diminished,
+    ChordShape::Augmented,
+    ChordShape::Sus2,
+    ChordShape::Sus4,
+    ChordShape::Major7,
+    ChordShape::Minor7,
+    ChordShape::Dominant7,
+    ChordShape::HalfDiminished7,
+    ChordShape::Diminished7,
+    ChordShape::Custom,
+];
+
+// Anything off the top comes down whole octaves
+fn fold(mut note: i32) -> u8 {
+    while note > 127 {
+        note -= 12;
+    }
+    note as u8
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ChordParam {
+    Shape,
+    Inversion,
+    Spread,
+    Quantize,
+    Custom(usize),
+}
+
+impl ChordParam {
+    pub fn next(self) -> Self {
+        match self {
+            ChordParam::Shape => ChordParam::Inversion,
+            ChordParam::Inversion => ChordParam::Spread,
+            ChordParam::Spread => ChordParam::Quantize,
+            ChordParam::Quantize => ChordParam::Custom(0),
+            ChordParam::Custom(index) if index + 1 < VOICES - 1 => ChordParam::Custom(index + 1),
+            ChordParam::Custom(_) => ChordParam::Shape,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ChordParams {
+    pub shape: ChordShape,
+    // how many of the lowest chord tones go up an octave
+    pub inversion: u8,
+    // octaves every other voice is raised by, for open voicings
+    pub spread: u8,
+    // semitones above the played note for the custom shape
+    pub custom: [u8; VOICES - 1],
+    // chord tones fall to the nearest note of the key below, so the
+    // chords are diatonic
+    pub quantize: bool,
+}
+
+impl ChordParams {
+    pub fn new() -> Self {
+        Self {
+            shape: ChordShape::Off,
+            inversion: 0,
+            spread: 0,
+            custom: [7, 12, 19],
+            quantize: false,
+        }
+    }
+
+    // Semitones above the played note and how many of them there are,
+    // triads double their root up an octave on the last voice
+    fn intervals(&self) -> ([u8; VOICES], usize) {
+        let c = self.custom;
+        match self.shape {
+            ChordShape::Off => ([0; VOICES], 1),
+            ChordShape::Major => ([0, 4, 7, 0], 3),
+            ChordShape::Minor => ([0, 3, 7, 0], 3),
+            ChordShape::Diminished => ([0, 3, 6, 0], 3),
+            ChordShape::Augmented => ([0, 4, 8, 0], 3),
+            ChordShape::Sus2 => ([0, 2, 7, 0], 3),
+            ChordShape::Sus4 => ([0, 5, 7, 0], 3),
+            ChordShape::Major7 => ([0, 4, 7, 11], 4),
+            ChordShape::Minor7 => ([0, 3, 7, 10], 4),
+            ChordShape::Dominant7 => ([0, 4, 7, 10], 4),
+            ChordShape::HalfDiminished7 => ([0, 3, 6, 10], 4),
+            ChordShape::Diminished7 => ([0, 3, 6, 9], 4),
+            ChordShape::Custom => ([0, c[0], c[1], c[2]], 4),
+        }
+    }
+
+    // A note for each voice, lowest first before the spread
+    pub fn tones(&self, note: u8, scale: Scale, key: u8) -> [u8; VOICES] {
+        let (intervals, count) = self.intervals();
+        let root = if self.quantize {
+            scale.snap_down(note, key)
+        } else {
+            note
+        };
+        let mut tones = [0i32; VOICES];
+        for (tone, interval) in tones.iter_mut().zip(intervals.iter()) {
+            *tone = fold(root as i32 + *interval as i32) as i32;
+            if self.quantize {
+                *tone = scale.snap_down(*tone as u8, key) as i32;
+            }
+        }
+        let inversion = self.inversion as usize % count;
+        tones[..count].sort_unstable();
+        for tone in tones[..inversion].iter_mut() {
+            *tone += 12;
+        }
+        tones[..count].sort_unstable();
+        for index in count..VOICES {
+            tones[index] = tones[index - count] + 12;
+        }
+        for tone in tones.iter_mut().skip(1).step_by(2) {
+            *tone += 12 * self.spread as i32;
+        }
+        let mut notes = [0; VOICES];
+        for (note, tone) in notes.iter_mut().zip(tones.iter()) {
+            *note = fold(*tone);
+        }
+        notes
+    }
+
+    // Encoder turns, switches flip on any turn
+    pub fn adjust(&mut self, param: ChordParam, steps: i32) {
+        let add = |value: u8, max: i32| (value as i32 + steps).clamp(0, max) as u8;
+        match param {
+            ChordParam::Shape => {
+                let index = SHAPES
+                    .iter()
+                    .position(|shape| *shape == self.shape)
+                    .unwrap_or(0);
+                let index = (index as i32 + steps).rem_euclid(SHAPES.len() as i32);
+                self.shape = SHAPES[index as usize];
+            }
+            ChordParam::Inversion => self.inversion = add(self.inversion, VOICES as i32 - 1),
+            ChordParam::Spread => self.spread = add(self.spread, MAX_SPREAD as i32),
+            ChordParam::Quantize => self.quantize = !self.quantize,
+            ChordParam::Custom(index) => self.custom[index] = add(self.custom[index], 24),
+        }
+    }
+
+    // Any channel's controllers set the chord
+    pub fn control(&mut self, control: u8, value: u16) {
+        let value = (value >> 7) as usize;
+        match control {
+            CHORD_SHAPE => self.shape = SHAPES[value * SHAPES.len() / 128],
+            CHORD_INVERSION => self.inversion = (value * VOICES / 128) as u8,
+            CHORD_SPREAD => self.spread = (value * (MAX_SPREAD as usize + 1) / 128) as u8,
+            CHORD_QUANTIZE => self.quantize = value >= 64,
+            _ => {}
+        }
+    }
+}
+
+impl Default for ChordParams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Spreads the note played last across the voices as a chord, so one key
+// plays a paraphonic patch
+pub struct Harmonizer {
+    params: ChordParams,
+    // the channel and note sounding the chord
+    held: Option<(u8, u8)>,
+    tones: [u8; VOICES],
+}
+
+impl Harmonizer {
+    pub fn new() -> Self {
+        Self {
+            params: ChordParams::new(),
+            held: None,
+            tones: [0; VOICES],
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.params.shape != ChordShape::Off
+    }
+
+    pub fn params(&self) -> ChordParams {
+        self.params
+    }
+
+    // Returns the chord to release if the change turns chords off
+    pub fn set_params(&mut self, params: ChordParams) -> Option<[u8; VOICES]> {
+        self.params = params;
+        if self.is_enabled() {
+            None
+        } else {
+            self.clear()
+        }
+    }
+
+    pub fn control(&mut self, control: u8, value: u16) -> Option<[u8; VOICES]> {
+        let mut params = self.params;
+        params.control(control, value);
+        self.set_params(params)
+    }
+
+    // The new chord in a key, and the one it takes over from
+    pub fn note_on(
+        &mut self,
+        channel: u8,
+        note: u8,
+        scale: Scale,
+        key: u8,
+    ) -> (Option<[u8; VOICES]>, [u8; VOICES]) {
+        let previous = self.held.map(|_| self.tones);
+        self.held = Some((channel, note));
+        self.tones = self.params.tones(note, scale, key);
+        (previous, self.tones)
+    }
+
+    // The chord to release, if the note is the one sounding it
+    pub fn note_off(&mut self, channel: u8, note: u8) -> Option<[u8; VOICES]> {
+        if self.held != Some((channel, note)) {
+            return None;
+        }
+        self.clear()
+    }
+
+    pub fn clear(&mut self) -> Option<[u8; VOICES]> {
+        self.held.take().map(|_| self.tones)
+    }
+}
+
+impl Default for Harmonizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
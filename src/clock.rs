@@ -0,0 +1,413 @@
+use crate::cv::CvPanel;
+use crate::instrument::{GATE_OFF, GATE_ON};
+use crate::midi::message::MidiMessage;
+use crate::routing::Output;
+
+pub const PPQN: u32 = 24;
+// Song position pointers count sixteenth notes
+pub const TICKS_PER_SIXTEENTH: u32 = PPQN / 4;
+pub const CLOCK_OUTPUTS: usize = 4;
+
+// Long enough for any module to see
+pub const TRIGGER_MS: u32 = 5;
+
+// Tempo is averaged over a quarter note of ticks
+const WINDOW: usize = PPQN as usize + 1;
+// A gap this long means the clock went away, so the estimate starts over
+const MAX_INTERVAL_MS: u32 = 250;
+
+// MIDI clock ticks from one pulse to the next
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Division(pub u16);
+
+impl Division {
+    pub const PPQN_24: Division = Division(1);
+    pub const PPQN_8: Division = Division(3);
+    pub const PPQN_4: Division = Division(6);
+    pub const PPQN_2: Division = Division(12);
+    pub const QUARTER: Division = Division(24);
+    pub const BAR: Division = Division(96);
+
+    // Through the step lengths the encoder offers, a quarter down to a 32nd
+    pub fn step(self, steps: i32) -> Self {
+        const DIVISIONS: [Division; 6] = [
+            Division::QUARTER,
+            Division::PPQN_2,
+            Division(8),
+            Division::PPQN_4,
+            Division(4),
+            Division::PPQN_8,
+        ];
+        let index = DIVISIONS
+            .iter()
+            .position(|division| *division == self)
+            .unwrap_or(0) as i32;
+        DIVISIONS[(index + steps).clamp(0, DIVISIONS.len() as i32 - 1) as usize]
+    }
+}
+
+// What each clock output can be set to, fastest first
+const CLOCK_DIVISIONS: [Division; 7] = [
+    Division::PPQN_24,
+    Division::PPQN_8,
+    Division::PPQN_4,
+    Division::PPQN_2,
+    Division::QUARTER,
+    Division(48),
+    Division::BAR,
+];
+
+// Times of the last ticks in a ring. The span of the whole window averages
+// out millisecond and USB frame jitter, the rest is smoothed over.
+struct TempoEstimate {
+    times: [u32; WINDOW],
+    next: usize,
+    count: usize,
+    quarter_us: Option<u32>,
+}
+
+impl TempoEstimate {
+    fn new() -> Self {
+        Self {
+            times: [0; WINDOW],
+            next: 0,
+            count: 0,
+            quarter_us: None,
+        }
+    }
+
+    fn tick(&mut self, now: u32) {
+        let last = self.times[(self.next + WINDOW - 1) % WINDOW];
+        if self.count > 0 && now.wrapping_sub(last) > MAX_INTERVAL_MS {
+            *self = Self::new();
+        }
+        self.times[self.next] = now;
+        self.next = (self.next + 1) % WINDOW;
+        self.count = (self.count + 1).min(WINDOW);
+        if self.count < 2 {
+            return;
+        }
+
+        let oldest = self.times[(self.next + WINDOW - self.count) % WINDOW];
+        let span_us = now.wrapping_sub(oldest) * 1000;
+        let quarter_us = span_us * PPQN / (self.count as u32 - 1);
+        self.quarter_us = match self.quarter_us {
+            Some(previous) if self.count == WINDOW => {
+                Some(previous - previous / 8 + quarter_us / 8)
+            }
+            _ => Some(quarter_us),
+        };
+    }
+}
+
+// What the control tick needs from the clock, copied out once per idle loop
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ClockSync {
+    pub position: u32,
+    pub quarter_us: Option<u32>,
+}
+
+impl ClockSync {
+    // Which step of a division the tick just gone started, if the position
+    // moved on from the last one seen and landed on a step. Counted from the
+    // top of the song, so a relocate or loop realigns whatever is stepping.
+    pub fn step(&self, last: u32, division: Division) -> Option<u32> {
+        let division = division.0.max(1) as u32;
+        // the tick just gone is the one before the position
+        let tick = self.position.checked_sub(1)?;
+        if self.position != last && tick % division == 0 {
+            Some(tick / division)
+        } else {
+            None
+        }
+    }
+}
+
+// How the internal clock runs when there's no DAW to follow, kept apart from
+// the timer so it can be saved
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MasterParams {
+    // beats per minute in tenths
+    pub tempo: u32,
+    // percent of each eighth note given to its first sixteenth
+    pub swing: u8,
+    pub send_to_host: bool,
+}
+
+impl MasterParams {
+    pub fn new() -> Self {
+        Self {
+            tempo: 1200,
+            swing: 50,
+            send_to_host: false,
+        }
+    }
+}
+
+impl Default for MasterParams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Follows MIDI beat clock and turns it into divided triggers, plus run and
+// reset outputs for the transport
+pub struct Clock {
+    running: bool,
+    // ticks since the start of the song, every division is phased from it
+    // so relocating realigns them all
+    position: u32,
+    divisions: [Division; CLOCK_OUTPUTS],
+    // when each output last fired
+    fired: [Option<u32>; CLOCK_OUTPUTS],
+    reset: Option<u32>,
+    tempo: TempoEstimate,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self {
+            running: false,
+            position: 0,
+            divisions: [
+                Division::PPQN_4,
+                Division::QUARTER,
+                Division::PPQN_24,
+                Division::BAR,
+            ],
+            fired: [None; CLOCK_OUTPUTS],
+            reset: None,
+            tempo: TempoEstimate::new(),
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    pub fn position(&self) -> u32 {
+        self.position
+    }
+
+    pub fn division(&self, output: usize) -> Division {
+        self.divisions[output]
+    }
+
+    pub fn set_division(&mut self, output: usize, division: Division) {
+        self.divisions[output] = Division(division.0.max(1));
+    }
+
+    // Steps an output through the clock divisions, slower for positive steps
+    pub fn adjust(&mut self, output: usize, steps: i32) {
+        let index = CLOCK_DIVISIONS
+            .iter()
+            .position(|division| *division == self.divisions[output])
+            .unwrap_or(0) as i32;
+        let index = (index + steps).clamp(0, CLOCK_DIVISIONS.len() as i32 - 1);
+        self.set_division(output, CLOCK_DIVISIONS[index as usize]);
+    }
+
+    pub fn bpm(&self) -> Option<f32> {
+        self.tempo
+            .quarter_us
+            .map(|quarter_us| 60_000_000.0 / quarter_us as f32)
+    }
+
+    pub fn sync(&self) -> ClockSync {
+        ClockSync {
+            position: self.position,
+            quarter_us: self.tempo.quarter_us,
+        }
+    }
+
+    pub fn handle(&mut self, message: MidiMessage, now: u32) {
+        match message {
+            MidiMessage::TimingClock => self.tick(now),
+            MidiMessage::Start => {
+                self.position = 0;
+                self.running = true;
+                self.reset = Some(now);
+            }
+            MidiMessage::SongPosition { position } => self.locate(position as u32, now),
+            // carries on from the last song position
+            MidiMessage::Continue => self.running = true,
+            MidiMessage::Stop => self.running = false,
+            _ => {}
+        }
+    }
+
+    // Hosts send a song position when the playhead jumps or loops, normally
+    // while stopped. Going back to the top also fires reset for sequencers
+    // that can only start over.
+    pub fn locate(&mut self, sixteenths: u32, now: u32) {
+        self.position = sixteenths * TICKS_PER_SIXTEENTH;
+        if sixteenths == 0 {
+            self.reset = Some(now);
+        }
+    }
+
+    fn tick(&mut self, now: u32) {
+        self.tempo.tick(now);
+        if !self.running {
+            return;
+        }
+        for (division, fired) in self.divisions.iter().zip(self.fired.iter_mut()) {
+            if self.position.is_multiple_of(division.0 as u32) {
+                *fired = Some(now);
+            }
+        }
+        self.position = self.position.wrapping_add(1);
+    }
+
+    pub fn render(&self, now: u32, cv_panel: &mut CvPanel) {
+        let level = |high| if high { GATE_ON } else { GATE_OFF };
+        let triggered = |fired| matches!(fired, Some(at) if now.wrapping_sub(at) < TRIGGER_MS);
+        for (index, fired) in self.fired.iter().enumerate() {
+            cv_panel
+                .output(Output::Clock(index as u8))
+                .set_millivolts(level(triggered(*fired)));
+        }
+        cv_panel
+            .output(Output::Run)
+            .set_millivolts(level(self.running));
+        cv_panel
+            .output(Output::Reset)
+            .set_millivolts(level(triggered(self.reset)));
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Ticks the clock at a millisecond a tick, returning which outputs fired
+    // on the last one
+    fn ticks(clock: &mut Clock, count: u32, now: &mut u32) -> [bool; CLOCK_OUTPUTS] {
+        for _ in 0..count {
+            *now += 1;
+            clock.handle(MidiMessage::TimingClock, *now);
+        }
+        let mut fired = [false; CLOCK_OUTPUTS];
+        for (fired, at) in fired.iter_mut().zip(clock.fired.iter()) {
+            *fired = *at == Some(*now);
+        }
+        fired
+    }
+
+    const SIXTEENTH: usize = 0;
+    const QUARTER: usize = 1;
+    const BAR: usize = 3;
+
+    #[test]
+    fn stepping_through_the_divisions() {
+        let mut division = Division::QUARTER;
+        let mut seen = [0; 6];
+        for ticks in seen.iter_mut() {
+            *ticks = division.0;
+            division = division.step(1);
+        }
+        assert_eq!(seen, [24, 12, 8, 6, 4, 3]);
+        assert_eq!(division, Division::PPQN_8);
+        assert_eq!(division.step(-5), Division::QUARTER);
+        assert_eq!(division.step(-6), Division::QUARTER);
+    }
+
+    #[test]
+    fn locate_while_stopped() {
+        let mut clock = Clock::new();
+        let mut now = 0;
+        // the second beat of the second bar
+        clock.handle(MidiMessage::SongPosition { position: 20 }, now);
+        assert_eq!(clock.position(), 120);
+        assert_eq!(clock.reset, None);
+        // stopped, ticks only feed the tempo
+        ticks(&mut clock, 3, &mut now);
+        assert_eq!(clock.position(), 120);
+
+        clock.handle(MidiMessage::Continue, now);
+        let fired = ticks(&mut clock, 1, &mut now);
+        assert!(fired[SIXTEENTH] && fired[QUARTER] && !fired[BAR]);
+        assert_eq!(clock.position(), 121);
+        // the next bar lines up with the song, not with the continue
+        assert!(ticks(&mut clock, 72, &mut now)[BAR]);
+    }
+
+    #[test]
+    fn locate_while_running() {
+        let mut clock = Clock::new();
+        let mut now = 0;
+        clock.handle(MidiMessage::Start, now);
+        ticks(&mut clock, 10, &mut now);
+
+        // the host jumps to the sixth sixteenth, halfway between quarters
+        clock.handle(MidiMessage::SongPosition { position: 6 }, now);
+        assert_eq!(clock.position(), 36);
+        let fired = ticks(&mut clock, 1, &mut now);
+        assert!(fired[SIXTEENTH] && !fired[QUARTER]);
+        assert!(!ticks(&mut clock, 5, &mut now)[SIXTEENTH]);
+        assert!(ticks(&mut clock, 1, &mut now)[SIXTEENTH]);
+        assert!(ticks(&mut clock, 6, &mut now)[QUARTER]);
+    }
+
+    #[test]
+    fn loop_wraps_to_the_loop_start() {
+        let mut clock = Clock::new();
+        let mut now = 0;
+        clock.handle(MidiMessage::Start, now);
+        let start = now;
+        // two bars looping from the second bar, the playhead runs to the end
+        // of the third and jumps back
+        clock.handle(MidiMessage::SongPosition { position: 16 }, now);
+        ticks(&mut clock, 2 * 96 - 1, &mut now);
+        assert_eq!(clock.position(), 3 * 96 - 1);
+        clock.handle(MidiMessage::SongPosition { position: 16 }, now);
+        let fired = ticks(&mut clock, 1, &mut now);
+        assert_eq!(fired, [true; CLOCK_OUTPUTS]);
+        assert_eq!(clock.position(), 96 + 1);
+        // not back at the top, so no reset
+        assert_eq!(clock.reset, Some(start));
+
+        // a loop from the top does reset
+        ticks(&mut clock, 20, &mut now);
+        clock.handle(MidiMessage::SongPosition { position: 0 }, now);
+        assert_eq!(clock.reset, Some(now));
+        assert_eq!(ticks(&mut clock, 1, &mut now), [true; CLOCK_OUTPUTS]);
+    }
+
+    #[test]
+    fn adjusting_divisions() {
+        let mut clock = Clock::new();
+        clock.adjust(SIXTEENTH, 1);
+        assert_eq!(clock.division(SIXTEENTH), Division::PPQN_2);
+        clock.adjust(SIXTEENTH, -10);
+        assert_eq!(clock.division(SIXTEENTH), Division::PPQN_24);
+        clock.adjust(BAR, 1);
+        assert_eq!(clock.division(BAR), Division::BAR);
+        clock.adjust(BAR, -1);
+        assert_eq!(clock.division(BAR), Division(48));
+
+        // a half note fires every other quarter
+        let mut now = 0;
+        clock.handle(MidiMessage::Start, now);
+        assert!(ticks(&mut clock, 1, &mut now)[BAR]);
+        assert!(!ticks(&mut clock, 24, &mut now)[BAR]);
+        assert!(ticks(&mut clock, 24, &mut now)[BAR]);
+    }
+
+    #[test]
+    fn sync_follows_the_position() {
+        let mut clock = Clock::new();
+        let mut now = 0;
+        clock.handle(MidiMessage::Start, now);
+        ticks(&mut clock, 30, &mut now);
+        clock.locate(4, now);
+        assert_eq!(clock.sync().position, 24);
+        assert_eq!(clock.sync().quarter_us, Some(24_000));
+    }
+}
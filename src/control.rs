@@ -0,0 +1,26 @@
+// Rate of the fixed control tick every time based feature runs on: glides,
+// envelopes and LFOs
+pub const CONTROL_HZ: u32 = 1000;
+
+// Ticks made up after a slow idle loop, anything longer like a flash erase
+// is dropped rather than rushed through
+const MAX_CATCH_UP: u32 = CONTROL_HZ / 100;
+
+// Counts control ticks off the millisecond clock, so the work happens in the
+// idle loop at a fixed rate however long each pass takes
+pub struct ControlTimer {
+    last_ms: u32,
+}
+
+impl ControlTimer {
+    pub fn new(now: u32) -> Self {
+        Self { last_ms: now }
+    }
+
+    // Ticks due since the last call
+    pub fn ticks(&mut self, now: u32) -> u32 {
+        let behind = now.wrapping_sub(self.last_ms) * CONTROL_HZ / 1000;
+        self.last_ms = now;
+        behind.min(MAX_CATCH_UP)
+    }
+}
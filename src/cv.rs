@@ -1,93 +1,276 @@
-use crate::hal::{
-    gpio::{
-        gpiob::{PB10, PB9},
-        gpiof::{PF10, PF12, PF14, PF8},
-        OpenDrain, Output, PushPull,
-    },
-    rcc::Clocks,
-    time::U32Ext,
-};
-use crate::mcp4728::{Mcp4728, Mcp4728Error, Mcp4728I2c};
-use embedded_hal::digital::v2::OutputPin;
-
-type SCL = PB10<Output<OpenDrain>>;
-type SDA = PB9<Output<OpenDrain>>;
+use crate::frame::{OutputFrame, Priority, OUTPUTS};
+use crate::routing::{self, RoutingTable};
+use crate::volts::{Millivolts, OutputRange};
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OutputParam {
+    Routing,
+    // the range of one DAC channel, by slot
+    Range(u8),
+}
+
+impl OutputParam {
+    pub fn next(self) -> Self {
+        match self {
+            OutputParam::Routing => OutputParam::Range(0),
+            OutputParam::Range(slot) if (slot as usize) < OUTPUTS - 1 => {
+                OutputParam::Range(slot + 1)
+            }
+            OutputParam::Range(_) => OutputParam::Routing,
+        }
+    }
+}
+
+// What the outputs should read, routed and converted into DAC codes. The
+// DAC writer clocks the frame out, woken whenever a slot changes.
+#[derive(Clone)]
 pub struct CvPanel {
-    i2c: Mcp4728I2c<SCL, SDA>,
-    dacs: [Mcp4728<SCL, SDA>; 4],
+    frame: OutputFrame,
+    ranges: [OutputRange; OUTPUTS],
+    routing: RoutingTable,
+    wake: bool,
 }
 
+// Transfers the writer may start per 4ms frame
+const WRITE_BUDGET: u8 = 8;
+
 impl CvPanel {
-    pub fn new(
-        clocks: &Clocks,
-        mut ldac1: PF8<Output<PushPull>>,
-        mut ldac2: PF10<Output<PushPull>>,
-        mut ldac3: PF12<Output<PushPull>>,
-        mut ldac4: PF14<Output<PushPull>>,
-        scl: PB10<Output<OpenDrain>>,
-        sda: PB9<Output<OpenDrain>>,
-    ) -> Self {
-        let mut i2c = Mcp4728I2c::new(&clocks, 100.khz(), scl, sda);
-        ldac1.set_high().unwrap();
-        ldac2.set_high().unwrap();
-        ldac3.set_high().unwrap();
-        ldac4.set_high().unwrap();
-        let dac1 = Mcp4728::new(ldac1, 0x1, &mut i2c).unwrap();
-        let dac2 = Mcp4728::new(ldac2, 0x2, &mut i2c).unwrap();
-        let dac3 = Mcp4728::new(ldac3, 0x3, &mut i2c).unwrap();
-        let dac4 = Mcp4728::new(ldac4, 0x4, &mut i2c).unwrap();
-        Self {
-            i2c,
-            dacs: [dac1, dac2, dac3, dac4],
+    pub fn new() -> Self {
+        let mut panel = Self {
+            frame: OutputFrame::new(WRITE_BUDGET),
+            ranges: [OutputRange::default(); OUTPUTS],
+            routing: RoutingTable::empty(),
+            wake: false,
+        };
+        panel.set_routing(RoutingTable::default());
+        panel
+    }
+
+    // True once since a slot last changed, the writer sleeps until then
+    pub fn take_wake(&mut self) -> bool {
+        core::mem::replace(&mut self.wake, false)
+    }
+
+    pub fn frame(&self) -> &OutputFrame {
+        &self.frame
+    }
+
+    pub fn frame_mut(&mut self) -> &mut OutputFrame {
+        &mut self.frame
+    }
+
+    // Takes the values rendered into a copy of this panel, leaving what the
+    // writer has sent alone
+    pub fn apply(&mut self, rendered: &CvPanel) {
+        for slot in 0..OUTPUTS {
+            self.wake |= self.frame.set(slot, rendered.frame.value(slot));
         }
     }
 
-    pub fn gate<'a>(&'a mut self, voice: usize) -> Cv<'a> {
-        assert!(voice <= 3);
-        Cv::<'a> {
-            panel: self,
-            dac: voice,
-            channel: 3,
+    pub fn set_write_budget(&mut self, budget: u8) {
+        self.frame.set_budget(budget);
+    }
+
+    // Slots are indexed dac * 4 + channel, the same as the output frame. The
+    // slot is recoded to hold its voltage, as near as the new range allows.
+    pub fn set_range(&mut self, slot: usize, range: OutputRange) {
+        let voltage = self.ranges[slot].to_millivolts(self.frame.value(slot));
+        self.ranges[slot] = range;
+        self.wake |= self.frame.set(slot, range.to_code(voltage));
+    }
+
+    pub fn range(&self, slot: usize) -> OutputRange {
+        self.ranges[slot]
+    }
+
+    pub fn routing(&self) -> &RoutingTable {
+        &self.routing
+    }
+
+    // Gates and pitch keep their write priority wherever they are routed. A
+    // channel that changes hands rests at 0V until its new output is set,
+    // rather than holding whatever the old one left there.
+    pub fn set_routing(&mut self, routing: RoutingTable) {
+        for slot in 0..OUTPUTS {
+            let output = routing.output(slot);
+            let priority = output.map_or(Priority::Aux, |output| output.priority());
+            self.frame.set_priority(slot, priority);
+            if output != self.routing.output(slot) {
+                let rest = self.ranges[slot].to_code(Millivolts(0));
+                self.wake |= self.frame.set(slot, rest);
+            }
         }
+        self.routing = routing;
     }
 
-    pub fn pitch<'a>(&'a mut self, voice: usize) -> Cv<'a> {
-        assert!(voice <= 3);
-        Cv::<'a> {
-            panel: self,
-            dac: voice,
-            channel: 2,
+    pub fn adjust(&mut self, param: OutputParam, steps: i32) {
+        match param {
+            OutputParam::Routing => self.set_routing(self.routing.step(steps)),
+            OutputParam::Range(slot) => {
+                let slot = slot as usize;
+                self.set_range(slot, self.ranges[slot].step(steps));
+            }
         }
     }
 
-    pub fn aux1<'a>(&'a mut self, voice: usize) -> Cv<'a> {
-        assert!(voice <= 3);
+    pub fn output<'a>(&'a mut self, output: routing::Output) -> Cv<'a> {
         Cv::<'a> {
             panel: self,
-            dac: voice,
-            channel: 1,
+            output,
         }
     }
 
+    pub fn gate<'a>(&'a mut self, voice: usize) -> Cv<'a> {
+        self.output(routing::Output::Gate(voice as u8))
+    }
+
+    pub fn pitch<'a>(&'a mut self, voice: usize) -> Cv<'a> {
+        self.output(routing::Output::Pitch(voice as u8))
+    }
+
+    pub fn aux1<'a>(&'a mut self, voice: usize) -> Cv<'a> {
+        self.output(routing::Output::Aux(voice as u8 * 2))
+    }
+
     pub fn aux2<'a>(&'a mut self, voice: usize) -> Cv<'a> {
-        assert!(voice <= 3);
-        Cv::<'a> {
-            panel: self,
-            dac: voice,
-            channel: 0,
-        }
+        self.output(routing::Output::Aux(voice as u8 * 2 + 1))
     }
 }
 
 pub struct Cv<'a> {
     panel: &'a mut CvPanel,
-    dac: usize,
-    channel: u8,
+    output: routing::Output,
 }
 
 impl<'a> Cv<'a> {
-    pub fn set(&mut self, value: u16) -> Result<(), Mcp4728Error> {
-        self.panel.dacs[self.dac].set_channel(&mut self.panel.i2c, self.channel, value)
+    fn set_slots<F: Fn(&OutputRange) -> u16>(&mut self, code: F) {
+        let panel = &mut *self.panel;
+        let mut dirty = false;
+        for slot in panel.routing.slots_for(self.output) {
+            dirty |= panel.frame.set(slot, code(&panel.ranges[slot]));
+        }
+        panel.wake |= dirty;
+    }
+
+    pub fn set(&mut self, value: u16) {
+        assert!(value < 4096);
+        self.set_slots(|_| value);
+    }
+
+    // None when the output isn't routed to any DAC channel
+    pub fn range(&self) -> Option<OutputRange> {
+        let slot = self.panel.routing.slots_for(self.output).next()?;
+        Some(self.panel.ranges[slot])
+    }
+
+    pub fn set_millivolts<V: Into<Millivolts>>(&mut self, voltage: V) {
+        let voltage = voltage.into();
+        self.set_slots(|range| range.to_code(voltage));
+    }
+
+    pub fn millivolts(&self) -> Option<Millivolts> {
+        let slot = self.panel.routing.slots_for(self.output).next()?;
+        Some(self.panel.ranges[slot].to_millivolts(self.panel.frame.value(slot)))
+    }
+}
+
+impl Default for CvPanel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routing::Output;
+
+    #[test]
+    fn rerouted_channels_rest_at_zero() {
+        let mut panel = CvPanel::new();
+        panel.take_wake();
+        panel.set_range(13, OutputRange::BIPOLAR_5V);
+        panel.output(Output::Aux(6)).set(4000);
+        panel.output(Output::Aux(7)).set(4000);
+        panel.take_wake();
+
+        // the fourth DAC's aux channels become clocks
+        panel.adjust(OutputParam::Routing, 1);
+        assert_eq!(*panel.routing(), RoutingTable::clocked());
+        assert!(panel.take_wake());
+        assert_eq!(panel.frame().value(13), 2048);
+        assert_eq!(panel.frame().value(12), 0);
+        assert_eq!(panel.output(Output::Aux(6)).millivolts(), None);
+        // a channel that kept its output is left alone
+        panel.output(Output::Pitch(0)).set(1234);
+        panel.adjust(OutputParam::Routing, -1);
+        assert_eq!(panel.frame().value(2), 1234);
+    }
+
+    #[test]
+    fn ranges_by_slot() {
+        let mut panel = CvPanel::new();
+        let mut param = OutputParam::Routing;
+        for _ in 0..3 {
+            param = param.next();
+        }
+        assert_eq!(param, OutputParam::Range(2));
+        panel.adjust(param, 2);
+        assert_eq!(panel.range(2), OutputRange::BIPOLAR_5V);
+        assert_eq!(
+            panel.output(Output::Pitch(0)).range(),
+            Some(OutputRange::BIPOLAR_5V)
+        );
+        assert_eq!(panel.range(3), OutputRange::default());
+
+        for _ in 0..OUTPUTS - 3 {
+            param = param.next();
+        }
+        assert_eq!(param, OutputParam::Range(15));
+        assert_eq!(param.next(), OutputParam::Routing);
+    }
+
+    #[test]
+    fn new_ranges_keep_the_voltage() {
+        let mut panel = CvPanel::new();
+        panel
+            .output(Output::Pitch(0))
+            .set_millivolts(Millivolts(2_000));
+        assert_eq!(panel.frame_mut().next_write(), Some((2, 819)));
+        panel.take_wake();
+
+        panel.set_range(2, OutputRange::BIPOLAR_5V);
+        assert!(panel.take_wake());
+        assert!(panel.frame().is_dirty(2));
+        assert_eq!(panel.frame().value(2), 2867);
+
+        // below the new range, so it pins to the bottom
+        panel
+            .output(Output::Pitch(0))
+            .set_millivolts(Millivolts(-3_000));
+        panel.set_range(2, OutputRange::UNIPOLAR_5V);
+        assert_eq!(panel.frame().value(2), 0);
+    }
+
+    #[test]
+    fn rendered_copies_apply_their_values() {
+        let mut panel = CvPanel::new();
+        panel.output(Output::Gate(0)).set(4000);
+        let mut rendered = panel.clone();
+        rendered.output(Output::Pitch(0)).set(1234);
+
+        // the writer sends a slot while the copy is being rendered
+        assert_eq!(panel.frame_mut().next_write(), Some((3, 4000)));
+        panel.take_wake();
+
+        panel.apply(&rendered);
+        assert!(panel.take_wake());
+        assert!(panel.frame().is_dirty(2));
+        assert_eq!(panel.frame().value(2), 1234);
+        assert!(!panel.frame().is_dirty(3));
+        // once sent, the same values don't wake the writer again
+        assert_eq!(panel.frame_mut().next_write(), Some((2, 1234)));
+        panel.apply(&rendered);
+        assert!(!panel.take_wake());
     }
 }
@@ -0,0 +1,172 @@
+use crate::hal::{
+    gpio::{
+        gpiob::{PB10, PB9},
+        gpiof::{PF10, PF12, PF14, PF8},
+        OpenDrain, Output, PushPull,
+    },
+    pac::{RCC, TIM2},
+    rcc::Clocks,
+};
+use crate::i2c_bus::BusManager;
+use crate::mcp4728::{Mcp4728, Mcp4728Error, Mcp4728I2c, WriteTransfer};
+use core::task::Poll;
+use embedded_hal::digital::v2::OutputPin;
+use multimidi::frame::OutputFrame;
+
+type SCL = PB10<Output<OpenDrain>>;
+type SDA = PB9<Output<OpenDrain>>;
+
+pub type I2c = Mcp4728I2c<SCL, SDA>;
+pub type Dac = Mcp4728<SCL, SDA>;
+
+// Two timer ticks per I2C clock, so this gives a 100kHz bus
+const TICK_HZ: u32 = 200_000;
+
+// The write budget is refilled every 4ms, which fits about ten transfers
+const FRAME_TICKS: u16 = 800;
+
+// APB1 timers run at twice the bus clock when APB1 is divided
+pub fn apb1_timer_clock(clocks: &Clocks) -> u32 {
+    let rcc = unsafe { &(*RCC::ptr()) };
+    if rcc.cfgr.read().ppre1().bits() & 0b100 != 0 {
+        clocks.pclk1().0 * 2
+    } else {
+        clocks.pclk1().0
+    }
+}
+
+// Clocks dirty output frame slots out to the DACs from the TIM2 interrupt.
+// Only the newest value of a slot is ever sent, so a burst of writes to one
+// channel collapses into a single transfer.
+pub struct DacWriter {
+    timer: TIM2,
+    frame_ticks: u16,
+    transfer: Option<(usize, WriteTransfer)>,
+    error: Option<Mcp4728Error>,
+}
+
+impl DacWriter {
+    pub fn new(tim2: TIM2, clocks: &Clocks) -> Self {
+        let rcc = unsafe { &(*RCC::ptr()) };
+        rcc.apb1enr.modify(|_, w| w.tim2en().set_bit());
+        let timer_clock = apb1_timer_clock(clocks);
+
+        tim2.psc.write(|w| unsafe { w.bits(0) });
+        tim2.arr
+            .write(|w| unsafe { w.bits(timer_clock / TICK_HZ - 1) });
+        tim2.cr1.write(|w| w.urs().set_bit());
+        tim2.egr.write(|w| w.ug().set_bit());
+        tim2.sr.modify(|_, w| w.uif().clear_bit());
+        tim2.dier.write(|w| w.uie().set_bit());
+
+        Self {
+            timer: tim2,
+            frame_ticks: 0,
+            transfer: None,
+            error: None,
+        }
+    }
+
+    pub fn wake(&mut self) {
+        self.timer.cr1.modify(|_, w| w.cen().set_bit());
+    }
+
+    pub fn take_error(&mut self) -> Option<Mcp4728Error> {
+        self.error.take()
+    }
+
+    pub fn tick(&mut self, bus: &BusManager<I2c>, dacs: &[Dac; 4], frame: &mut OutputFrame) {
+        self.timer.sr.modify(|_, w| w.uif().clear_bit());
+
+        self.frame_ticks += 1;
+        if self.frame_ticks == FRAME_TICKS {
+            self.frame_ticks = 0;
+            frame.begin_frame();
+        }
+
+        if self.transfer.is_none() {
+            if !frame.any_dirty() {
+                // nothing left to send, sleep until the next wake
+                self.timer.cr1.modify(|_, w| w.cen().clear_bit());
+                self.frame_ticks = 0;
+                frame.begin_frame();
+                return;
+            }
+            if !bus.try_claim() {
+                return;
+            }
+            match frame.next_write() {
+                Some((slot, value)) => {
+                    let transfer = dacs[slot / 4].channel_transfer((slot % 4) as u8, value);
+                    self.transfer = Some((slot, transfer));
+                }
+                None => {
+                    // out of budget until the next frame
+                    bus.release();
+                    return;
+                }
+            }
+        }
+
+        if let Some((slot, transfer)) = self.transfer.as_mut() {
+            if let Poll::Ready(result) = bus.lock_claimed(|i2c| i2c.step(transfer)) {
+                if let Err(e) = result {
+                    frame.invalidate(*slot);
+                    self.error = Some(e);
+                }
+                self.transfer = None;
+                bus.release();
+            }
+        }
+    }
+}
+
+// The four DACs on the shared bus and the writer feeding them
+pub struct Dacs {
+    bus: &'static BusManager<I2c>,
+    dacs: [Dac; 4],
+    writer: DacWriter,
+}
+
+impl Dacs {
+    pub fn new(
+        clocks: &Clocks,
+        tim2: TIM2,
+        mut ldac1: PF8<Output<PushPull>>,
+        mut ldac2: PF10<Output<PushPull>>,
+        mut ldac3: PF12<Output<PushPull>>,
+        mut ldac4: PF14<Output<PushPull>>,
+        bus: &'static BusManager<I2c>,
+    ) -> Self {
+        ldac1.set_high().unwrap();
+        ldac2.set_high().unwrap();
+        ldac3.set_high().unwrap();
+        ldac4.set_high().unwrap();
+        let (dac1, dac2, dac3, dac4) = bus.lock(|i2c| {
+            (
+                Mcp4728::new(ldac1, 0x1, i2c).unwrap(),
+                Mcp4728::new(ldac2, 0x2, i2c).unwrap(),
+                Mcp4728::new(ldac3, 0x3, i2c).unwrap(),
+                Mcp4728::new(ldac4, 0x4, i2c).unwrap(),
+            )
+        });
+        Self {
+            bus,
+            dacs: [dac1, dac2, dac3, dac4],
+            writer: DacWriter::new(tim2, clocks),
+        }
+    }
+
+    // Called from the TIM2 interrupt to clock queued writes out to the DACs
+    pub fn tick(&mut self, frame: &mut OutputFrame) {
+        self.writer.tick(self.bus, &self.dacs, frame);
+    }
+
+    pub fn wake(&mut self) {
+        self.writer.wake();
+    }
+
+    pub fn take_error(&mut self) -> Option<Mcp4728Error> {
+        self.writer.take_error()
+    }
+}
@@ -0,0 +1,61 @@
+use core::fmt::Write;
+
+use multimidi::midi::mtc::{FrameRate, Timecode};
+use multimidi::ui::Page;
+use ssd1306::{prelude::*, Builder, I2CDIBuilder};
+
+use crate::dac_writer::I2c;
+use crate::i2c_bus::I2cProxy;
+
+type Interface = I2CInterface<I2cProxy<'static, I2c>>;
+
+// A row takes a good few milliseconds to send, so a running timecode is
+// shown at most this often
+pub const REDRAW_MS: u32 = 100;
+
+// A 128x64 SSD1306 on the DACs' bus, driven as a 16x8 character terminal.
+// Every byte goes out over the bit-banged I2C with the DAC writes waiting, so
+// it's only redrawn when what it shows has changed.
+pub struct StatusDisplay {
+    terminal: TerminalMode<Interface>,
+}
+
+impl StatusDisplay {
+    // None when there's no display answering on the bus
+    pub fn new(i2c: I2cProxy<'static, I2c>) -> Option<Self> {
+        let interface = I2CDIBuilder::new().init(i2c);
+        let mut terminal: TerminalMode<_> = Builder::new().connect(interface).into();
+        terminal.init().ok()?;
+        terminal.clear().ok()?;
+        write!(terminal, "MTC").ok()?;
+        let mut display = Self { terminal };
+        display.timecode(None);
+        Some(display)
+    }
+
+    // The row under "MTC", e.g. "01:02:03:04 30df"
+    pub fn timecode(&mut self, timecode: Option<Timecode>) {
+        if self.terminal.set_position(0, 1).is_err() {
+            return;
+        }
+        let _ = match timecode {
+            Some(tc) => {
+                let drop = if tc.rate == FrameRate::Fps30Drop {
+                    "df"
+                } else {
+                    "  "
+                };
+                write!(self.terminal, "{} {}{}", tc, tc.rate.frames(), drop)
+            }
+            None => write!(self.terminal, "{:16}", "--:--:--:--"),
+        };
+    }
+
+    // The page the encoder is on, a row clear of the timecode
+    pub fn page(&mut self, page: Page) {
+        if self.terminal.set_position(0, 3).is_err() {
+            return;
+        }
+        let _ = write!(self.terminal, "Page {:11}", page.name());
+    }
+}
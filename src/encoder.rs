@@ -7,9 +7,13 @@ use crate::hal::{
 };
 use embedded_hal::digital::v2::InputPin;
 
+// The timer counts both edges of one quadrature input, two per detent
+const COUNTS_PER_STEP: i16 = 2;
+
 pub struct Encoder {
     timer: TIM3,
     select: PC5<Input<Floating>>,
+    last: u16,
 }
 
 impl Encoder {
@@ -47,17 +51,26 @@ impl Encoder {
         Self {
             timer: tim3,
             select: pc5,
+            last: 0x8000,
         }
     }
 
     pub fn reset(&mut self) {
         self.timer.cnt.write(|w| w.cnt().bits(0x8000));
+        self.last = 0x8000;
     }
 
     pub fn count(&self) -> u32 {
         self.timer.cnt.read().cnt().bits() as u32
     }
 
+    // Whole detents turned since the last call, clockwise positive
+    pub fn steps(&mut self) -> i32 {
+        let delta = (self.count() as u16).wrapping_sub(self.last) as i16 / COUNTS_PER_STEP;
+        self.last = self.last.wrapping_add((delta * COUNTS_PER_STEP) as u16);
+        delta as i32
+    }
+
     pub fn select_pressed(&self) -> bool {
         self.select.is_low().unwrap()
     }
@@ -0,0 +1,212 @@
+use crate::control::CONTROL_HZ;
+use crate::midi::controllers::{widen, MAX_VALUE};
+
+// GM2 sound controllers where there is one, the rest are undefined numbers
+const RELEASE_TIME: u8 = 72;
+const ATTACK_TIME: u8 = 73;
+const DECAY_TIME: u8 = 75;
+const SUSTAIN_LEVEL: u8 = 79;
+const VELOCITY_AMOUNT: u8 = 85;
+const CURVE: u8 = 86;
+
+// Stage time with its parameter at full
+const MAX_TIME_MS: u32 = 10_000;
+
+// Extra fraction bits so long stages still move every tick
+const FRACTION: u32 = 16;
+const FULL: i32 = (MAX_VALUE as i32) << FRACTION;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EnvelopeParam {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Velocity,
+    Curve,
+}
+
+impl EnvelopeParam {
+    // None after the last one
+    pub fn next(self) -> Option<Self> {
+        match self {
+            EnvelopeParam::Attack => Some(EnvelopeParam::Decay),
+            EnvelopeParam::Decay => Some(EnvelopeParam::Sustain),
+            EnvelopeParam::Sustain => Some(EnvelopeParam::Release),
+            EnvelopeParam::Release => Some(EnvelopeParam::Velocity),
+            EnvelopeParam::Velocity => Some(EnvelopeParam::Curve),
+            EnvelopeParam::Curve => None,
+        }
+    }
+}
+
+// Shared by every voice. All values are 14 bit like the controllers that
+// set them, times follow a square law up to MAX_TIME_MS.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct EnvelopeParams {
+    pub attack: u16,
+    pub decay: u16,
+    pub sustain: u16,
+    pub release: u16,
+    // how far velocity scales the peak, zero plays every note at full
+    pub velocity: u16,
+    // zero is linear, full is squared
+    pub curve: u16,
+}
+
+pub const ENVELOPE_PRESETS: [EnvelopeParams; 4] = [
+    // 5ms attack, 300ms decay to 70%, 300ms release
+    EnvelopeParams::new(366, 2838, 11468, 2838, MAX_VALUE, 8192),
+    // pluck
+    EnvelopeParams::new(366, 3663, 0, 2317, MAX_VALUE, MAX_VALUE),
+    // pad
+    EnvelopeParams::new(7327, 5181, 12288, 8192, 4096, 0),
+    // organ, the gate with the clicks taken off
+    EnvelopeParams::new(0, 0, MAX_VALUE, 733, 0, 0),
+];
+
+impl EnvelopeParams {
+    pub const fn new(
+        attack: u16,
+        decay: u16,
+        sustain: u16,
+        release: u16,
+        velocity: u16,
+        curve: u16,
+    ) -> Self {
+        Self {
+            attack,
+            decay,
+            sustain,
+            release,
+            velocity,
+            curve,
+        }
+    }
+
+    pub fn get(&self, param: EnvelopeParam) -> u16 {
+        match param {
+            EnvelopeParam::Attack => self.attack,
+            EnvelopeParam::Decay => self.decay,
+            EnvelopeParam::Sustain => self.sustain,
+            EnvelopeParam::Release => self.release,
+            EnvelopeParam::Velocity => self.velocity,
+            EnvelopeParam::Curve => self.curve,
+        }
+    }
+
+    pub fn set(&mut self, param: EnvelopeParam, value: u16) {
+        let value = value.min(MAX_VALUE);
+        match param {
+            EnvelopeParam::Attack => self.attack = value,
+            EnvelopeParam::Decay => self.decay = value,
+            EnvelopeParam::Sustain => self.sustain = value,
+            EnvelopeParam::Release => self.release = value,
+            EnvelopeParam::Velocity => self.velocity = value,
+            EnvelopeParam::Curve => self.curve = value,
+        }
+    }
+
+    // Any channel's controllers set the envelope
+    pub fn control(&mut self, control: u8, value: u16) {
+        let param = match control {
+            ATTACK_TIME => EnvelopeParam::Attack,
+            DECAY_TIME => EnvelopeParam::Decay,
+            SUSTAIN_LEVEL => EnvelopeParam::Sustain,
+            RELEASE_TIME => EnvelopeParam::Release,
+            VELOCITY_AMOUNT => EnvelopeParam::Velocity,
+            CURVE => EnvelopeParam::Curve,
+            _ => return,
+        };
+        self.set(param, value);
+    }
+}
+
+impl Default for EnvelopeParams {
+    fn default() -> Self {
+        ENVELOPE_PRESETS[0]
+    }
+}
+
+// Level change per control tick to cover the full range in a stage's time
+fn step(value: u16) -> i32 {
+    let value = value.min(MAX_VALUE) as u32;
+    let ms = value * value / MAX_VALUE as u32 * MAX_TIME_MS / MAX_VALUE as u32;
+    let ticks = (ms * CONTROL_HZ / 1000).max(1);
+    FULL / ticks as i32
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Stage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+#[derive(Clone, Copy)]
+pub struct Envelope {
+    stage: Stage,
+    // linear level << FRACTION, shaped on the way out
+    level: i32,
+    // peak from the note's velocity
+    gain: u16,
+}
+
+impl Envelope {
+    pub fn new() -> Self {
+        Self {
+            stage: Stage::Release,
+            level: 0,
+            gain: MAX_VALUE,
+        }
+    }
+
+    // The attack starts from wherever the envelope is, so a retrigger
+    // doesn't click
+    pub fn trigger(&mut self, velocity: u8, params: &EnvelopeParams) {
+        let amount = params.velocity.min(MAX_VALUE) as u32;
+        let missing = MAX_VALUE as u32 - widen(velocity) as u32;
+        self.gain = (MAX_VALUE as u32 - amount * missing / MAX_VALUE as u32) as u16;
+        self.stage = Stage::Attack;
+    }
+
+    // One control tick. The gate going down releases from any stage.
+    pub fn update(&mut self, gate: bool, params: &EnvelopeParams) {
+        if !gate {
+            self.stage = Stage::Release;
+        }
+        let sustain = (params.sustain.min(MAX_VALUE) as i32) << FRACTION;
+        match self.stage {
+            Stage::Attack => {
+                self.level = (self.level + step(params.attack)).min(FULL);
+                if self.level == FULL {
+                    self.stage = Stage::Decay;
+                }
+            }
+            Stage::Decay => {
+                self.level = (self.level - step(params.decay)).max(sustain);
+                if self.level == sustain {
+                    self.stage = Stage::Sustain;
+                }
+            }
+            Stage::Sustain => self.level = sustain,
+            Stage::Release => self.level = (self.level - step(params.release)).max(0),
+        }
+    }
+
+    // 14-bit output level
+    pub fn level(&self, params: &EnvelopeParams) -> u16 {
+        let linear = (self.level >> FRACTION) as u32;
+        let squared = linear * linear / MAX_VALUE as u32;
+        let shaped =
+            linear - (linear - squared) * params.curve.min(MAX_VALUE) as u32 / MAX_VALUE as u32;
+        (shaped * self.gain as u32 / MAX_VALUE as u32) as u16
+    }
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
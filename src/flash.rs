@@ -0,0 +1,201 @@
+use crate::hal::pac::FLASH;
+use core::ptr;
+
+// The last 128K sector of the STM32F733's 512K flash is kept for settings.
+// memory.x ends the firmware image below 0x0806_0000 and asserts the two
+// agree.
+const SECTOR: u32 = 7;
+const BASE: usize = 0x0806_0000;
+const SIZE: usize = 128 * 1024;
+
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xCDEF_89AB;
+
+const CR_PG: u32 = 1 << 0;
+const CR_SER: u32 = 1 << 1;
+const CR_SNB_SHIFT: u32 = 3;
+const CR_PSIZE_X32: u32 = 0b10 << 8;
+const CR_STRT: u32 = 1 << 16;
+const CR_LOCK: u32 = 1 << 31;
+const SR_BSY: u32 = 1 << 16;
+// OPERR, WRPERR, PGAERR, PGPERR and ERSERR
+const SR_ERRORS: u32 = 0b1111_0010;
+
+const MAGIC: u32 = 0x4D4D_5354;
+const ERASED: u32 = 0xFFFF_FFFF;
+
+#[derive(Debug, Clone, Copy)]
+pub enum FlashError {
+    TooLarge,
+    Program,
+}
+
+// Settings records are appended one after another, so most saves only
+// program a few words. The sector is erased, stalling the CPU for a second
+// or so, only once it fills up. Each record is a magic word (programmed
+// last, so a torn write is never picked up), its length, the payload padded
+// to a word, and a checksum.
+pub struct SettingsStore {
+    flash: FLASH,
+    // offset of the first free word in the sector
+    free: usize,
+}
+
+impl SettingsStore {
+    pub fn new(flash: FLASH) -> Self {
+        let mut store = Self { flash, free: 0 };
+        store.free = store.scan().1;
+        store
+    }
+
+    fn read_word(offset: usize) -> u32 {
+        unsafe { ptr::read_volatile((BASE + offset) as *const u32) }
+    }
+
+    fn record_len(len: usize) -> usize {
+        8 + (len + 3) / 4 * 4 + 4
+    }
+
+    fn payload_checksum(offset: usize, len: usize) -> u32 {
+        let payload = unsafe { core::slice::from_raw_parts((BASE + offset + 8) as *const u8, len) };
+        checksum(payload)
+    }
+
+    // Returns the offset of the newest valid record and the first free word.
+    // A record torn before its magic went in still has its length, so it's
+    // stepped over and the next save appends after it rather than erasing.
+    fn scan(&self) -> (Option<usize>, usize) {
+        let mut latest = None;
+        let mut offset = 0;
+        while offset + 8 <= SIZE {
+            let magic = Self::read_word(offset);
+            let len = Self::read_word(offset + 4);
+            if magic == ERASED && len == ERASED {
+                break;
+            }
+            // no telling where this one ends, so treat the sector as full
+            if len == ERASED || len as usize > SIZE {
+                return (latest, SIZE);
+            }
+            let len = len as usize;
+            let next = offset + Self::record_len(len);
+            if next > SIZE {
+                return (latest, SIZE);
+            }
+            if magic == MAGIC && Self::read_word(next - 4) == Self::payload_checksum(offset, len) {
+                latest = Some(offset);
+            }
+            offset = next;
+        }
+        (latest, offset)
+    }
+
+    // Copies the newest saved payload into buf and returns its length
+    pub fn load(&self, buf: &mut [u8]) -> Option<usize> {
+        let offset = self.scan().0?;
+        let len = Self::read_word(offset + 4) as usize;
+        if len > buf.len() {
+            return None;
+        }
+        for (i, byte) in buf[..len].iter_mut().enumerate() {
+            *byte = unsafe { ptr::read_volatile((BASE + offset + 8 + i) as *const u8) };
+        }
+        Some(len)
+    }
+
+    pub fn save(&mut self, payload: &[u8]) -> Result<(), FlashError> {
+        let needed = Self::record_len(payload.len());
+        if needed > SIZE {
+            return Err(FlashError::TooLarge);
+        }
+
+        self.unlock();
+        let result = self.append(payload, needed);
+        self.lock();
+        result
+    }
+
+    fn append(&mut self, payload: &[u8], needed: usize) -> Result<(), FlashError> {
+        let blank = self.free + needed <= SIZE
+            && (self.free..self.free + needed)
+                .step_by(4)
+                .all(|offset| Self::read_word(offset) == ERASED);
+        if !blank {
+            self.erase()?;
+            self.free = 0;
+        }
+
+        let offset = self.free;
+        self.program(offset + 4, payload.len() as u32)?;
+        for (i, chunk) in payload.chunks(4).enumerate() {
+            let mut word = [0xFF; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.program(offset + 8 + i * 4, u32::from_le_bytes(word))?;
+        }
+        self.program(offset + needed - 4, checksum(payload))?;
+        self.program(offset, MAGIC)?;
+        self.free = offset + needed;
+        Ok(())
+    }
+
+    fn unlock(&mut self) {
+        if self.flash.cr.read().bits() & CR_LOCK != 0 {
+            self.flash.keyr.write(|w| unsafe { w.bits(KEY1) });
+            self.flash.keyr.write(|w| unsafe { w.bits(KEY2) });
+        }
+    }
+
+    fn lock(&mut self) {
+        self.flash
+            .cr
+            .modify(|r, w| unsafe { w.bits(r.bits() | CR_LOCK) });
+    }
+
+    fn wait(&mut self) -> Result<(), FlashError> {
+        while self.flash.sr.read().bits() & SR_BSY != 0 {}
+        let errors = self.flash.sr.read().bits() & SR_ERRORS;
+        if errors != 0 {
+            // error flags are cleared by writing ones
+            self.flash.sr.write(|w| unsafe { w.bits(errors) });
+            Err(FlashError::Program)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn erase(&mut self) -> Result<(), FlashError> {
+        self.wait()?;
+        self.flash
+            .cr
+            .write(|w| unsafe { w.bits(CR_SER | CR_PSIZE_X32 | SECTOR << CR_SNB_SHIFT) });
+        self.flash
+            .cr
+            .modify(|r, w| unsafe { w.bits(r.bits() | CR_STRT) });
+        let result = self.wait();
+        self.flash.cr.write(|w| unsafe { w.bits(0) });
+        result
+    }
+
+    fn program(&mut self, offset: usize, word: u32) -> Result<(), FlashError> {
+        self.wait()?;
+        self.flash
+            .cr
+            .write(|w| unsafe { w.bits(CR_PG | CR_PSIZE_X32) });
+        unsafe { ptr::write_volatile((BASE + offset) as *mut u32, word) };
+        cortex_m::asm::dsb();
+        let result = self.wait();
+        self.flash.cr.write(|w| unsafe { w.bits(0) });
+        result?;
+        if Self::read_word(offset) != word {
+            return Err(FlashError::Program);
+        }
+        Ok(())
+    }
+}
+
+// FNV-1a, plenty to catch a torn or stale record
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811C_9DC5, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
@@ -0,0 +1,164 @@
+pub const OUTPUTS: usize = 16;
+
+// Lower sorts first, so gates win over pitch and pitch over aux when the
+// write budget runs short
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Priority {
+    Gate,
+    Pitch,
+    Aux,
+}
+
+// Shadow copy of every DAC output. Slots are indexed dac * 4 + channel and
+// only slots whose value differs from what was last sent are written.
+#[derive(Clone)]
+pub struct OutputFrame {
+    values: [u16; OUTPUTS],
+    sent: [Option<u16>; OUTPUTS],
+    priorities: [Priority; OUTPUTS],
+    budget: u8,
+    remaining: u8,
+    next: usize,
+}
+
+impl OutputFrame {
+    pub fn new(budget: u8) -> Self {
+        let mut priorities = [Priority::Aux; OUTPUTS];
+        for dac in 0..4 {
+            priorities[dac * 4 + 3] = Priority::Gate;
+            priorities[dac * 4 + 2] = Priority::Pitch;
+        }
+        Self {
+            // the DAC driver zeroes every channel when it starts up
+            values: [0; OUTPUTS],
+            sent: [Some(0); OUTPUTS],
+            priorities,
+            budget,
+            remaining: budget,
+            next: 0,
+        }
+    }
+
+    pub fn value(&self, slot: usize) -> u16 {
+        self.values[slot]
+    }
+
+    // Returns true if the slot now needs writing
+    pub fn set(&mut self, slot: usize, value: u16) -> bool {
+        self.values[slot] = value;
+        self.is_dirty(slot)
+    }
+
+    pub fn is_dirty(&self, slot: usize) -> bool {
+        self.sent[slot] != Some(self.values[slot])
+    }
+
+    pub fn any_dirty(&self) -> bool {
+        (0..OUTPUTS).any(|slot| self.is_dirty(slot))
+    }
+
+    pub fn set_priority(&mut self, slot: usize, priority: Priority) {
+        self.priorities[slot] = priority;
+    }
+
+    pub fn set_budget(&mut self, budget: u8) {
+        self.budget = budget;
+        self.remaining = self.remaining.min(budget);
+    }
+
+    pub fn begin_frame(&mut self) {
+        self.remaining = self.budget;
+    }
+
+    // Picks the most important dirty slot, round robin within a priority so
+    // a busy pitch output can't starve the others, and marks it as sent.
+    pub fn next_write(&mut self) -> Option<(usize, u16)> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let slot = (0..OUTPUTS)
+            .map(|i| (self.next + i) % OUTPUTS)
+            .filter(|slot| self.is_dirty(*slot))
+            .min_by_key(|slot| self.priorities[*slot])?;
+        self.remaining -= 1;
+        self.next = (slot + 1) % OUTPUTS;
+        self.sent[slot] = Some(self.values[slot]);
+        Some((slot, self.values[slot]))
+    }
+
+    // A failed write leaves the DAC in an unknown state, so send it again
+    pub fn invalidate(&mut self, slot: usize) {
+        self.sent[slot] = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gates_then_pitch_then_aux() {
+        let mut frame = OutputFrame::new(8);
+        // aux, pitch and gate of the first DAC, in slot order
+        frame.set(0, 100);
+        frame.set(2, 200);
+        frame.set(3, 300);
+        assert_eq!(frame.next_write(), Some((3, 300)));
+        assert_eq!(frame.next_write(), Some((2, 200)));
+        assert_eq!(frame.next_write(), Some((0, 100)));
+        assert_eq!(frame.next_write(), None);
+    }
+
+    #[test]
+    fn sent_slots_are_clean_until_changed() {
+        let mut frame = OutputFrame::new(8);
+        assert!(!frame.set(5, 0));
+        assert!(frame.set(5, 10));
+        assert!(frame.any_dirty());
+        assert_eq!(frame.next_write(), Some((5, 10)));
+        assert!(!frame.is_dirty(5));
+        assert!(!frame.any_dirty());
+        // setting the value already sent needs no write
+        assert!(!frame.set(5, 10));
+
+        // a slot that changes and changes back before it's sent is clean
+        frame.set(5, 20);
+        assert!(!frame.set(5, 10));
+        assert_eq!(frame.next_write(), None);
+
+        frame.invalidate(5);
+        assert_eq!(frame.next_write(), Some((5, 10)));
+    }
+
+    #[test]
+    fn round_robin_within_a_priority() {
+        let mut frame = OutputFrame::new(2);
+        // the pitch outputs of all four DACs
+        let pitches = [2, 6, 10, 14];
+        for (i, slot) in pitches.iter().enumerate() {
+            frame.set(*slot, i as u16 + 1);
+        }
+        assert_eq!(frame.next_write(), Some((2, 1)));
+        assert_eq!(frame.next_write(), Some((6, 2)));
+        // out of budget until the next frame
+        assert_eq!(frame.next_write(), None);
+
+        // a busy first pitch doesn't starve the ones after it
+        frame.begin_frame();
+        frame.set(2, 100);
+        assert_eq!(frame.next_write(), Some((10, 3)));
+        assert_eq!(frame.next_write(), Some((14, 4)));
+        frame.begin_frame();
+        assert_eq!(frame.next_write(), Some((2, 100)));
+    }
+
+    #[test]
+    fn priorities_follow_the_routing() {
+        let mut frame = OutputFrame::new(8);
+        frame.set_priority(0, Priority::Gate);
+        frame.set(0, 1);
+        frame.set(3, 2);
+        assert_eq!(frame.next_write(), Some((0, 1)));
+        assert_eq!(frame.next_write(), Some((3, 2)));
+    }
+}
@@ -0,0 +1,96 @@
+use crate::midi::controllers::MAX_VALUE;
+use crate::pitch::{Pitch, SEMITONE};
+
+// Portamento time with CC5 at full
+const MAX_TIME_MS: u32 = 5000;
+const OCTAVE: i32 = 12 * SEMITONE;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GlideMode {
+    // every glide takes the portamento time
+    ConstantTime,
+    // the portamento time is per octave
+    ConstantRate,
+    // constant time, but only from a note still held
+    Legato,
+}
+
+impl GlideMode {
+    pub fn next(self) -> Self {
+        match self {
+            GlideMode::ConstantTime => GlideMode::ConstantRate,
+            GlideMode::ConstantRate => GlideMode::Legato,
+            GlideMode::Legato => GlideMode::ConstantTime,
+        }
+    }
+}
+
+// CC5 portamento time, squared so the short end gets the resolution
+pub fn portamento_ms(value: u16) -> i32 {
+    let value = value.min(MAX_VALUE) as u32;
+    (value * value / MAX_VALUE as u32 * MAX_TIME_MS / MAX_VALUE as u32) as i32
+}
+
+// Extra fraction bits so long glides over small intervals still move
+const FRACTION: u32 = 8;
+
+// Slews a voice's note pitch once per control tick. It works in pitch units
+// rather than DAC codes, so a glide moves evenly across the calibration.
+#[derive(Clone, Copy)]
+pub struct Glide {
+    // pitch << FRACTION
+    position: Option<i32>,
+    target: i32,
+    // per tick, zero jumps straight to the target
+    step: i32,
+}
+
+impl Glide {
+    pub fn new() -> Self {
+        Self {
+            position: None,
+            target: 0,
+            step: 0,
+        }
+    }
+
+    pub fn pitch(&self) -> Option<Pitch> {
+        self.position.map(|position| Pitch(position >> FRACTION))
+    }
+
+    // Heads for a new note over the portamento time in control ticks. Legato
+    // is whether the last note was still held.
+    pub fn note(&mut self, target: Pitch, mode: GlideMode, ticks: i32, legato: bool) {
+        self.target = target.0 << FRACTION;
+        let from = match self.position {
+            Some(from) => from,
+            None => {
+                self.position = Some(self.target);
+                return;
+            }
+        };
+        self.step = if ticks <= 0 || (mode == GlideMode::Legato && !legato) {
+            0
+        } else if mode == GlideMode::ConstantRate {
+            ((OCTAVE << FRACTION) / ticks).max(1)
+        } else {
+            ((self.target - from).abs() / ticks).max(1)
+        };
+        if self.step == 0 {
+            self.position = Some(self.target);
+        }
+    }
+
+    pub fn update(&mut self) {
+        if let Some(position) = &mut self.position {
+            let remaining = self.target - *position;
+            *position += remaining.clamp(-self.step, self.step);
+        }
+    }
+}
+
+impl Default for Glide {
+    fn default() -> Self {
+        Self::new()
+    }
+}
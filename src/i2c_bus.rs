@@ -0,0 +1,99 @@
+use core::cell::{Cell, RefCell};
+use cortex_m::interrupt::{self, Mutex};
+use embedded_hal::blocking::i2c::{Read, Write, WriteRead};
+
+// Owns an I2C master so several drivers can share one bus. Each driver gets
+// an I2cProxy, and every transaction runs with the bus borrowed inside a
+// critical section so transfers from different drivers never interleave.
+pub struct BusManager<I2C> {
+    bus: Mutex<RefCell<I2C>>,
+    claimed: Mutex<Cell<bool>>,
+}
+
+impl<I2C> BusManager<I2C> {
+    pub fn new(i2c: I2C) -> Self {
+        Self {
+            bus: Mutex::new(RefCell::new(i2c)),
+            claimed: Mutex::new(Cell::new(false)),
+        }
+    }
+
+    pub fn acquire(&self) -> I2cProxy<'_, I2C> {
+        I2cProxy { manager: self }
+    }
+
+    // Waits out any claim, so never call this from a context that preempts
+    // the claim holder.
+    pub fn lock<R, F>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut I2C) -> R,
+    {
+        let mut f = Some(f);
+        loop {
+            let result = interrupt::free(|cs| {
+                if self.claimed.borrow(cs).get() {
+                    None
+                } else {
+                    f.take().map(|f| f(&mut self.bus.borrow(cs).borrow_mut()))
+                }
+            });
+            if let Some(result) = result {
+                return result;
+            }
+        }
+    }
+
+    // A claim reserves the bus across several lock_claimed calls, e.g. for a
+    // transfer stepped from an interrupt.
+    pub fn try_claim(&self) -> bool {
+        interrupt::free(|cs| {
+            let claimed = self.claimed.borrow(cs);
+            !claimed.replace(true)
+        })
+    }
+
+    pub fn release(&self) {
+        interrupt::free(|cs| self.claimed.borrow(cs).set(false));
+    }
+
+    pub fn lock_claimed<R, F>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut I2C) -> R,
+    {
+        interrupt::free(|cs| f(&mut self.bus.borrow(cs).borrow_mut()))
+    }
+}
+
+pub struct I2cProxy<'a, I2C> {
+    manager: &'a BusManager<I2C>,
+}
+
+impl<I2C: Write> Write for I2cProxy<'_, I2C> {
+    type Error = I2C::Error;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.manager.lock(|i2c| i2c.write(address, bytes))
+    }
+}
+
+impl<I2C: Read> Read for I2cProxy<'_, I2C> {
+    type Error = I2C::Error;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        self.manager.lock(|i2c| i2c.read(address, buffer))
+    }
+}
+
+impl<I2C: WriteRead> WriteRead for I2cProxy<'_, I2C> {
+    type Error = I2C::Error;
+
+    fn write_read(
+        &mut self,
+        address: u8,
+        bytes: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        self.manager
+            .lock(|i2c| i2c.write_read(address, bytes, buffer))
+    }
+}
@@ -0,0 +1,1010 @@
+use crate::arpeggiator::{ArpNote, ArpParams, Arpeggiator};
+use crate::chord::{ChordParams, Harmonizer};
+use crate::clock::ClockSync;
+use crate::control::CONTROL_HZ;
+use crate::cv::CvPanel;
+use crate::envelope::{Envelope, EnvelopeParams};
+use crate::glide::{portamento_ms, Glide, GlideMode};
+use crate::lfo::{Lfo, Random, Retrigger, LFOS, LFO_DEFAULTS};
+use crate::mapping::{MappingTable, Source};
+use crate::midi::{
+    controllers::{
+        widen, ControllerEvent, ControllerState, ALL_NOTES_OFF, ALL_SOUND_OFF, CENTER_VALUE,
+        MAX_VALUE, MONO_ON, OMNI_OFF, OMNI_ON, POLY_ON, RESET_ALL_CONTROLLERS, RPN_COARSE_TUNING,
+        RPN_FINE_TUNING, RPN_MPE_CONFIGURATION, RPN_PITCH_BEND_SENSITIVITY, SOSTENUTO, SUSTAIN,
+    },
+    message::MidiMessage,
+};
+use crate::mod_matrix::{ModMatrix, ModSource, ONE};
+use crate::mode::{Mode, Target};
+use crate::pitch::{Calibration, Pitch, SEMITONE};
+use crate::quantizer::Quantizer;
+use crate::routing::Output;
+use crate::sequencer::{SeqEvent, Sequencer};
+use crate::volts::Millivolts;
+
+pub const VOICES: usize = 4;
+
+pub const GATE_ON: Millivolts = Millivolts(5000);
+pub const GATE_OFF: Millivolts = Millivolts(0);
+
+// RPN 0 data entry: whole semitones in the MSB and cents in the LSB. The
+// General MIDI default is +/-2 semitones.
+const DEFAULT_BEND_RANGE: u16 = 2 << 7;
+// MPE member channels default to +/-48 semitones
+const MEMBER_BEND_RANGE: u16 = 48 << 7;
+
+// MPE timbre, the third dimension after bend and pressure
+const TIMBRE: u8 = 74;
+const MODULATION: u8 = 1;
+const PORTAMENTO_TIME: u8 = 5;
+const EXPRESSION: u8 = 11;
+const PORTAMENTO: u8 = 65;
+
+// Sequencer tracks play on the first channel, at full velocity
+const SEQUENCER_CHANNEL: u8 = 0;
+const SEQUENCER_VELOCITY: u8 = 127;
+// Glide into a slid step
+const SLIDE_MS: i32 = 60;
+
+#[derive(Clone, Copy)]
+struct ChannelState {
+    controllers: ControllerState,
+    // latest 14-bit value of every controller
+    controls: [u16; 128],
+    // -8192..=8191
+    bend: i16,
+    bend_range: u16,
+    fine_tuning: u16,
+    coarse_tuning: u16,
+    pressure: u8,
+    poly_pressure: [u8; 128],
+    last_note: u8,
+    velocity: u8,
+    release_velocity: u8,
+    sustain: bool,
+    sostenuto: bool,
+}
+
+impl ChannelState {
+    fn new() -> Self {
+        Self {
+            controllers: ControllerState::new(),
+            controls: [0; 128],
+            bend: 0,
+            bend_range: DEFAULT_BEND_RANGE,
+            fine_tuning: CENTER_VALUE,
+            coarse_tuning: CENTER_VALUE,
+            pressure: 0,
+            poly_pressure: [0; 128],
+            last_note: 0,
+            velocity: 0,
+            release_velocity: 0,
+            sustain: false,
+            sostenuto: false,
+        }
+    }
+
+    // 14-bit value of a mapping source
+    fn source(&self, source: Source) -> u16 {
+        match source {
+            Source::Control(control) => self.controls[control as usize & 0x7F],
+            Source::ChannelPressure => widen(self.pressure),
+            Source::PolyPressure => widen(self.poly_pressure[self.last_note as usize]),
+            Source::Velocity => widen(self.velocity),
+            Source::ReleaseVelocity => widen(self.release_velocity),
+            Source::PitchBend => (self.bend + 8192) as u16,
+            // voice and LFO sources are the instrument's
+            Source::Envelope(_) | Source::Lfo(_) => 0,
+        }
+    }
+
+    // Bend plus channel tuning, added to every note on the channel
+    fn pitch_offset(&self) -> Pitch {
+        let range_cents =
+            (self.bend_range >> 7) as i32 * 100 + (self.bend_range & 0x7F).min(99) as i32;
+        // wide MPE ranges overflow an i32 before the divide
+        let bend = self.bend as i64 * range_cents as i64 * SEMITONE as i64 / (100 * 8192);
+        // fine tuning spans +/-100 cents, coarse tuning is whole semitones
+        let fine = (self.fine_tuning as i32 - CENTER_VALUE as i32) * SEMITONE / 8192;
+        let coarse = ((self.coarse_tuning >> 7) as i32 - 64) * SEMITONE;
+        Pitch(bend as i32 + fine + coarse)
+    }
+
+    // Reset All Controllers as in RP-015, which leaves volume, pan and the
+    // registered parameters alone
+    fn reset_controllers(&mut self) {
+        self.controllers.reset();
+        self.controls[MODULATION as usize] = 0;
+        self.controls[EXPRESSION as usize] = MAX_VALUE;
+        for control in SUSTAIN..=67 {
+            self.controls[control as usize] = 0;
+        }
+        self.bend = 0;
+        self.pressure = 0;
+        self.poly_pressure = [0; 128];
+        self.sustain = false;
+        self.sostenuto = false;
+    }
+
+    fn rpn(&mut self, parameter: u16) -> Option<&mut u16> {
+        match parameter {
+            RPN_PITCH_BEND_SENSITIVITY => Some(&mut self.bend_range),
+            RPN_FINE_TUNING => Some(&mut self.fine_tuning),
+            RPN_COARSE_TUNING => Some(&mut self.coarse_tuning),
+            _ => None,
+        }
+    }
+
+    fn control_change(&mut self, control: u8, value: u8) -> Option<ControllerEvent> {
+        let event = self.controllers.control_change(control, value);
+        match event {
+            Some(ControllerEvent::Control { control, value }) => {
+                self.controls[control as usize] = value;
+            }
+            Some(ControllerEvent::Rpn { parameter, value }) => {
+                if let Some(rpn) = self.rpn(parameter) {
+                    *rpn = value;
+                }
+            }
+            Some(ControllerEvent::RpnStep { parameter, delta }) => {
+                if let Some(rpn) = self.rpn(parameter) {
+                    *rpn = (*rpn as i32 + delta as i32).clamp(0, MAX_VALUE as i32) as u16;
+                }
+            }
+            _ => {}
+        }
+        event
+    }
+}
+
+// 14-bit source value across 0-10V
+fn unipolar(value: u16) -> Millivolts {
+    Millivolts(value.min(MAX_VALUE) as i32 * 10000 / MAX_VALUE as i32)
+}
+
+#[derive(Clone, Copy)]
+struct Voice {
+    channel: u8,
+    // the last note played, held after release so the pitch doesn't jump
+    note: Option<u8>,
+    // note pitch with any split transpose, before bend and tuning
+    glide: Glide,
+    envelope: Envelope,
+    velocity: u8,
+    // bipolar, for the matrix's random source
+    random: i32,
+    // 7-bit values from the sequencer step playing
+    aux: [u8; 2],
+    gate: bool,
+    // key released but the gate held by a pedal
+    sustained: bool,
+    // held when the sostenuto pedal went down
+    sostenuto: bool,
+    // allocation count when the note started, for stealing the oldest
+    started: u32,
+}
+
+impl Voice {
+    fn new() -> Self {
+        Self {
+            channel: 0,
+            note: None,
+            glide: Glide::new(),
+            envelope: Envelope::new(),
+            velocity: 0,
+            random: 0,
+            aux: [0; 2],
+            gate: false,
+            sustained: false,
+            sostenuto: false,
+            started: 0,
+        }
+    }
+
+    fn release(&mut self) {
+        self.gate = false;
+        self.sustained = false;
+        self.sostenuto = false;
+    }
+
+    // Messages on an MPE master channel also reach its members' notes
+    fn follows(&self, mode: &Mode, channel: u8) -> bool {
+        self.channel == channel || mode.master(self.channel) == Some(channel)
+    }
+}
+
+pub struct Instrument {
+    calibration: Calibration,
+    channels: [ChannelState; 16],
+    voices: [Voice; VOICES],
+    allocations: u32,
+    mode: Mode,
+    // mode to go back to when an MPE configuration message turns MPE off
+    fallback: Mode,
+    mappings: MappingTable,
+    glide_mode: GlideMode,
+    envelope: EnvelopeParams,
+    lfos: [Lfo; LFOS],
+    matrix: ModMatrix,
+    random: Random,
+    quantizer: Quantizer,
+    harmonizer: Harmonizer,
+    arp: Arpeggiator,
+    sequencer: Sequencer,
+}
+
+impl Instrument {
+    pub fn new() -> Self {
+        Self {
+            calibration: Calibration::new(),
+            channels: [ChannelState::new(); 16],
+            voices: [Voice::new(); VOICES],
+            allocations: 0,
+            mode: Mode::Omni,
+            fallback: Mode::Omni,
+            mappings: MappingTable::default(),
+            glide_mode: GlideMode::ConstantTime,
+            envelope: EnvelopeParams::default(),
+            lfos: [
+                Lfo::new(LFO_DEFAULTS[0], 1),
+                Lfo::new(LFO_DEFAULTS[1], 2),
+                Lfo::new(LFO_DEFAULTS[2], 3),
+                Lfo::new(LFO_DEFAULTS[3], 4),
+            ],
+            matrix: ModMatrix::new(),
+            random: Random::new(5),
+            quantizer: Quantizer::new(),
+            harmonizer: Harmonizer::new(),
+            arp: Arpeggiator::new(),
+            sequencer: Sequencer::new(),
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    // Notes held under the old mode would never see their note off
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+        for voice in self.voices.iter_mut() {
+            voice.release();
+        }
+        if let Mode::Mpe { .. } = mode {
+            for (channel, state) in self.channels.iter_mut().enumerate() {
+                state.bend_range = match mode.master(channel as u8) {
+                    Some(_) => MEMBER_BEND_RANGE,
+                    None => DEFAULT_BEND_RANGE,
+                };
+            }
+        }
+    }
+
+    // MPE configuration message on a zone's master channel. A zone grows at
+    // the expense of the other and both at zero leaves MPE.
+    fn configure_mpe(&mut self, channel: u8, members: u8) {
+        let (mut lower, mut upper) = match self.mode {
+            Mode::Mpe { lower, upper } => (lower, upper),
+            _ => (0, 0),
+        };
+        let members = members.min(15);
+        match channel {
+            0 => {
+                lower = members;
+                upper = upper.min(14u8.saturating_sub(lower));
+            }
+            15 => {
+                upper = members;
+                lower = lower.min(14u8.saturating_sub(upper));
+            }
+            _ => return,
+        }
+
+        let mode = if lower == 0 && upper == 0 {
+            self.fallback
+        } else {
+            Mode::Mpe { lower, upper }
+        };
+        if !matches!(self.mode, Mode::Mpe { .. }) {
+            self.fallback = self.mode;
+        }
+        if mode != self.mode {
+            self.set_mode(mode);
+        }
+    }
+
+    pub fn glide_mode(&self) -> GlideMode {
+        self.glide_mode
+    }
+
+    pub fn set_glide_mode(&mut self, glide_mode: GlideMode) {
+        self.glide_mode = glide_mode;
+    }
+
+    pub fn envelope(&self) -> EnvelopeParams {
+        self.envelope
+    }
+
+    pub fn set_envelope(&mut self, envelope: EnvelopeParams) {
+        self.envelope = envelope;
+    }
+
+    pub fn calibration(&self) -> Calibration {
+        self.calibration
+    }
+
+    pub fn set_calibration(&mut self, calibration: Calibration) {
+        self.calibration = calibration;
+    }
+
+    pub fn lfo(&self, index: usize) -> &Lfo {
+        &self.lfos[index]
+    }
+
+    pub fn lfo_mut(&mut self, index: usize) -> &mut Lfo {
+        &mut self.lfos[index]
+    }
+
+    pub fn matrix(&self) -> &ModMatrix {
+        &self.matrix
+    }
+
+    pub fn matrix_mut(&mut self) -> &mut ModMatrix {
+        &mut self.matrix
+    }
+
+    pub fn quantizer(&self) -> Quantizer {
+        self.quantizer
+    }
+
+    // Sounding notes keep their pitch, the change comes in with the next
+    pub fn set_quantizer(&mut self, quantizer: Quantizer) {
+        self.quantizer = quantizer;
+    }
+
+    pub fn chord(&self) -> ChordParams {
+        self.harmonizer.params()
+    }
+
+    pub fn set_chord(&mut self, params: ChordParams) {
+        if let Some(tones) = self.harmonizer.set_params(params) {
+            self.chord_off(&tones);
+        }
+    }
+
+    pub fn arp(&self) -> ArpParams {
+        self.arp.params()
+    }
+
+    pub fn set_arp(&mut self, params: ArpParams) {
+        if let Some(note) = self.arp.set_params(params) {
+            self.arp_off(note);
+        }
+    }
+
+    pub fn sequencer(&self) -> &Sequencer {
+        &self.sequencer
+    }
+
+    pub fn sequencer_mut(&mut self) -> &mut Sequencer {
+        &mut self.sequencer
+    }
+
+    pub fn mappings(&self) -> &MappingTable {
+        &self.mappings
+    }
+
+    pub fn mappings_mut(&mut self) -> &mut MappingTable {
+        &mut self.mappings
+    }
+
+    pub fn handle(&mut self, message: MidiMessage) {
+        match message {
+            MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => {
+                let state = &mut self.channels[channel as usize];
+                state.last_note = note;
+                state.velocity = velocity;
+                self.sequencer.record(note, velocity);
+                if self.harmonizer.is_enabled() {
+                    self.chord_on(channel, note, velocity);
+                    return;
+                }
+                // the arpeggiator plays held notes back on its own steps
+                if self.arp.is_enabled() {
+                    self.arp.note_on(channel, note, velocity);
+                    return;
+                }
+                self.restart_lfos(Retrigger::Note);
+                let mode = self.mode;
+                mode.targets(channel, note, VOICES, |target| {
+                    self.note_on(target, channel, note)
+                });
+            }
+            MidiMessage::NoteOff {
+                channel,
+                note,
+                velocity,
+            } => {
+                self.channels[channel as usize].release_velocity = velocity;
+                if self.harmonizer.is_enabled() {
+                    if let Some(tones) = self.harmonizer.note_off(channel, note) {
+                        self.chord_off(&tones);
+                    }
+                    return;
+                }
+                if self.arp.is_enabled() {
+                    self.arp.note_off(note);
+                    return;
+                }
+                for index in 0..VOICES {
+                    let voice = &self.voices[index];
+                    if voice.channel == channel
+                        && voice.note == Some(note)
+                        && !self.sequencer.owns(index)
+                    {
+                        self.key_up(index);
+                    }
+                }
+            }
+            MidiMessage::ControlChange {
+                channel,
+                control,
+                value,
+            } => match self.channels[channel as usize].control_change(control, value) {
+                Some(ControllerEvent::Control {
+                    control: SUSTAIN,
+                    value,
+                }) => self.set_sustain(channel, value >= CENTER_VALUE),
+                Some(ControllerEvent::Control {
+                    control: SOSTENUTO,
+                    value,
+                }) => self.set_sostenuto(channel, value >= CENTER_VALUE),
+                Some(ControllerEvent::Rpn {
+                    parameter: RPN_MPE_CONFIGURATION,
+                    value,
+                }) => self.configure_mpe(channel, (value >> 7) as u8),
+                Some(ControllerEvent::Control { control, .. }) if control >= ALL_SOUND_OFF => {
+                    self.channel_mode(channel, control)
+                }
+                Some(ControllerEvent::Control { control, value }) => {
+                    self.envelope.control(control, value);
+                    self.quantizer.control(control, value);
+                    if let Some(tones) = self.harmonizer.control(control, value) {
+                        self.chord_off(&tones);
+                    }
+                }
+                Some(ControllerEvent::Nrpn { parameter, value }) => {
+                    self.matrix.nrpn(parameter, value)
+                }
+                _ => {}
+            },
+            MidiMessage::Start => {
+                self.restart_lfos(Retrigger::Start);
+                self.sequencer.transport(message);
+            }
+            MidiMessage::Continue | MidiMessage::Stop => self.sequencer.transport(message),
+            MidiMessage::PitchBend { channel, value } => {
+                self.channels[channel as usize].bend = value as i16 - 8192;
+            }
+            MidiMessage::ChannelPressure { channel, pressure } => {
+                self.channels[channel as usize].pressure = pressure;
+            }
+            MidiMessage::PolyPressure {
+                channel,
+                note,
+                pressure,
+            } => {
+                self.channels[channel as usize].poly_pressure[note as usize] = pressure;
+            }
+            _ => {}
+        }
+    }
+
+    // A pedal on an MPE master channel also holds its member channels
+    fn sustain_down(&self, channel: u8) -> bool {
+        self.channels[channel as usize].sustain
+            || matches!(self.mode.master(channel), Some(master) if self.channels[master as usize].sustain)
+    }
+
+    fn set_sustain(&mut self, channel: u8, down: bool) {
+        self.channels[channel as usize].sustain = down;
+        if !down {
+            self.release_sustained();
+        }
+    }
+
+    // Only notes with their key down as the pedal goes down are caught
+    fn set_sostenuto(&mut self, channel: u8, down: bool) {
+        let state = &mut self.channels[channel as usize];
+        if state.sostenuto == down {
+            return;
+        }
+        state.sostenuto = down;
+        let mode = self.mode;
+        for voice in self.voices.iter_mut() {
+            if voice.follows(&mode, channel) {
+                voice.sostenuto = down && voice.gate && !voice.sustained;
+            }
+        }
+        if !down {
+            self.release_sustained();
+        }
+    }
+
+    // Key released, a pedal may keep the gate up
+    fn key_up(&mut self, index: usize) {
+        let voice = self.voices[index];
+        if !voice.gate || voice.sustained {
+            return;
+        }
+        if voice.sostenuto || self.sustain_down(voice.channel) {
+            self.voices[index].sustained = true;
+        } else {
+            self.voices[index].release();
+        }
+    }
+
+    fn channel_mode(&mut self, channel: u8, control: u8) {
+        let mode = self.mode;
+        match control {
+            // gates drop at once, whatever the pedals say
+            ALL_SOUND_OFF => {
+                for voice in self.voices.iter_mut() {
+                    if voice.follows(&mode, channel) {
+                        voice.release();
+                    }
+                }
+            }
+            RESET_ALL_CONTROLLERS => {
+                self.channels[channel as usize].reset_controllers();
+                for voice in self.voices.iter_mut() {
+                    if voice.follows(&mode, channel) {
+                        voice.sostenuto = false;
+                    }
+                }
+                self.release_sustained();
+            }
+            // the mode changes imply All Notes Off, local control means nothing here
+            ALL_NOTES_OFF..=POLY_ON => {
+                if let Some(tones) = self.harmonizer.clear() {
+                    self.chord_off(&tones);
+                }
+                if let Some(note) = self.arp.clear() {
+                    self.arp_off(note);
+                }
+                for index in 0..VOICES {
+                    if self.voices[index].follows(&mode, channel) {
+                        self.key_up(index);
+                    }
+                }
+                let mode = match (control, mode) {
+                    (OMNI_OFF, _) => Mode::Single(channel),
+                    (OMNI_ON, _) => Mode::Omni,
+                    (MONO_ON, _) => Mode::PerVoice { base: channel },
+                    (POLY_ON, Mode::Omni) => Mode::Omni,
+                    (POLY_ON, _) => Mode::Single(channel),
+                    (_, mode) => mode,
+                };
+                if mode != self.mode {
+                    self.set_mode(mode);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Drops every gate and lets go of the pedals, for when the host goes away
+    pub fn panic(&mut self) {
+        self.harmonizer.clear();
+        self.arp.clear();
+        for voice in self.voices.iter_mut() {
+            voice.release();
+        }
+        for state in self.channels.iter_mut() {
+            state.sustain = false;
+            state.sostenuto = false;
+        }
+    }
+
+    // Drops the gates of released notes no pedal holds any more
+    fn release_sustained(&mut self) {
+        for index in 0..VOICES {
+            let voice = self.voices[index];
+            if voice.sustained && !voice.sostenuto && !self.sustain_down(voice.channel) {
+                self.voices[index].release();
+            }
+        }
+    }
+
+    fn note_on(&mut self, target: Target, channel: u8, note: u8) {
+        let (index, transpose) = match target {
+            Target::Poly => match self.allocate(channel, note) {
+                Some(index) => (index, 0),
+                None => return,
+            },
+            Target::Voice { voice, transpose } => (voice, transpose),
+        };
+        if self.sequencer.owns(index) {
+            return;
+        }
+        // portamento follows the channel the new note arrived on
+        let state = &self.channels[channel as usize];
+        let ticks = if state.controls[PORTAMENTO as usize] >= CENTER_VALUE {
+            portamento_ms(state.controls[PORTAMENTO_TIME as usize]) * CONTROL_HZ as i32 / 1000
+        } else {
+            0
+        };
+        let pitch = Pitch::from_note(self.quantizer.process(note))
+            .offset(Pitch(transpose as i32 * SEMITONE));
+        let velocity = state.velocity;
+        self.play(
+            index,
+            channel,
+            note,
+            pitch,
+            velocity,
+            (self.glide_mode, ticks),
+        );
+    }
+
+    fn play(
+        &mut self,
+        index: usize,
+        channel: u8,
+        note: u8,
+        pitch: Pitch,
+        velocity: u8,
+        (glide_mode, ticks): (GlideMode, i32),
+    ) {
+        let previous = self.voices[index];
+        let mut glide = previous.glide;
+        glide.note(pitch, glide_mode, ticks, previous.gate);
+        let mut envelope = previous.envelope;
+        envelope.trigger(velocity, &self.envelope);
+        let random = self.random.bipolar() * 2;
+
+        self.allocations = self.allocations.wrapping_add(1);
+        self.voices[index] = Voice {
+            channel,
+            note: Some(note),
+            glide,
+            envelope,
+            velocity,
+            random,
+            aux: previous.aux,
+            gate: true,
+            sustained: false,
+            sostenuto: false,
+            started: self.allocations,
+        };
+    }
+
+    // Retriggers a voice already on the note, otherwise takes the voice
+    // released longest ago, then the oldest note only a pedal is holding,
+    // then steals the oldest note. Voices the sequencer owns are left alone.
+    fn allocate(&self, channel: u8, note: u8) -> Option<usize> {
+        let age = |voice: &Voice| self.allocations.wrapping_sub(voice.started);
+        let voices = self
+            .voices
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| !self.sequencer.owns(*index));
+        if let Some((index, _)) = voices
+            .clone()
+            .find(|(_, voice)| voice.channel == channel && voice.note == Some(note))
+        {
+            return Some(index);
+        }
+        voices
+            .clone()
+            .filter(|(_, voice)| !voice.gate)
+            .max_by_key(|(_, voice)| age(voice))
+            .or_else(|| {
+                voices
+                    .clone()
+                    .filter(|(_, voice)| voice.sustained)
+                    .max_by_key(|(_, voice)| age(voice))
+            })
+            .or_else(|| voices.max_by_key(|(_, voice)| age(voice)))
+            .map(|(index, _)| index)
+    }
+
+    // Chord tones go to a voice each, or into the arpeggiator
+    fn chord_on(&mut self, channel: u8, note: u8, velocity: u8) {
+        let (scale, key) = (self.quantizer.scale, self.quantizer.root);
+        let (previous, tones) = self.harmonizer.note_on(channel, note, scale, key);
+        if self.arp.is_enabled() {
+            for tone in previous.iter().flatten() {
+                self.arp.note_off(*tone);
+            }
+            for tone in tones.iter() {
+                self.arp.note_on(channel, *tone, velocity);
+            }
+            return;
+        }
+        // a new chord glides on from the last, voice by voice
+        self.restart_lfos(Retrigger::Note);
+        for (voice, tone) in tones.iter().enumerate() {
+            let target = Target::Voice {
+                voice,
+                transpose: 0,
+            };
+            self.note_on(target, channel, *tone);
+        }
+    }
+
+    fn chord_off(&mut self, tones: &[u8; VOICES]) {
+        if self.arp.is_enabled() {
+            for tone in tones.iter() {
+                self.arp.note_off(*tone);
+            }
+            return;
+        }
+        for (index, tone) in tones.iter().enumerate() {
+            if self.voices[index].note == Some(*tone) && !self.sequencer.owns(index) {
+                self.key_up(index);
+            }
+        }
+    }
+
+    // Arpeggiated notes skip the pedals, the gate length is the arpeggiator's
+    fn arp_off(&mut self, note: u8) {
+        for index in 0..VOICES {
+            let voice = &mut self.voices[index];
+            if voice.gate && voice.note == Some(note) && !self.sequencer.owns(index) {
+                voice.release();
+            }
+        }
+    }
+
+    // A tied step only moves the pitch, the gate and envelope carry on
+    fn sequence(&mut self, index: usize, event: SeqEvent) {
+        if event.off {
+            self.voices[index].release();
+        }
+        let step = match event.on {
+            Some(step) => step,
+            None => return,
+        };
+        let ticks = if step.slide {
+            SLIDE_MS * CONTROL_HZ as i32 / 1000
+        } else {
+            0
+        };
+        let pitch = Pitch::from_note(self.quantizer.process(step.note));
+        if step.legato {
+            let voice = &mut self.voices[index];
+            voice
+                .glide
+                .note(pitch, GlideMode::ConstantTime, ticks, true);
+            voice.note = Some(step.note);
+        } else {
+            self.play(
+                index,
+                SEQUENCER_CHANNEL,
+                step.note,
+                pitch,
+                SEQUENCER_VELOCITY,
+                (GlideMode::ConstantTime, ticks),
+            );
+        }
+        self.voices[index].aux = step.aux;
+    }
+
+    fn arp_on(&mut self, step: ArpNote) {
+        let channel = self.arp.channel();
+        self.channels[channel as usize].velocity = step.velocity;
+        self.restart_lfos(Retrigger::Note);
+        match step.voice {
+            Some(voice) => self.note_on(
+                Target::Voice {
+                    voice,
+                    transpose: 0,
+                },
+                channel,
+                step.note,
+            ),
+            None => {
+                let mode = self.mode;
+                mode.targets(channel, step.note, VOICES, |target| {
+                    self.note_on(target, channel, step.note)
+                });
+            }
+        }
+    }
+
+    fn restart_lfos(&mut self, retrigger: Retrigger) {
+        for lfo in self.lfos.iter_mut() {
+            if lfo.params().retrigger == retrigger {
+                lfo.restart();
+            }
+        }
+    }
+
+    // An LFO's bipolar value at its depth, under the channel's mod wheel
+    fn lfo_value(&self, index: usize, channel: u8) -> i32 {
+        let params = self.lfos[index].params();
+        let value = self.lfos[index].value() * params.depth as i32 / 100;
+        if params.mod_wheel {
+            let depth = self.channels[channel as usize & 0x0F].controls[MODULATION as usize];
+            value * depth as i32 / MAX_VALUE as i32
+        } else {
+            value
+        }
+    }
+
+    fn vibrato(&self, channel: u8) -> Pitch {
+        let mut vibrato = 0;
+        for index in 0..LFOS {
+            let cents = self.lfos[index].params().vibrato as i32;
+            if cents != 0 {
+                vibrato += self.lfo_value(index, channel) * cents * SEMITONE / (100 * 8192);
+            }
+        }
+        Pitch(vibrato)
+    }
+
+    fn mod_source(&self, index: usize, source: ModSource) -> i32 {
+        let voice = &self.voices[index];
+        let channel = &self.channels[voice.channel as usize];
+        match source {
+            ModSource::Velocity => widen(voice.velocity) as i32,
+            ModSource::Control(control) => channel.controls[control as usize & 0x7F] as i32,
+            ModSource::Aftertouch => {
+                let poly = voice
+                    .note
+                    .map_or(0, |note| channel.poly_pressure[note as usize]);
+                widen(channel.pressure.max(poly)) as i32
+            }
+            ModSource::PitchBend => channel.bend as i32 * 2,
+            ModSource::KeyTrack => voice.note.map_or(0, |note| note as i32 * ONE / 120),
+            ModSource::Lfo(lfo) if (lfo as usize) < LFOS => {
+                self.lfo_value(lfo as usize, voice.channel) * 2
+            }
+            ModSource::Lfo(_) => 0,
+            ModSource::Envelope => voice.envelope.level(&self.envelope) as i32,
+            ModSource::Random => voice.random,
+        }
+    }
+
+    // One control tick, CONTROL_HZ times a second
+    pub fn update(&mut self, sync: &ClockSync) {
+        for lfo in self.lfos.iter_mut() {
+            lfo.update(sync);
+        }
+        let step = self.arp.update(sync);
+        if let Some(note) = step.off {
+            self.arp_off(note);
+        }
+        if let Some(note) = step.on {
+            self.arp_on(note);
+        }
+        let events = self.sequencer.update(sync);
+        for (index, event) in events.iter().enumerate() {
+            self.sequence(index, *event);
+        }
+        for voice in self.voices.iter_mut() {
+            voice.glide.update();
+            voice.envelope.update(voice.gate, &self.envelope);
+        }
+        // the sources need the rest of the instrument
+        let mut matrix = self.matrix;
+        matrix.evaluate(|voice, source| self.mod_source(voice, source));
+        self.matrix = matrix;
+    }
+
+    // Everything but pitch goes through the matrix on the way out
+    fn set_output(&self, cv_panel: &mut CvPanel, output: Output, voltage: Millivolts) {
+        cv_panel
+            .output(output)
+            .set_millivolts(self.matrix.modulate(output, voltage));
+    }
+
+    pub fn render(&self, cv_panel: &mut CvPanel) {
+        let mpe = matches!(self.mode, Mode::Mpe { .. });
+        // the voices' aux outputs follow their notes' expression in MPE, or
+        // their sequencer track
+        let expression = |output| match output {
+            Output::Aux(aux) if (aux as usize) < 2 * VOICES => {
+                mpe || self.sequencer.owns(aux as usize / 2)
+            }
+            _ => false,
+        };
+        for (index, voice) in self.voices.iter().enumerate() {
+            if let Some(pitch) = voice.glide.pitch() {
+                let channel = &self.channels[voice.channel as usize];
+                let mut pitch = pitch
+                    .offset(channel.pitch_offset())
+                    .offset(self.vibrato(voice.channel))
+                    .offset(self.matrix.fine_tune(index));
+                // an offset on a pitch output is 1V/octave
+                let offset = self.matrix.offset(Output::Pitch(index as u8));
+                pitch = pitch.offset(Pitch(offset.0 * 12 * SEMITONE / 1000));
+                // MPE notes also follow their zone's master channel
+                if let Some(master) = self.mode.master(voice.channel) {
+                    pitch = pitch.offset(self.channels[master as usize].pitch_offset());
+                }
+                cv_panel.pitch(index).set(self.calibration.code(pitch));
+
+                if mpe && !self.sequencer.owns(index) {
+                    let aux = index as u8 * 2;
+                    let pressure = unipolar(widen(channel.pressure));
+                    let timbre = unipolar(channel.controls[TIMBRE as usize]);
+                    self.set_output(cv_panel, Output::Aux(aux), pressure);
+                    self.set_output(cv_panel, Output::Aux(aux + 1), timbre);
+                }
+            }
+            if self.sequencer.owns(index) {
+                for (offset, value) in voice.aux.iter().enumerate() {
+                    let output = Output::Aux(index as u8 * 2 + offset as u8);
+                    self.set_output(cv_panel, output, unipolar(widen(*value)));
+                }
+            }
+            let gate = if voice.gate { GATE_ON } else { GATE_OFF };
+            self.set_output(cv_panel, Output::Gate(index as u8), gate);
+        }
+
+        for mapping in self.mappings.iter() {
+            if expression(mapping.output) {
+                continue;
+            }
+            let value = match mapping.source {
+                Source::Envelope(voice) => self
+                    .voices
+                    .get(voice as usize)
+                    .map_or(0, |voice| voice.envelope.level(&self.envelope)),
+                Source::Lfo(index) if (index as usize) < LFOS => {
+                    (self.lfo_value(index as usize, mapping.channel) + 8192) as u16
+                }
+                Source::Lfo(_) => 0,
+                source => self.channels[mapping.channel as usize & 0x0F].source(source),
+            };
+            self.set_output(cv_panel, mapping.output, mapping.scale(value));
+        }
+
+        // aux outputs with nothing else on them sit at the matrix's offsets
+        for output in self.matrix.offset_outputs() {
+            let mapped = self.mappings.iter().any(|mapping| mapping.output == output);
+            if matches!(output, Output::Aux(_)) && !mapped && !expression(output) {
+                self.set_output(cv_panel, output, Millivolts(0));
+            }
+        }
+    }
+}
+
+impl Default for Instrument {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_bend_over_member_range() {
+        let mut channel = ChannelState::new();
+        channel.bend_range = MEMBER_BEND_RANGE;
+        channel.bend = -8192;
+        assert_eq!(channel.pitch_offset(), Pitch(-48 * SEMITONE));
+        channel.bend = 8191;
+        assert_eq!(channel.pitch_offset(), Pitch(48 * SEMITONE * 8191 / 8192));
+        channel.bend = 0;
+        assert_eq!(channel.pitch_offset(), Pitch(0));
+    }
+
+    #[test]
+    fn bend_range_cents() {
+        let mut channel = ChannelState::new();
+        // a semitone and a half
+        channel.bend_range = 1 << 7 | 50;
+        channel.bend = -8192;
+        assert_eq!(channel.pitch_offset(), Pitch(-3 * SEMITONE / 2));
+    }
+}
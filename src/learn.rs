@@ -0,0 +1,272 @@
+use crate::mapping::{Mapping, MappingTable, Source};
+use crate::midi::{controllers, message::MidiMessage};
+use crate::routing::Output;
+
+// Outputs the encoder steps through while selecting
+const LEARNABLE: [Output; 8] = [
+    Output::Aux(0),
+    Output::Aux(1),
+    Output::Aux(2),
+    Output::Aux(3),
+    Output::Aux(4),
+    Output::Aux(5),
+    Output::Aux(6),
+    Output::Aux(7),
+];
+
+// Holding select this long while armed clears the output's mapping
+const CLEAR_HOLD_MS: u32 = 1500;
+// An armed learn that hears nothing gives up after this long
+const ARMED_TIMEOUT_MS: u32 = 10_000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LearnEvent {
+    Select(Output),
+    Armed(Output),
+    Bind(Mapping),
+    Clear(Output),
+    Cancel,
+}
+
+impl LearnEvent {
+    // Returns true if the mappings changed and should be saved
+    pub fn apply(&self, mappings: &mut MappingTable) -> bool {
+        match self {
+            LearnEvent::Bind(mapping) => mappings.assign(*mapping),
+            LearnEvent::Clear(output) => {
+                mappings.clear_output(*output);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Idle,
+    // select held, learn starts once the encoder turns
+    Selecting { turned: bool },
+    Armed { since: u32 },
+    // select pressed again while armed: release to cancel, hold to clear
+    ArmedHeld { since: u32 },
+    // wait for select to be let go after a clear
+    Release,
+}
+
+// MIDI learn driven by the encoder: hold select and turn to pick an output,
+// release, then the next CC, aftertouch or pitch bend is bound to it. While
+// armed, a short press cancels and a long press clears the output's mapping.
+pub struct Learn {
+    state: State,
+    selected: usize,
+}
+
+impl Learn {
+    pub fn new() -> Self {
+        Self {
+            state: State::Idle,
+            selected: 0,
+        }
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.state == State::Idle
+    }
+
+    pub fn is_armed(&self) -> bool {
+        matches!(self.state, State::Armed { .. })
+    }
+
+    fn output(&self) -> Output {
+        LEARNABLE[self.selected]
+    }
+
+    fn select(&mut self, steps: i32) -> LearnEvent {
+        let count = LEARNABLE.len() as i32;
+        self.selected = (self.selected as i32 + steps).rem_euclid(count) as usize;
+        LearnEvent::Select(self.output())
+    }
+
+    // Call regularly with the select button state and encoder detents
+    // turned since the last call
+    pub fn update(&mut self, pressed: bool, steps: i32, now: u32) -> Option<LearnEvent> {
+        match self.state {
+            State::Idle if pressed => {
+                self.state = State::Selecting { turned: false };
+                None
+            }
+            State::Idle => None,
+            State::Selecting { .. } if pressed && steps != 0 => {
+                self.state = State::Selecting { turned: true };
+                Some(self.select(steps))
+            }
+            State::Selecting { turned } if !pressed => {
+                if turned {
+                    self.state = State::Armed { since: now };
+                    Some(LearnEvent::Armed(self.output()))
+                } else {
+                    self.state = State::Idle;
+                    None
+                }
+            }
+            State::Selecting { .. } => None,
+            State::Armed { since } => {
+                if pressed {
+                    self.state = State::ArmedHeld { since: now };
+                    None
+                } else if now.wrapping_sub(since) >= ARMED_TIMEOUT_MS {
+                    self.state = State::Idle;
+                    Some(LearnEvent::Cancel)
+                } else {
+                    None
+                }
+            }
+            State::ArmedHeld { .. } if steps != 0 => {
+                // turning again goes back to picking an output
+                self.state = State::Selecting { turned: true };
+                Some(self.select(steps))
+            }
+            State::ArmedHeld { .. } if !pressed => {
+                self.state = State::Idle;
+                Some(LearnEvent::Cancel)
+            }
+            State::ArmedHeld { since } => {
+                if now.wrapping_sub(since) >= CLEAR_HOLD_MS {
+                    self.state = State::Release;
+                    Some(LearnEvent::Clear(self.output()))
+                } else {
+                    None
+                }
+            }
+            State::Release => {
+                if !pressed {
+                    self.state = State::Idle;
+                }
+                None
+            }
+        }
+    }
+
+    // Offer each incoming message while armed, binds the first usable one
+    pub fn capture(&mut self, message: MidiMessage) -> Option<LearnEvent> {
+        if !self.is_armed() {
+            return None;
+        }
+        let (channel, source) = match message {
+            // parameter selection, data entry, the pedals and channel mode
+            // messages are the instrument's own and never reach a mapping
+            MidiMessage::ControlChange { control, .. } if is_reserved_control(control) => {
+                return None
+            }
+            // LSBs of paired controllers learn as their MSB
+            MidiMessage::ControlChange {
+                channel, control, ..
+            } if (32..64).contains(&control) => (channel, Source::Control(control - 32)),
+            MidiMessage::ControlChange {
+                channel, control, ..
+            } => (channel, Source::Control(control)),
+            MidiMessage::ChannelPressure { channel, .. } => (channel, Source::ChannelPressure),
+            MidiMessage::PolyPressure { channel, .. } => (channel, Source::PolyPressure),
+            MidiMessage::PitchBend { channel, .. } => (channel, Source::PitchBend),
+            _ => return None,
+        };
+        self.state = State::Idle;
+        Some(LearnEvent::Bind(Mapping::new(
+            source,
+            channel,
+            self.output(),
+        )))
+    }
+}
+
+fn is_reserved_control(control: u8) -> bool {
+    control >= controllers::ALL_SOUND_OFF
+        || matches!(
+            control,
+            controllers::SUSTAIN
+                | controllers::SOSTENUTO
+                | controllers::DATA_ENTRY_MSB
+                | controllers::DATA_ENTRY_LSB
+                | controllers::DATA_INCREMENT
+                | controllers::DATA_DECREMENT
+                | controllers::NRPN_LSB
+                | controllers::NRPN_MSB
+                | controllers::RPN_LSB
+                | controllers::RPN_MSB
+        )
+}
+
+impl Default for Learn {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn armed() -> Learn {
+        let mut learn = Learn::new();
+        learn.update(true, 0, 0);
+        assert_eq!(
+            learn.update(true, 1, 0),
+            Some(LearnEvent::Select(Output::Aux(1)))
+        );
+        assert_eq!(
+            learn.update(false, 0, 0),
+            Some(LearnEvent::Armed(Output::Aux(1)))
+        );
+        learn
+    }
+
+    fn cc(control: u8) -> MidiMessage {
+        MidiMessage::ControlChange {
+            channel: 0,
+            control,
+            value: 64,
+        }
+    }
+
+    #[test]
+    fn reserved_controls_are_not_learned() {
+        let mut learn = armed();
+        for control in [
+            controllers::SUSTAIN,
+            controllers::SOSTENUTO,
+            controllers::DATA_ENTRY_MSB,
+            controllers::NRPN_MSB,
+            120,
+            122,
+            127,
+        ]
+        .iter()
+        {
+            assert_eq!(learn.capture(cc(*control)), None);
+            assert!(learn.is_armed());
+        }
+        assert_eq!(
+            learn.capture(cc(74)),
+            Some(LearnEvent::Bind(Mapping::new(
+                Source::Control(74),
+                0,
+                Output::Aux(1)
+            )))
+        );
+        assert!(learn.is_idle());
+    }
+
+    #[test]
+    fn lsbs_learn_as_their_msb() {
+        let mut learn = armed();
+        assert_eq!(
+            learn.capture(cc(33)),
+            Some(LearnEvent::Bind(Mapping::new(
+                Source::Control(1),
+                0,
+                Output::Aux(1)
+            )))
+        );
+    }
+}
@@ -0,0 +1,370 @@
+use crate::clock::{ClockSync, Division, PPQN};
+use crate::control::CONTROL_HZ;
+
+pub const LFOS: usize = 4;
+
+// Bipolar values run -8192..=8191 like pitch bend
+const MIN: i32 = -8192;
+const MAX: i32 = 8191;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Shape {
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+    SampleHold,
+    SmoothRandom,
+}
+
+impl Shape {
+    fn step(self, steps: i32) -> Self {
+        const SHAPES: [Shape; 6] = [
+            Shape::Sine,
+            Shape::Triangle,
+            Shape::Saw,
+            Shape::Square,
+            Shape::SampleHold,
+            Shape::SmoothRandom,
+        ];
+        let index = SHAPES.iter().position(|shape| *shape == self).unwrap_or(0) as i32;
+        SHAPES[(index + steps).rem_euclid(SHAPES.len() as i32) as usize]
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Rate {
+    // hundredths of a hertz
+    Hz(u16),
+    // one cycle per division of the followed clock
+    Sync(Division),
+}
+
+impl Rate {
+    // Slowest first, turning past the fastest free rate goes on to the
+    // synced ones from four bars down to an eighth note triplet
+    fn step(self, steps: i32) -> Self {
+        const RATES: [Rate; 17] = [
+            Rate::Hz(5),
+            Rate::Hz(10),
+            Rate::Hz(25),
+            Rate::Hz(50),
+            Rate::Hz(100),
+            Rate::Hz(200),
+            Rate::Hz(350),
+            Rate::Hz(550),
+            Rate::Hz(800),
+            Rate::Hz(1200),
+            Rate::Hz(2000),
+            Rate::Sync(Division(384)),
+            Rate::Sync(Division::BAR),
+            Rate::Sync(Division(48)),
+            Rate::Sync(Division::QUARTER),
+            Rate::Sync(Division::PPQN_2),
+            Rate::Sync(Division(8)),
+        ];
+        let index = RATES.iter().position(|rate| *rate == self).unwrap_or(0) as i32;
+        RATES[(index + steps).clamp(0, RATES.len() as i32 - 1) as usize]
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Retrigger {
+    Free,
+    Note,
+    Start,
+}
+
+// What the LFO page edits, by LFO
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LfoParam {
+    Shape(u8),
+    Rate(u8),
+    Depth(u8),
+}
+
+impl LfoParam {
+    pub fn next(self) -> Self {
+        match self {
+            LfoParam::Shape(lfo) => LfoParam::Rate(lfo),
+            LfoParam::Rate(lfo) => LfoParam::Depth(lfo),
+            LfoParam::Depth(lfo) => LfoParam::Shape((lfo + 1) % LFOS as u8),
+        }
+    }
+
+    pub fn lfo(self) -> usize {
+        match self {
+            LfoParam::Shape(lfo) | LfoParam::Rate(lfo) | LfoParam::Depth(lfo) => lfo as usize,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LfoParams {
+    pub shape: Shape,
+    pub rate: Rate,
+    pub retrigger: Retrigger,
+    // pitch swing at full depth in cents, zero keeps the LFO off the pitch
+    pub vibrato: i16,
+    // the channel's mod wheel sets the depth
+    pub mod_wheel: bool,
+    // percent of full swing, before the mod wheel
+    pub depth: u8,
+}
+
+impl LfoParams {
+    pub const fn new(shape: Shape, rate: Rate, retrigger: Retrigger) -> Self {
+        Self {
+            shape,
+            rate,
+            retrigger,
+            vibrato: 0,
+            mod_wheel: false,
+            depth: 100,
+        }
+    }
+
+    pub fn adjust(&mut self, param: LfoParam, steps: i32) {
+        match param {
+            LfoParam::Shape(_) => self.shape = self.shape.step(steps),
+            LfoParam::Rate(_) => self.rate = self.rate.step(steps),
+            LfoParam::Depth(_) => self.depth = (self.depth as i32 + steps * 5).clamp(0, 100) as u8,
+        }
+    }
+}
+
+// Mod wheel vibrato, then a beat, a bar and a slow wander for the aux outputs
+pub const LFO_DEFAULTS: [LfoParams; LFOS] = [
+    LfoParams {
+        vibrato: 50,
+        mod_wheel: true,
+        ..LfoParams::new(Shape::Sine, Rate::Hz(550), Retrigger::Free)
+    },
+    LfoParams::new(
+        Shape::Triangle,
+        Rate::Sync(Division::QUARTER),
+        Retrigger::Start,
+    ),
+    LfoParams::new(Shape::Saw, Rate::Sync(Division::BAR), Retrigger::Start),
+    LfoParams::new(Shape::SmoothRandom, Rate::Hz(50), Retrigger::Free),
+];
+
+// Xorshift, plenty for modulation
+#[derive(Clone, Copy)]
+pub struct Random(u32);
+
+impl Random {
+    pub fn new(seed: u32) -> Self {
+        Random(seed.max(1))
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+
+    pub fn bipolar(&mut self) -> i32 {
+        (self.next_u32() >> 18) as i32 + MIN
+    }
+}
+
+// A parabola through the peaks and zero crossings, pulled towards the sine
+fn sine(phase: u32) -> i32 {
+    // half a cycle either side of zero in 14 bits
+    let x = phase as i32 >> 17;
+    let y = (4 * x * (16384 - x.abs())) >> 14;
+    let y = y + (y * y.abs() / 16384 - y) * 225 / 1000;
+    (y / 2).clamp(MIN, MAX)
+}
+
+#[derive(Clone, Copy)]
+pub struct Lfo {
+    params: LfoParams,
+    // a whole cycle is the full u32 range
+    phase: u32,
+    random: Random,
+    // the random shapes step to or glide towards `to` each cycle
+    from: i32,
+    to: i32,
+    value: i32,
+    // clock position seen last, synced LFOs realign on their division
+    position: u32,
+}
+
+impl Lfo {
+    pub fn new(params: LfoParams, seed: u32) -> Self {
+        let mut random = Random::new(seed);
+        let to = random.bipolar();
+        Self {
+            params,
+            phase: 0,
+            random,
+            from: 0,
+            to,
+            value: 0,
+            position: 0,
+        }
+    }
+
+    pub fn params(&self) -> LfoParams {
+        self.params
+    }
+
+    pub fn set_params(&mut self, params: LfoParams) {
+        self.params = params;
+    }
+
+    pub fn value(&self) -> i32 {
+        self.value
+    }
+
+    pub fn restart(&mut self) {
+        self.phase = 0;
+        self.next_cycle();
+    }
+
+    fn next_cycle(&mut self) {
+        self.from = self.to;
+        self.to = self.random.bipolar();
+    }
+
+    // Phase per control tick
+    fn increment(&mut self, sync: &ClockSync) -> u32 {
+        match self.params.rate {
+            Rate::Hz(centihertz) => ((centihertz as u64) << 32) / (100 * CONTROL_HZ as u64),
+            Rate::Sync(division) => {
+                let ticks = division.0.max(1) as u32;
+                if sync.step(self.position, division).is_some() {
+                    self.restart();
+                } else if sync.position.wrapping_sub(self.position) > 1 {
+                    // a relocate or loop lands partway through a cycle
+                    if let Some(tick) = sync.position.checked_sub(1) {
+                        self.phase = ((((tick % ticks) as u64) << 32) / ticks as u64) as u32;
+                    }
+                }
+                self.position = sync.position;
+                // holds still until there's a tempo to follow
+                match sync.quarter_us {
+                    Some(quarter_us) => {
+                        let cycle_us = (quarter_us as u64 * ticks as u64 / PPQN as u64).max(1);
+                        (1u64 << 32) * 1_000_000 / CONTROL_HZ as u64 / cycle_us
+                    }
+                    None => 0,
+                }
+            }
+        }
+        .min(u32::MAX as u64) as u32
+    }
+
+    pub fn update(&mut self, sync: &ClockSync) {
+        // a synced LFO can restart or jump as it works out its increment
+        let increment = self.increment(sync);
+        let (phase, wrapped) = self.phase.overflowing_add(increment);
+        self.phase = phase;
+        if wrapped {
+            self.next_cycle();
+        }
+        self.value = match self.params.shape {
+            Shape::Sine => sine(phase),
+            Shape::Triangle => {
+                // from zero rising, like the sine
+                let x = (phase.wrapping_add(1 << 30) >> 17) as i32;
+                if x < 16384 {
+                    x + MIN
+                } else {
+                    32767 - x + MIN
+                }
+            }
+            Shape::Saw => (phase >> 18) as i32 + MIN,
+            Shape::Square => {
+                if phase < 1 << 31 {
+                    MAX
+                } else {
+                    MIN
+                }
+            }
+            Shape::SampleHold => self.to,
+            Shape::SmoothRandom => self.from + (self.to - self.from) * (phase >> 18) as i32 / 16384,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Moves the clock on without a tempo, so only restarts and relocates
+    // move the phase
+    fn at(lfo: &mut Lfo, position: u32) {
+        lfo.update(&ClockSync {
+            position,
+            quarter_us: None,
+        });
+    }
+
+    #[test]
+    fn adjusting_the_page() {
+        let mut params = LFO_DEFAULTS[0];
+        let mut param = LfoParam::Shape(0);
+        params.adjust(param, -1);
+        assert_eq!(params.shape, Shape::SmoothRandom);
+
+        param = param.next();
+        assert_eq!(param, LfoParam::Rate(0));
+        params.adjust(param, 3);
+        assert_eq!(params.rate, Rate::Hz(2000));
+        params.adjust(param, 4);
+        assert_eq!(params.rate, Rate::Sync(Division::QUARTER));
+        params.adjust(param, 100);
+        assert_eq!(params.rate, Rate::Sync(Division(8)));
+        params.adjust(param, -100);
+        assert_eq!(params.rate, Rate::Hz(5));
+
+        param = param.next();
+        params.adjust(param, -3);
+        assert_eq!(params.depth, 85);
+        params.adjust(param, 10);
+        assert_eq!(params.depth, 100);
+        assert_eq!(param.next(), LfoParam::Shape(1));
+        assert_eq!(LfoParam::Depth(LFOS as u8 - 1).next(), LfoParam::Shape(0));
+        assert_eq!(LfoParam::Rate(2).lfo(), 2);
+    }
+
+    #[test]
+    fn synced_cycles_restart_on_the_division() {
+        let params = LfoParams::new(Shape::Saw, Rate::Sync(Division::QUARTER), Retrigger::Free);
+        let mut lfo = Lfo::new(params, 1);
+        at(&mut lfo, 1);
+        lfo.phase = 1 << 31;
+        for position in 2..=24 {
+            at(&mut lfo, position);
+            assert_eq!(lfo.phase, 1 << 31);
+        }
+        // the tick just gone is the 24th
+        at(&mut lfo, 25);
+        assert_eq!(lfo.phase, 0);
+        assert_eq!(lfo.value(), MIN);
+    }
+
+    #[test]
+    fn relocating_sets_the_phase() {
+        let params = LfoParams::new(Shape::Saw, Rate::Sync(Division::QUARTER), Retrigger::Free);
+        let mut lfo = Lfo::new(params, 1);
+        at(&mut lfo, 1);
+        at(&mut lfo, 2);
+        // six ticks into the second quarter
+        at(&mut lfo, 31);
+        assert_eq!(lfo.phase, 1 << 30);
+        // back to the top of the song
+        at(&mut lfo, 1);
+        assert_eq!(lfo.phase, 0);
+        // and staying put holds it
+        lfo.phase = 1234;
+        at(&mut lfo, 1);
+        assert_eq!(lfo.phase, 1234);
+    }
+}
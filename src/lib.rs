@@ -0,0 +1,27 @@
+#![cfg_attr(not(test), no_std)]
+
+// Everything that doesn't touch the hardware, so it can be tested on the host
+pub mod arpeggiator;
+pub mod chord;
+pub mod clock;
+pub mod control;
+pub mod cv;
+pub mod envelope;
+pub mod frame;
+pub mod glide;
+pub mod instrument;
+pub mod learn;
+pub mod lfo;
+pub mod mapping;
+pub mod midi;
+pub mod mod_matrix;
+pub mod mode;
+pub mod pitch;
+pub mod quantizer;
+pub mod routing;
+pub mod scale;
+pub mod sequencer;
+pub mod settings;
+pub mod timecode;
+pub mod ui;
+pub mod volts;
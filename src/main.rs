@@ -1,7 +1,7 @@
 #![no_main]
 #![no_std]
 
-use cortex_m::{asm::bkpt, asm::delay};
+use cortex_m::{asm::bkpt, asm::delay, peripheral::syst::SystClkSource};
 use rtt_target::{rprintln, rtt_init_print};
 
 use crate::hal::{
@@ -11,17 +11,42 @@ use crate::hal::{
 };
 use stm32f7xx_hal as hal;
 
-mod cv;
+mod dac_writer;
+mod display;
 mod encoder;
+mod flash;
+mod i2c_bus;
+mod master_clock;
 mod mcp4728;
-mod midi;
 mod usb_fs;
+mod usb_midi;
 
-use cv::CvPanel;
+use dac_writer::Dacs;
+use display::StatusDisplay;
 use encoder::Encoder;
-use midi::device::MidiClass;
+use flash::SettingsStore;
+use i2c_bus::BusManager;
+use master_clock::MasterClock;
+use mcp4728::Mcp4728I2c;
+use multimidi::arpeggiator::ArpParam;
+use multimidi::chord::ChordParam;
+use multimidi::clock::{Clock, MasterParams, CLOCK_OUTPUTS};
+use multimidi::control::ControlTimer;
+use multimidi::cv::{CvPanel, OutputParam};
+use multimidi::envelope::{EnvelopeParam, ENVELOPE_PRESETS};
+use multimidi::instrument::Instrument;
+use multimidi::learn::LearnEvent;
+use multimidi::lfo::LfoParam;
+use multimidi::midi::message::MidiMessage;
+use multimidi::pitch::CALIBRATION_POINTS;
+use multimidi::quantizer::QuantizerParam;
+use multimidi::settings::{self, Settings};
+use multimidi::timecode::{TimecodeFollower, TimecodeParam};
+use multimidi::ui::{Page, Ui, UiEvent};
+use rtic::Mutex;
 use usb_device::prelude::*;
 use usb_fs::{UsbBus, UsbBusType};
+use usb_midi::device::MidiClass;
 
 #[rtic::app(device=stm32f7xx_hal::pac, peripherals=true)]
 const APP: () = {
@@ -29,6 +54,14 @@ const APP: () = {
         led1r: PE9<Output<PushPull>>,
         encoder: Encoder,
         cv_panel: CvPanel,
+        dacs: Dacs,
+        instrument: Instrument,
+        clock: Clock,
+        timecode: TimecodeFollower,
+        display: Option<StatusDisplay>,
+        master_clock: MasterClock,
+        settings_store: SettingsStore,
+        millis: u32,
         usb_device: usb_device::device::UsbDevice<'static, UsbBusType>,
         midi_device: MidiClass<'static, UsbBusType>,
     }
@@ -49,21 +82,63 @@ const APP: () = {
             .usb(true)
             .freeze();
 
+        // 1ms system tick for the UI
+        let mut syst = cx.core.SYST;
+        syst.set_clock_source(SystClkSource::Core);
+        syst.set_reload(clocks.sysclk().0 / 1000 - 1);
+        syst.clear_current();
+        syst.enable_counter();
+        syst.enable_interrupt();
+
+        let settings_store = SettingsStore::new(peripherals.FLASH);
+        let mut instrument = Instrument::new();
+        let mut cv_panel = CvPanel::new();
+        let mut clock = Clock::new();
+        let mut timecode = TimecodeFollower::new();
+        let mut master_params = MasterParams::new();
+        let mut settings_buf = [0; settings::MAX_SIZE];
+        if let Some(settings) = settings_store
+            .load(&mut settings_buf)
+            .and_then(|len| Settings::decode(&settings_buf[..len]))
+        {
+            settings.apply(&mut instrument, &mut cv_panel, &mut clock, &mut timecode);
+            master_params = settings.master;
+            rprintln!("Loaded settings");
+        }
+
         let gpioe = peripherals.GPIOE.split();
         let led1r = gpioe.pe9.into_push_pull_output();
 
         let gpiof = peripherals.GPIOF.split();
         let gpiob = peripherals.GPIOB.split();
 
-        let cv_panel = CvPanel::new(
+        // The DAC bit-banger is the I2C master for every device on PB9/PB10,
+        // so it lives behind a bus manager that hands out proxies.
+        static mut I2C_BUS: Option<BusManager<dac_writer::I2c>> = None;
+        unsafe {
+            I2C_BUS = Some(BusManager::new(Mcp4728I2c::new(
+                &clocks,
+                100.khz(),
+                gpiob.pb10.into_open_drain_output(),
+                gpiob.pb9.into_open_drain_output(),
+            )));
+        }
+
+        let dacs = Dacs::new(
             &clocks,
+            peripherals.TIM2,
             gpiof.pf8.into_push_pull_output(),
             gpiof.pf10.into_push_pull_output(),
             gpiof.pf12.into_push_pull_output(),
             gpiof.pf14.into_push_pull_output(),
-            gpiob.pb10.into_open_drain_output(),
-            gpiob.pb9.into_open_drain_output(),
+            unsafe { I2C_BUS.as_ref().unwrap() },
         );
+        let display = StatusDisplay::new(unsafe { I2C_BUS.as_ref().unwrap() }.acquire());
+        if display.is_none() {
+            rprintln!("No status display");
+        }
+        let mut master_clock = MasterClock::new(peripherals.TIM5, &clocks);
+        master_clock.set_params(master_params);
 
         let gpioc = peripherals.GPIOC.split();
         let encoder = Encoder::new(
@@ -108,6 +183,14 @@ const APP: () = {
             led1r,
             encoder,
             cv_panel,
+            dacs,
+            instrument,
+            clock,
+            timecode,
+            display,
+            master_clock,
+            settings_store,
+            millis: 0,
             usb_device: usb_dev,
             midi_device,
         }
@@ -117,31 +200,318 @@ const APP: () = {
     // fn interrupt_usb(cx: interrupt_usb::Context) {
     // }
 
-    #[idle(resources=[led1r, encoder, cv_panel, usb_device, midi_device])]
-    fn idle(cx: idle::Context) -> ! {
+    #[task(binds=TIM2, priority=2, resources=[cv_panel, dacs])]
+    fn dac_tick(cx: dac_tick::Context) {
+        cx.resources.dacs.tick(cx.resources.cv_panel.frame_mut());
+    }
+
+    #[task(binds=TIM5, priority=2, resources=[master_clock, clock, millis])]
+    fn master_clock_tick(cx: master_clock_tick::Context) {
+        if let Some(message) = cx.resources.master_clock.tick() {
+            cx.resources.clock.handle(message, *cx.resources.millis);
+        }
+    }
+
+    #[task(binds=SysTick, priority=1, resources=[millis])]
+    fn sys_tick(mut cx: sys_tick::Context) {
+        cx.resources
+            .millis
+            .lock(|millis| *millis = millis.wrapping_add(1));
+    }
+
+    #[idle(resources=[led1r, encoder, cv_panel, dacs, instrument, clock, timecode, display, master_clock, settings_store, millis, usb_device, midi_device])]
+    fn idle(mut cx: idle::Context) -> ! {
+        let mut buf = [0; 64];
+        let mut ui = Ui::new();
+        let mut outgoing = [0; master_clock::OUTGOING * 4];
+        let mut configured = false;
+        let mut control = ControlTimer::new(0);
+        // the envelope page edits one parameter at a time, none picks a preset
+        let mut envelope_param: Option<EnvelopeParam> = None;
+        let mut envelope_preset = 0;
+        let mut quantizer_param = QuantizerParam::Quantize;
+        let mut chord_param = ChordParam::Shape;
+        let mut arp_param = ArpParam::Mode;
+        let mut lfo_param = LfoParam::Shape(0);
+        // the clock page edits one output's division at a time
+        let mut clock_output = 0;
+        let mut timecode_param = TimecodeParam::Interval(0);
+        let mut output_param = OutputParam::Routing;
+        // the calibration page trims one octave's C at a time
+        let mut calibration_point = 0;
+        // what the display's timecode row last showed, and when
+        let mut shown_timecode = None;
+        let mut timecode_drawn = 0;
+        if let Some(display) = cx.resources.display.as_mut() {
+            display.page(ui.page());
+        }
         loop {
+            let now = cx.resources.millis.lock(|millis| *millis);
+
+            let ticks = control.ticks(now);
+            if ticks > 0 {
+                let sync = cx.resources.clock.lock(|clock| clock.sync());
+                for _ in 0..ticks {
+                    cx.resources.instrument.update(&sync);
+                }
+            }
+
+            let pressed = cx.resources.encoder.select_pressed();
+            let steps = cx.resources.encoder.steps();
+            match ui.update(pressed, steps, now) {
+                Some(UiEvent::Learn(event)) => {
+                    if learn_event(event, cx.resources.instrument) {
+                        ui.edited();
+                    }
+                }
+                Some(UiEvent::Page(page)) => {
+                    if let Some(display) = cx.resources.display.as_mut() {
+                        display.page(page);
+                    }
+                }
+                Some(UiEvent::Turn(Page::Mode, steps)) => {
+                    let mode = cx.resources.instrument.mode().step(steps);
+                    cx.resources.instrument.set_mode(mode);
+                    ui.edited();
+                }
+                Some(UiEvent::Turn(Page::Glide, _)) => {
+                    let glide_mode = cx.resources.instrument.glide_mode().next();
+                    cx.resources.instrument.set_glide_mode(glide_mode);
+                    ui.edited();
+                }
+                Some(UiEvent::Press(Page::Envelope, _)) => {
+                    envelope_param = match envelope_param {
+                        None => Some(EnvelopeParam::Attack),
+                        Some(param) => param.next(),
+                    };
+                }
+                Some(UiEvent::Turn(Page::Envelope, steps)) => {
+                    let mut envelope = cx.resources.instrument.envelope();
+                    match envelope_param {
+                        Some(param) => {
+                            // one 7-bit controller step per detent
+                            let value = envelope.get(param) as i32 + steps * 128;
+                            envelope.set(param, value.max(0) as u16);
+                        }
+                        None => {
+                            envelope_preset = (envelope_preset as i32 + steps)
+                                .rem_euclid(ENVELOPE_PRESETS.len() as i32)
+                                as usize;
+                            envelope = ENVELOPE_PRESETS[envelope_preset];
+                        }
+                    }
+                    cx.resources.instrument.set_envelope(envelope);
+                    ui.edited();
+                }
+                Some(UiEvent::Press(Page::Scale, _)) => {
+                    quantizer_param = quantizer_param.next();
+                }
+                Some(UiEvent::Turn(Page::Scale, steps)) => {
+                    let mut quantizer = cx.resources.instrument.quantizer();
+                    quantizer.adjust(quantizer_param, steps);
+                    cx.resources.instrument.set_quantizer(quantizer);
+                    ui.edited();
+                }
+                Some(UiEvent::Press(Page::Chord, _)) => {
+                    chord_param = chord_param.next();
+                }
+                Some(UiEvent::Turn(Page::Chord, steps)) => {
+                    let mut chord = cx.resources.instrument.chord();
+                    chord.adjust(chord_param, steps);
+                    cx.resources.instrument.set_chord(chord);
+                    ui.edited();
+                }
+                Some(UiEvent::Press(Page::Arp, _)) => {
+                    arp_param = arp_param.next();
+                }
+                Some(UiEvent::Turn(Page::Arp, steps)) => {
+                    let mut arp = cx.resources.instrument.arp();
+                    arp.adjust(arp_param, steps);
+                    cx.resources.instrument.set_arp(arp);
+                    ui.edited();
+                }
+                Some(UiEvent::Press(Page::Sequencer, _)) => {
+                    cx.resources.instrument.sequencer_mut().next_field();
+                }
+                Some(UiEvent::Turn(Page::Sequencer, steps)) => {
+                    cx.resources.instrument.sequencer_mut().adjust(steps);
+                    ui.edited();
+                }
+                Some(UiEvent::Press(Page::Lfo, _)) => {
+                    lfo_param = lfo_param.next();
+                }
+                Some(UiEvent::Turn(Page::Lfo, steps)) => {
+                    let lfo = cx.resources.instrument.lfo_mut(lfo_param.lfo());
+                    let mut params = lfo.params();
+                    params.adjust(lfo_param, steps);
+                    lfo.set_params(params);
+                    ui.edited();
+                }
+                Some(UiEvent::Turn(Page::Tempo, steps)) => {
+                    cx.resources.master_clock.lock(|master| {
+                        master.set_tempo((master.tempo() as i32 + steps * 10).max(0) as u32);
+                    });
+                    ui.edited();
+                }
+                Some(UiEvent::Press(Page::Tempo, at)) => {
+                    cx.resources.master_clock.lock(|master| {
+                        master.tap(at);
+                    });
+                    ui.edited();
+                }
+                Some(UiEvent::Turn(Page::Swing, steps)) => {
+                    cx.resources.master_clock.lock(|master| {
+                        master.set_swing((master.swing() as i32 + steps).max(0) as u8);
+                    });
+                    ui.edited();
+                }
+                Some(UiEvent::Turn(Page::Transport, _)) => {
+                    cx.resources.master_clock.lock(|master| {
+                        master.set_send_to_host(!master.sends_to_host());
+                    });
+                    ui.edited();
+                }
+                Some(UiEvent::Press(Page::Transport, _)) => {
+                    // the followers see the internal clock like any other
+                    let clock = &mut cx.resources.clock;
+                    let instrument = &mut cx.resources.instrument;
+                    cx.resources.master_clock.lock(|master| {
+                        if master.is_running() {
+                            let stop = master.stop();
+                            instrument.handle(stop);
+                            clock.lock(|clock| clock.handle(stop, now));
+                        } else {
+                            for message in master.start().iter() {
+                                instrument.handle(*message);
+                                clock.lock(|clock| clock.handle(*message, now));
+                            }
+                        }
+                    });
+                }
+                Some(UiEvent::Press(Page::Clock, _)) => {
+                    clock_output = (clock_output + 1) % CLOCK_OUTPUTS;
+                }
+                Some(UiEvent::Turn(Page::Clock, steps)) => {
+                    cx.resources
+                        .clock
+                        .lock(|clock| clock.adjust(clock_output, steps));
+                    ui.edited();
+                }
+                Some(UiEvent::Press(Page::Timecode, _)) => {
+                    timecode_param = timecode_param.next();
+                }
+                Some(UiEvent::Turn(Page::Timecode, steps)) => {
+                    cx.resources.timecode.adjust(timecode_param, steps);
+                    ui.edited();
+                }
+                Some(UiEvent::Press(Page::Outputs, _)) => {
+                    output_param = output_param.next();
+                }
+                Some(UiEvent::Turn(Page::Outputs, steps)) => {
+                    cx.resources
+                        .cv_panel
+                        .lock(|cv_panel| cv_panel.adjust(output_param, steps));
+                    ui.edited();
+                }
+                Some(UiEvent::Press(Page::Calibrate, _)) => {
+                    calibration_point = (calibration_point + 1) % CALIBRATION_POINTS;
+                }
+                Some(UiEvent::Turn(Page::Calibrate, steps)) => {
+                    let mut calibration = cx.resources.instrument.calibration();
+                    calibration.adjust(calibration_point, steps);
+                    cx.resources.instrument.set_calibration(calibration);
+                    ui.edited();
+                }
+                _ => {}
+            }
+
+            if ui.save_due(now) {
+                let instrument = &cx.resources.instrument;
+                let clock = &mut cx.resources.clock;
+                let timecode = &cx.resources.timecode;
+                let master = cx.resources.master_clock.lock(|master| master.params());
+                let settings = cx.resources.cv_panel.lock(|cv_panel| {
+                    clock.lock(|clock| {
+                        Settings::new(instrument, cv_panel, clock, master, timecode)
+                    })
+                });
+                save_settings(&settings, cx.resources.settings_store);
+            }
+
+            let internal_clock = cx.resources.master_clock.lock(|master| master.is_running());
+
             if cx
                 .resources
                 .usb_device
                 .poll(&mut [cx.resources.midi_device])
             {
-                if let Ok(packet) = cx.resources.midi_device.read_packet() {
-                    if packet[1] & 0xf0 == 0x90 {
-                        let offset = (packet[2] - 24) as u16;
-                        cx.resources
-                            .cv_panel
-                            .pitch(0)
-                            .set((800 + (offset * 34)).min(4095))
-                            .unwrap();
-                        rprintln!("Note on: {}", offset);
-                    } else if packet[1] & 0xf0 == 0x80 {
-                        cx.resources.cv_panel.pitch(0).set(0).unwrap();
-                        let offset = (packet[2] - 24) as u16;
-                        rprintln!("Note off: {}", offset);
+                if let Ok(len) = cx.resources.midi_device.read_packets(&mut buf) {
+                    for packet in buf[..len].chunks_exact(4) {
+                        let packet = [packet[0], packet[1], packet[2], packet[3]];
+                        cx.resources.timecode.packet(packet, now);
+                        if let Some(message) = MidiMessage::from_packet(packet) {
+                            if let Some(event) = ui.capture(message) {
+                                if learn_event(event, cx.resources.instrument) {
+                                    ui.edited();
+                                }
+                            }
+                            cx.resources.instrument.handle(message);
+                            // the internal clock takes over while it runs
+                            if !internal_clock {
+                                cx.resources.clock.lock(|clock| clock.handle(message, now));
+                            }
+                        }
                     }
                 }
             }
 
+            let status = cx.resources.timecode.status(now);
+            if status != shown_timecode && now.wrapping_sub(timecode_drawn) >= display::REDRAW_MS {
+                if let Some(display) = cx.resources.display.as_mut() {
+                    display.timecode(status);
+                }
+                shown_timecode = status;
+                timecode_drawn = now;
+            }
+
+            // nothing will turn held notes off once the host has gone away
+            let was_configured = configured;
+            configured = cx.resources.usb_device.state() == UsbDeviceState::Configured;
+            if was_configured && !configured {
+                rprintln!("USB not configured, dropping gates");
+                cx.resources.instrument.panic();
+            }
+
+            let len = cx
+                .resources
+                .master_clock
+                .lock(|master| master.take_outgoing(&mut outgoing));
+            if len > 0 && configured {
+                cx.resources
+                    .midi_device
+                    .write_packets(&outgoing[..len])
+                    .ok();
+            }
+
+            // render into a copy so the writer isn't held off meanwhile
+            let mut rendered = cx.resources.cv_panel.lock(|cv_panel| cv_panel.clone());
+            cx.resources.instrument.render(&mut rendered);
+            cx.resources
+                .clock
+                .lock(|clock| clock.render(now, &mut rendered));
+            cx.resources.timecode.render(now, &mut rendered);
+            let dacs = &mut cx.resources.dacs;
+            cx.resources.cv_panel.lock(|cv_panel| {
+                cv_panel.apply(&rendered);
+                if cv_panel.take_wake() {
+                    dacs.lock(|dacs| dacs.wake());
+                }
+            });
+
+            if let Some(e) = cx.resources.dacs.lock(|dacs| dacs.take_error()) {
+                rprintln!("DAC write failed: {:?}", e);
+            }
+
             // rprintln!("Idle...");
             // delay(1000000);
             // }
@@ -149,6 +519,19 @@ const APP: () = {
     }
 };
 
+// True when the mappings changed and want saving
+fn learn_event(event: LearnEvent, instrument: &mut Instrument) -> bool {
+    event.apply(instrument.mappings_mut())
+}
+
+fn save_settings(settings: &Settings, store: &mut SettingsStore) {
+    let mut buf = [0; settings::MAX_SIZE];
+    let len = settings.encode(&mut buf);
+    if let Err(e) = store.save(&buf[..len]) {
+        rprintln!("Saving settings failed: {:?}", e);
+    }
+}
+
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
     rprintln!("{}", info);
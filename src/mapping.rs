@@ -0,0 +1,127 @@
+use crate::midi::controllers::MAX_VALUE;
+use crate::routing::Output;
+use crate::volts::Millivolts;
+
+pub const MAPPINGS: usize = 16;
+
+// Per-note sources follow the most recent note on the mapping's channel
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Source {
+    Control(u8),
+    ChannelPressure,
+    PolyPressure,
+    Velocity,
+    ReleaseVelocity,
+    PitchBend,
+    // a voice's envelope, whatever the channel
+    Envelope(u8),
+    // the channel sets the depth of LFOs under the mod wheel
+    Lfo(u8),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Mapping {
+    pub source: Source,
+    pub channel: u8,
+    pub output: Output,
+    // output voltage at the bottom and top of the source's travel
+    pub min: Millivolts,
+    pub max: Millivolts,
+    pub invert: bool,
+}
+
+impl Mapping {
+    pub fn new(source: Source, channel: u8, output: Output) -> Self {
+        Self {
+            source,
+            channel,
+            output,
+            min: Millivolts(0),
+            max: Millivolts(10000),
+            invert: false,
+        }
+    }
+
+    // Scales a 14-bit source value into the mapping's voltage span
+    pub fn scale(&self, value: u16) -> Millivolts {
+        let value = value.min(MAX_VALUE);
+        let value = if self.invert {
+            MAX_VALUE - value
+        } else {
+            value
+        } as i32;
+        let span = self.max.0 - self.min.0;
+        Millivolts(self.min.0 + span * value / MAX_VALUE as i32)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MappingTable {
+    mappings: [Option<Mapping>; MAPPINGS],
+}
+
+impl MappingTable {
+    pub fn empty() -> Self {
+        Self {
+            mappings: [None; MAPPINGS],
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<Mapping> {
+        self.mappings[index]
+    }
+
+    pub fn set(&mut self, index: usize, mapping: Option<Mapping>) {
+        self.mappings[index] = mapping;
+    }
+
+    // Replaces any mapping already driving the same output, otherwise takes
+    // a free slot. Returns false if the table is full.
+    pub fn assign(&mut self, mapping: Mapping) -> bool {
+        let index = self
+            .mappings
+            .iter()
+            .position(|m| matches!(m, Some(m) if m.output == mapping.output))
+            .or_else(|| self.mappings.iter().position(Option::is_none));
+        match index {
+            Some(index) => {
+                self.mappings[index] = Some(mapping);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn clear_output(&mut self, output: Output) {
+        for mapping in self.mappings.iter_mut() {
+            if matches!(mapping, Some(m) if m.output == output) {
+                *mapping = None;
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Mapping> {
+        self.mappings.iter().flatten()
+    }
+}
+
+impl Default for MappingTable {
+    // The mod wheel on channel 1 drives the first voice's first aux output
+    // and the other voices' first aux outputs take an LFO each. The second
+    // aux output of every voice follows its envelope.
+    fn default() -> Self {
+        let mut table = Self::empty();
+        table.assign(Mapping::new(Source::Control(1), 0, Output::Aux(0)));
+        for lfo in 1..4 {
+            table.assign(Mapping::new(Source::Lfo(lfo), 0, Output::Aux(lfo * 2)));
+        }
+        for voice in 0..4 {
+            table.assign(Mapping::new(
+                Source::Envelope(voice),
+                0,
+                Output::Aux(voice * 2 + 1),
+            ));
+        }
+        table
+    }
+}
@@ -0,0 +1,200 @@
+use crate::dac_writer::apb1_timer_clock;
+use crate::hal::{
+    pac::{RCC, TIM5},
+    rcc::Clocks,
+};
+use multimidi::clock::{MasterParams, PPQN};
+use multimidi::midi::message::MidiMessage;
+
+// The timer counts microseconds and TIM5 is 32 bit, so any tempo fits
+const TIMER_HZ: u32 = 1_000_000;
+
+const MIN_TEMPO: u32 = 200;
+const MAX_TEMPO: u32 = 3000;
+
+// Percent of each eighth note given to its first sixteenth, 50 is straight
+const MIN_SWING: u8 = 50;
+const MAX_SWING: u8 = 75;
+
+// Taps further apart than this start a new tempo
+const MAX_TAP_MS: u32 = 2000;
+// Intervals averaged for tap tempo
+const TAPS: u32 = 4;
+
+pub const OUTGOING: usize = 8;
+
+// Generates 24 PPQN clock from TIM5 when there's no DAW to follow. Each
+// update interrupt is one tick, with the timer period changed per tick to
+// lay swing over the sixteenths.
+pub struct MasterClock {
+    timer: TIM5,
+    // beats per minute in tenths
+    tempo: u32,
+    swing: u8,
+    running: bool,
+    // ticks since start
+    ticks: u32,
+    last_tap: Option<u32>,
+    tap_ms: u32,
+    tap_count: u32,
+    // realtime bytes waiting for the idle loop to send to the host
+    send_to_host: bool,
+    outgoing: [u8; OUTGOING],
+    outgoing_len: usize,
+}
+
+impl MasterClock {
+    pub fn new(tim5: TIM5, clocks: &Clocks) -> Self {
+        let rcc = unsafe { &(*RCC::ptr()) };
+        rcc.apb1enr.modify(|_, w| w.tim5en().set_bit());
+        let timer_psc = apb1_timer_clock(clocks) / TIMER_HZ - 1;
+
+        tim5.psc.write(|w| unsafe { w.bits(timer_psc) });
+        tim5.cr1.write(|w| w.urs().set_bit());
+        tim5.egr.write(|w| w.ug().set_bit());
+        tim5.sr.modify(|_, w| w.uif().clear_bit());
+        tim5.dier.write(|w| w.uie().set_bit());
+
+        Self {
+            timer: tim5,
+            tempo: 1200,
+            swing: MIN_SWING,
+            running: false,
+            ticks: 0,
+            last_tap: None,
+            tap_ms: 0,
+            tap_count: 0,
+            send_to_host: false,
+            outgoing: [0; OUTGOING],
+            outgoing_len: 0,
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    pub fn tempo(&self) -> u32 {
+        self.tempo
+    }
+
+    pub fn set_tempo(&mut self, tempo: u32) {
+        self.tempo = tempo.clamp(MIN_TEMPO, MAX_TEMPO);
+    }
+
+    pub fn swing(&self) -> u8 {
+        self.swing
+    }
+
+    pub fn set_swing(&mut self, swing: u8) {
+        self.swing = swing.clamp(MIN_SWING, MAX_SWING);
+    }
+
+    pub fn sends_to_host(&self) -> bool {
+        self.send_to_host
+    }
+
+    pub fn set_send_to_host(&mut self, send: bool) {
+        self.send_to_host = send;
+        self.outgoing_len = 0;
+    }
+
+    pub fn params(&self) -> MasterParams {
+        MasterParams {
+            tempo: self.tempo,
+            swing: self.swing,
+            send_to_host: self.send_to_host,
+        }
+    }
+
+    pub fn set_params(&mut self, params: MasterParams) {
+        self.set_tempo(params.tempo);
+        self.set_swing(params.swing);
+        self.set_send_to_host(params.send_to_host);
+    }
+
+    // Successive taps set the tempo from their average interval
+    pub fn tap(&mut self, now: u32) {
+        if let Some(last) = self.last_tap {
+            let interval = now.wrapping_sub(last);
+            if interval <= MAX_TAP_MS {
+                let count = self.tap_count.min(TAPS - 1);
+                self.tap_ms = (self.tap_ms * count + interval) / (count + 1);
+                self.tap_count += 1;
+                self.set_tempo(600_000 / self.tap_ms.max(1));
+            } else {
+                self.tap_count = 0;
+            }
+        }
+        self.last_tap = Some(now);
+    }
+
+    // Time from the last tick sent to the next
+    fn period_us(&self) -> u32 {
+        let eighth_us = 300_000_000 / self.tempo;
+        let first_us = eighth_us * self.swing as u32 / 100;
+        let sixteenth = self.ticks.wrapping_sub(1) / (PPQN / 4);
+        let sixteenth_us = if sixteenth % 2 == 0 {
+            first_us
+        } else {
+            eighth_us - first_us
+        };
+        sixteenth_us / (PPQN / 4)
+    }
+
+    // Returns the messages to feed the clock follower, the first tick goes
+    // out at once with the start
+    pub fn start(&mut self) -> [MidiMessage; 2] {
+        self.running = true;
+        self.ticks = 1;
+        self.queue(0xFA);
+        self.queue(0xF8);
+        self.timer.cnt.write(|w| unsafe { w.bits(0) });
+        self.timer
+            .arr
+            .write(|w| unsafe { w.bits(self.period_us() - 1) });
+        self.timer.cr1.modify(|_, w| w.cen().set_bit());
+        [MidiMessage::Start, MidiMessage::TimingClock]
+    }
+
+    pub fn stop(&mut self) -> MidiMessage {
+        self.running = false;
+        self.timer.cr1.modify(|_, w| w.cen().clear_bit());
+        self.queue(0xFC);
+        MidiMessage::Stop
+    }
+
+    // From the TIM5 interrupt
+    pub fn tick(&mut self) -> Option<MidiMessage> {
+        self.timer.sr.modify(|_, w| w.uif().clear_bit());
+        if !self.running {
+            return None;
+        }
+        self.ticks = self.ticks.wrapping_add(1);
+        self.timer
+            .arr
+            .write(|w| unsafe { w.bits(self.period_us() - 1) });
+        self.queue(0xF8);
+        Some(MidiMessage::TimingClock)
+    }
+
+    fn queue(&mut self, status: u8) {
+        if self.send_to_host && self.outgoing_len < OUTGOING {
+            self.outgoing[self.outgoing_len] = status;
+            self.outgoing_len += 1;
+        }
+    }
+
+    // Realtime bytes for the host as USB-MIDI event packets on cable 0
+    pub fn take_outgoing(&mut self, packets: &mut [u8; OUTGOING * 4]) -> usize {
+        for (status, packet) in self.outgoing[..self.outgoing_len]
+            .iter()
+            .zip(packets.chunks_exact_mut(4))
+        {
+            packet.copy_from_slice(&[0x0F, *status, 0, 0]);
+        }
+        let len = self.outgoing_len * 4;
+        self.outgoing_len = 0;
+        len
+    }
+}
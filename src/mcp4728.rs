@@ -1,7 +1,10 @@
 use crate::hal::{rcc::Clocks, time::Hertz};
-use core::{fmt::Debug, marker::PhantomData};
+use core::{fmt::Debug, marker::PhantomData, task::Poll};
 use cortex_m::{asm::delay, interrupt};
-use embedded_hal::digital::v2::{InputPin, OutputPin};
+use embedded_hal::{
+    blocking::i2c::{Read, Write, WriteRead},
+    digital::v2::{InputPin, OutputPin},
+};
 
 const GENERAL_CALL_ADDR: u8 = 0x0;
 const DEVICE_CODE: u8 = 0x60;
@@ -54,15 +57,15 @@ where
         })
     }
 
-    pub fn set_channel(
-        &mut self,
-        i2c: &mut Mcp4728I2c<SCL, SDA>,
-        channel: u8,
-        value: u16,
-    ) -> Result<(), Mcp4728Error> {
+    pub fn channel_transfer(&self, channel: u8, value: u16) -> WriteTransfer {
         assert!(channel <= 3);
         assert!(value < 4096);
-        Self::write_channel(self.address, channel, value, i2c)
+        WriteTransfer::new([
+            self.address << 1,
+            0x40 | (channel << 1),
+            ((value & 0xF00) >> 8) as u8 | 0x80,
+            (value & 0xFF) as u8,
+        ])
     }
 
     fn read_address<P>(ldac: &mut P, i2c: &mut Mcp4728I2c<SCL, SDA>) -> Result<u8, Mcp4728Error>
@@ -136,12 +139,45 @@ where
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum Mcp4728Error {
     NoAck,
     AddressMismatch,
 }
 
+#[derive(Clone, Copy)]
+enum Phase {
+    Start,
+    Low,
+    High,
+    StopLow,
+    StopHigh,
+    Stop,
+}
+
+// A write transaction that is clocked out one half bit per call to
+// Mcp4728I2c::step, so it can be driven from a timer interrupt instead of
+// busy-waiting with interrupts disabled.
+pub struct WriteTransfer {
+    bytes: [u8; 4],
+    byte: usize,
+    bit: u8,
+    phase: Phase,
+    result: Result<(), Mcp4728Error>,
+}
+
+impl WriteTransfer {
+    fn new(bytes: [u8; 4]) -> Self {
+        Self {
+            bytes,
+            byte: 0,
+            bit: 0,
+            phase: Phase::Start,
+            result: Ok(()),
+        }
+    }
+}
+
 pub struct Mcp4728I2c<SCL, SDA>
 where
     SCL: OutputPin,
@@ -291,4 +327,150 @@ where
         self.sda.set_high().unwrap();
         delay(self.full_delay);
     }
+
+    // Expects the bus idle on the first call and leaves it idle once the
+    // transfer is ready. Call at twice the desired bus frequency.
+    pub fn step(&mut self, transfer: &mut WriteTransfer) -> Poll<Result<(), Mcp4728Error>> {
+        match transfer.phase {
+            Phase::Start => {
+                self.sda.set_low().unwrap();
+                transfer.phase = Phase::Low;
+            }
+            Phase::Low => {
+                self.scl.set_low().unwrap();
+                if transfer.bit == 8 || transfer.bytes[transfer.byte] & (0x80 >> transfer.bit) != 0
+                {
+                    // release for the ack bit, or a one
+                    self.sda.set_high().unwrap();
+                } else {
+                    self.sda.set_low().unwrap();
+                }
+                transfer.phase = Phase::High;
+            }
+            Phase::High => {
+                self.scl.set_high().unwrap();
+                transfer.phase = Phase::Low;
+                if transfer.bit < 8 {
+                    transfer.bit += 1;
+                } else if self.sda.is_high().unwrap() {
+                    transfer.result = Err(Mcp4728Error::NoAck);
+                    transfer.phase = Phase::StopLow;
+                } else {
+                    transfer.bit = 0;
+                    transfer.byte += 1;
+                    if transfer.byte == transfer.bytes.len() {
+                        transfer.phase = Phase::StopLow;
+                    }
+                }
+            }
+            Phase::StopLow => {
+                self.scl.set_low().unwrap();
+                self.sda.set_low().unwrap();
+                transfer.phase = Phase::StopHigh;
+            }
+            Phase::StopHigh => {
+                self.scl.set_high().unwrap();
+                transfer.phase = Phase::Stop;
+            }
+            Phase::Stop => {
+                self.sda.set_high().unwrap();
+                return Poll::Ready(transfer.result);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl<SCL, SDA> Mcp4728I2c<SCL, SDA>
+where
+    SCL: OutputPin,
+    SCL::Error: Debug,
+    SDA: OutputPin + InputPin,
+    <SDA as embedded_hal::digital::v2::OutputPin>::Error: Debug,
+    <SDA as embedded_hal::digital::v2::InputPin>::Error: Debug,
+{
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Mcp4728Error> {
+        for byte in bytes {
+            self.write_byte(*byte);
+            self.check_ack()?;
+        }
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, buffer: &mut [u8]) {
+        let last = buffer.len().saturating_sub(1);
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            // ack every byte but the last so the slave releases the bus
+            *byte = self.read_byte(i != last);
+        }
+    }
+}
+
+impl<SCL, SDA> Write for Mcp4728I2c<SCL, SDA>
+where
+    SCL: OutputPin,
+    SCL::Error: Debug,
+    SDA: OutputPin + InputPin,
+    <SDA as embedded_hal::digital::v2::OutputPin>::Error: Debug,
+    <SDA as embedded_hal::digital::v2::InputPin>::Error: Debug,
+{
+    type Error = Mcp4728Error;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        interrupt::free(|_| {
+            let result = self
+                .start(address, false)
+                .and_then(|_| self.write_bytes(bytes));
+            self.stop();
+            result
+        })
+    }
+}
+
+impl<SCL, SDA> Read for Mcp4728I2c<SCL, SDA>
+where
+    SCL: OutputPin,
+    SCL::Error: Debug,
+    SDA: OutputPin + InputPin,
+    <SDA as embedded_hal::digital::v2::OutputPin>::Error: Debug,
+    <SDA as embedded_hal::digital::v2::InputPin>::Error: Debug,
+{
+    type Error = Mcp4728Error;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        interrupt::free(|_| {
+            let result = self.start(address, true).map(|_| self.read_bytes(buffer));
+            self.stop();
+            result
+        })
+    }
+}
+
+impl<SCL, SDA> WriteRead for Mcp4728I2c<SCL, SDA>
+where
+    SCL: OutputPin,
+    SCL::Error: Debug,
+    SDA: OutputPin + InputPin,
+    <SDA as embedded_hal::digital::v2::OutputPin>::Error: Debug,
+    <SDA as embedded_hal::digital::v2::InputPin>::Error: Debug,
+{
+    type Error = Mcp4728Error;
+
+    fn write_read(
+        &mut self,
+        address: u8,
+        bytes: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        interrupt::free(|_| {
+            // repeated start between the write and read phases, no stop
+            let result = self
+                .start(address, false)
+                .and_then(|_| self.write_bytes(bytes))
+                .and_then(|_| self.start(address, true))
+                .map(|_| self.read_bytes(buffer));
+            self.stop();
+            result
+        })
+    }
 }
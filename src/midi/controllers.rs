@@ -0,0 +1,340 @@
+// Controller numbers with a fixed meaning in the assembler
+pub const DATA_ENTRY_MSB: u8 = 6;
+pub const DATA_ENTRY_LSB: u8 = 38;
+pub const DATA_INCREMENT: u8 = 96;
+pub const DATA_DECREMENT: u8 = 97;
+pub const NRPN_LSB: u8 = 98;
+pub const NRPN_MSB: u8 = 99;
+pub const RPN_LSB: u8 = 100;
+pub const RPN_MSB: u8 = 101;
+
+// Pedals that hold notes
+pub const SUSTAIN: u8 = 64;
+pub const SOSTENUTO: u8 = 66;
+
+// Channel mode messages
+pub const ALL_SOUND_OFF: u8 = 120;
+pub const RESET_ALL_CONTROLLERS: u8 = 121;
+pub const ALL_NOTES_OFF: u8 = 123;
+pub const OMNI_OFF: u8 = 124;
+pub const OMNI_ON: u8 = 125;
+pub const MONO_ON: u8 = 126;
+pub const POLY_ON: u8 = 127;
+
+// Registered parameter numbers, (MSB << 7) | LSB
+pub const RPN_PITCH_BEND_SENSITIVITY: u16 = 0x0000;
+pub const RPN_FINE_TUNING: u16 = 0x0001;
+pub const RPN_COARSE_TUNING: u16 = 0x0002;
+pub const RPN_MPE_CONFIGURATION: u16 = 0x0006;
+pub const RPN_NULL: u16 = 0x3FFF;
+
+pub const MAX_VALUE: u16 = 0x3FFF;
+pub const CENTER_VALUE: u16 = 0x2000;
+
+// All values are 14 bit. 7-bit controllers are widened so 127 still means
+// full scale, and paired controllers carry the LSB once one has been sent.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ControllerEvent {
+    Control { control: u8, value: u16 },
+    Rpn { parameter: u16, value: u16 },
+    Nrpn { parameter: u16, value: u16 },
+    RpnStep { parameter: u16, delta: i8 },
+    NrpnStep { parameter: u16, delta: i8 },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Selected {
+    None,
+    Rpn,
+    Nrpn,
+}
+
+// Per-channel state for assembling high resolution controllers and RPN/NRPN
+// data entry sequences from individual control changes
+#[derive(Clone, Copy)]
+pub struct ControllerState {
+    msb: [u8; 32],
+    lsb: [u8; 32],
+    // bit n is set once controller n has sent an LSB
+    fine: u32,
+    rpn: u16,
+    nrpn: u16,
+    selected: Selected,
+    data: u16,
+}
+
+pub fn widen(value: u8) -> u16 {
+    let value = value as u16 & 0x7F;
+    value << 7 | value
+}
+
+impl ControllerState {
+    pub fn new() -> Self {
+        Self {
+            msb: [0; 32],
+            lsb: [0; 32],
+            fine: 0,
+            rpn: RPN_NULL,
+            nrpn: RPN_NULL,
+            selected: Selected::None,
+            data: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    pub fn control_change(&mut self, control: u8, value: u8) -> Option<ControllerEvent> {
+        let value = value & 0x7F;
+        match control {
+            RPN_MSB => self.select(Selected::Rpn, Some(value), None),
+            RPN_LSB => self.select(Selected::Rpn, None, Some(value)),
+            NRPN_MSB => self.select(Selected::Nrpn, Some(value), None),
+            NRPN_LSB => self.select(Selected::Nrpn, None, Some(value)),
+            DATA_ENTRY_MSB => {
+                self.data = (value as u16) << 7;
+                self.parameter_event(self.data)
+            }
+            DATA_ENTRY_LSB => {
+                self.data = (self.data & 0x3F80) | value as u16;
+                self.parameter_event(self.data)
+            }
+            DATA_INCREMENT => self.step_event(1),
+            DATA_DECREMENT => self.step_event(-1),
+            0..=31 => {
+                let index = control as usize;
+                self.msb[index] = value;
+                // a new MSB invalidates the old LSB
+                self.lsb[index] = 0;
+                Some(ControllerEvent::Control {
+                    control,
+                    value: self.paired_value(index),
+                })
+            }
+            32..=63 => {
+                let index = control as usize - 32;
+                self.lsb[index] = value;
+                self.fine |= 1 << index;
+                Some(ControllerEvent::Control {
+                    control: control - 32,
+                    value: self.paired_value(index),
+                })
+            }
+            _ => Some(ControllerEvent::Control {
+                control,
+                value: widen(value),
+            }),
+        }
+    }
+
+    fn paired_value(&self, index: usize) -> u16 {
+        if self.fine & (1 << index) != 0 {
+            (self.msb[index] as u16) << 7 | self.lsb[index] as u16
+        } else {
+            widen(self.msb[index])
+        }
+    }
+
+    fn select(
+        &mut self,
+        selected: Selected,
+        msb: Option<u8>,
+        lsb: Option<u8>,
+    ) -> Option<ControllerEvent> {
+        let number = match selected {
+            Selected::Nrpn => &mut self.nrpn,
+            _ => &mut self.rpn,
+        };
+        if let Some(msb) = msb {
+            *number = (msb as u16) << 7 | (*number & 0x7F);
+        }
+        if let Some(lsb) = lsb {
+            *number = (*number & 0x3F80) | lsb as u16;
+        }
+        self.selected = if *number == RPN_NULL {
+            Selected::None
+        } else {
+            selected
+        };
+        self.data = 0;
+        None
+    }
+
+    fn parameter_event(&self, value: u16) -> Option<ControllerEvent> {
+        match self.selected {
+            Selected::Rpn => Some(ControllerEvent::Rpn {
+                parameter: self.rpn,
+                value,
+            }),
+            Selected::Nrpn => Some(ControllerEvent::Nrpn {
+                parameter: self.nrpn,
+                value,
+            }),
+            Selected::None => None,
+        }
+    }
+
+    fn step_event(&self, delta: i8) -> Option<ControllerEvent> {
+        match self.selected {
+            Selected::Rpn => Some(ControllerEvent::RpnStep {
+                parameter: self.rpn,
+                delta,
+            }),
+            Selected::Nrpn => Some(ControllerEvent::NrpnStep {
+                parameter: self.nrpn,
+                delta,
+            }),
+            Selected::None => None,
+        }
+    }
+}
+
+impl Default for ControllerState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn control(control: u8, value: u16) -> Option<ControllerEvent> {
+        Some(ControllerEvent::Control { control, value })
+    }
+
+    #[test]
+    fn seven_bit_controllers_widen() {
+        let mut state = ControllerState::new();
+        assert_eq!(state.control_change(74, 127), control(74, MAX_VALUE));
+        assert_eq!(state.control_change(74, 64), control(74, 64 << 7 | 64));
+        assert_eq!(state.control_change(74, 0), control(74, 0));
+    }
+
+    #[test]
+    fn msb_and_lsb_pair() {
+        let mut state = ControllerState::new();
+        // before any LSB the MSB alone is widened
+        assert_eq!(state.control_change(1, 127), control(1, MAX_VALUE));
+        assert_eq!(state.control_change(1, 64), control(1, 64 << 7 | 64));
+        assert_eq!(state.control_change(33, 5), control(1, 64 << 7 | 5));
+        assert_eq!(state.control_change(33, 6), control(1, 64 << 7 | 6));
+        // other controllers keep their own pairing
+        assert_eq!(state.control_change(7, 100), control(7, widen(100)));
+    }
+
+    #[test]
+    fn msb_only_update_drops_the_old_lsb() {
+        let mut state = ControllerState::new();
+        state.control_change(1, 64);
+        state.control_change(33, 5);
+        // a controller that has sent an LSB is fine from then on, so the
+        // MSB lands on a zero LSB until the next one
+        assert_eq!(state.control_change(1, 65), control(1, 65 << 7));
+        assert_eq!(state.control_change(33, 9), control(1, 65 << 7 | 9));
+
+        state.reset();
+        assert_eq!(state.control_change(1, 65), control(1, widen(65)));
+    }
+
+    #[test]
+    fn rpn_data_entry() {
+        let mut state = ControllerState::new();
+        assert_eq!(state.control_change(RPN_MSB, 0), None);
+        assert_eq!(state.control_change(RPN_LSB, 0), None);
+        assert_eq!(
+            state.control_change(DATA_ENTRY_MSB, 12),
+            Some(ControllerEvent::Rpn {
+                parameter: RPN_PITCH_BEND_SENSITIVITY,
+                value: 12 << 7,
+            })
+        );
+        assert_eq!(
+            state.control_change(DATA_ENTRY_LSB, 50),
+            Some(ControllerEvent::Rpn {
+                parameter: RPN_PITCH_BEND_SENSITIVITY,
+                value: 12 << 7 | 50,
+            })
+        );
+    }
+
+    #[test]
+    fn nrpn_takes_over_from_rpn() {
+        let mut state = ControllerState::new();
+        state.control_change(RPN_MSB, 0);
+        state.control_change(RPN_LSB, 2);
+        state.control_change(NRPN_MSB, 3);
+        state.control_change(NRPN_LSB, 7);
+        assert_eq!(
+            state.control_change(DATA_ENTRY_MSB, 1),
+            Some(ControllerEvent::Nrpn {
+                parameter: 3 << 7 | 7,
+                value: 1 << 7,
+            })
+        );
+        // reselecting the RPN goes back to the number it was on
+        state.control_change(RPN_LSB, 2);
+        assert_eq!(
+            state.control_change(DATA_ENTRY_MSB, 64),
+            Some(ControllerEvent::Rpn {
+                parameter: RPN_COARSE_TUNING,
+                value: 64 << 7,
+            })
+        );
+    }
+
+    #[test]
+    fn null_rpn_deselects() {
+        let mut state = ControllerState::new();
+        // nothing is selected to begin with
+        assert_eq!(state.control_change(DATA_ENTRY_MSB, 1), None);
+        assert_eq!(state.control_change(DATA_INCREMENT, 0), None);
+
+        state.control_change(RPN_MSB, 0);
+        state.control_change(RPN_LSB, 0);
+        state.control_change(RPN_MSB, 127);
+        state.control_change(RPN_LSB, 127);
+        assert_eq!(state.control_change(DATA_ENTRY_MSB, 1), None);
+        assert_eq!(state.control_change(DATA_ENTRY_LSB, 1), None);
+        assert_eq!(state.control_change(DATA_DECREMENT, 0), None);
+
+        // the null RPN also ends an NRPN
+        state.control_change(NRPN_MSB, 1);
+        state.control_change(NRPN_LSB, 1);
+        state.control_change(RPN_MSB, 127);
+        state.control_change(RPN_LSB, 127);
+        assert_eq!(state.control_change(DATA_ENTRY_MSB, 1), None);
+    }
+
+    #[test]
+    fn data_increment_and_decrement() {
+        let mut state = ControllerState::new();
+        state.control_change(RPN_MSB, 0);
+        state.control_change(RPN_LSB, 1);
+        assert_eq!(
+            state.control_change(DATA_INCREMENT, 0),
+            Some(ControllerEvent::RpnStep {
+                parameter: RPN_FINE_TUNING,
+                delta: 1,
+            })
+        );
+        assert_eq!(
+            state.control_change(DATA_DECREMENT, 0),
+            Some(ControllerEvent::RpnStep {
+                parameter: RPN_FINE_TUNING,
+                delta: -1,
+            })
+        );
+
+        state.control_change(NRPN_MSB, 2);
+        state.control_change(NRPN_LSB, 7);
+        assert_eq!(
+            state.control_change(DATA_DECREMENT, 0),
+            Some(ControllerEvent::NrpnStep {
+                parameter: 2 << 7 | 7,
+                delta: -1,
+            })
+        );
+    }
+}
@@ -0,0 +1,79 @@
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MidiMessage {
+    NoteOff { channel: u8, note: u8, velocity: u8 },
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    PolyPressure { channel: u8, note: u8, pressure: u8 },
+    ControlChange { channel: u8, control: u8, value: u8 },
+    ProgramChange { channel: u8, program: u8 },
+    ChannelPressure { channel: u8, pressure: u8 },
+    // 14 bit, centered on 8192
+    PitchBend { channel: u8, value: u16 },
+    // in sixteenth notes since the start of the song
+    SongPosition { position: u16 },
+    TimingClock,
+    Start,
+    Continue,
+    Stop,
+}
+
+impl MidiMessage {
+    // Decodes a USB-MIDI event packet: cable number and code index in the
+    // first byte, then up to three bytes of MIDI
+    pub fn from_packet(packet: [u8; 4]) -> Option<Self> {
+        let code_index = packet[0] & 0x0F;
+        let channel = packet[1] & 0x0F;
+        let (data1, data2) = (packet[2] & 0x7F, packet[3] & 0x7F);
+        match code_index {
+            0x8 => Some(MidiMessage::NoteOff {
+                channel,
+                note: data1,
+                velocity: data2,
+            }),
+            // note on with zero velocity is a note off by convention
+            0x9 if data2 == 0 => Some(MidiMessage::NoteOff {
+                channel,
+                note: data1,
+                velocity: 64,
+            }),
+            0x9 => Some(MidiMessage::NoteOn {
+                channel,
+                note: data1,
+                velocity: data2,
+            }),
+            0xA => Some(MidiMessage::PolyPressure {
+                channel,
+                note: data1,
+                pressure: data2,
+            }),
+            0xB => Some(MidiMessage::ControlChange {
+                channel,
+                control: data1,
+                value: data2,
+            }),
+            0xC => Some(MidiMessage::ProgramChange {
+                channel,
+                program: data1,
+            }),
+            0xD => Some(MidiMessage::ChannelPressure {
+                channel,
+                pressure: data1,
+            }),
+            0xE => Some(MidiMessage::PitchBend {
+                channel,
+                value: (data2 as u16) << 7 | data1 as u16,
+            }),
+            0x3 if packet[1] == 0xF2 => Some(MidiMessage::SongPosition {
+                position: (data2 as u16) << 7 | data1 as u16,
+            }),
+            // realtime messages are single bytes, sent either way
+            0x5 | 0xF => match packet[1] {
+                0xF8 => Some(MidiMessage::TimingClock),
+                0xFA => Some(MidiMessage::Start),
+                0xFB => Some(MidiMessage::Continue),
+                0xFC => Some(MidiMessage::Stop),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
@@ -1,2 +1,3 @@
-mod descriptors;
-pub mod device;
+pub mod controllers;
+pub mod message;
+pub mod mtc;
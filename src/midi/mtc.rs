@@ -0,0 +1,358 @@
+use core::fmt;
+
+// F0 7F <device> 01 01 hr mn sc fr F7
+const FULL_FRAME_LEN: usize = 10;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FrameRate {
+    Fps24,
+    Fps25,
+    // 29.97 drop frame
+    Fps30Drop,
+    Fps30,
+}
+
+impl FrameRate {
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0x3 {
+            0 => FrameRate::Fps24,
+            1 => FrameRate::Fps25,
+            2 => FrameRate::Fps30Drop,
+            _ => FrameRate::Fps30,
+        }
+    }
+
+    pub fn frames(self) -> u8 {
+        match self {
+            FrameRate::Fps24 => 24,
+            FrameRate::Fps25 => 25,
+            FrameRate::Fps30Drop | FrameRate::Fps30 => 30,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Timecode {
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+    pub frames: u8,
+    pub rate: FrameRate,
+}
+
+impl Timecode {
+    pub fn advance(&mut self) {
+        self.frames += 1;
+        if self.frames < self.rate.frames() {
+            return;
+        }
+        self.frames = 0;
+        self.seconds += 1;
+        if self.seconds < 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes += 1;
+        if self.minutes == 60 {
+            self.minutes = 0;
+            self.hours = (self.hours + 1) % 24;
+        }
+        // drop frame skips frames 0 and 1 of every minute but each tenth
+        if self.rate == FrameRate::Fps30Drop && !self.minutes.is_multiple_of(10) {
+            self.frames = 2;
+        }
+    }
+}
+
+impl fmt::Display for Timecode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let separator = if self.rate == FrameRate::Fps30Drop {
+            ';'
+        } else {
+            ':'
+        };
+        write!(
+            f,
+            "{:02}:{:02}:{:02}{}{:02}",
+            self.hours, self.minutes, self.seconds, separator, self.frames
+        )
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MtcEvent {
+    // a quarter frame that didn't move the timecode on
+    QuarterFrame,
+    // quarter frames moved the timecode on by a frame
+    Frame(Timecode),
+    // a full frame message, sent when the host locates
+    Located(Timecode),
+}
+
+// Decodes MIDI Time Code straight from USB-MIDI event packets, since full
+// frame messages are SysEx and span several packets
+pub struct MtcDecoder {
+    pieces: [u8; 8],
+    next_piece: u8,
+    // bit n is set once piece n arrived in sequence
+    received: u8,
+    timecode: Option<Timecode>,
+    sysex: [u8; FULL_FRAME_LEN],
+    // None once the SysEx is too long to be a full frame
+    sysex_len: Option<usize>,
+}
+
+impl MtcDecoder {
+    pub fn new() -> Self {
+        Self {
+            pieces: [0; 8],
+            next_piece: 0,
+            received: 0,
+            timecode: None,
+            sysex: [0; FULL_FRAME_LEN],
+            sysex_len: None,
+        }
+    }
+
+    pub fn timecode(&self) -> Option<Timecode> {
+        self.timecode
+    }
+
+    pub fn packet(&mut self, packet: [u8; 4]) -> Option<MtcEvent> {
+        match packet[0] & 0x0F {
+            0x2 if packet[1] == 0xF1 => Some(self.quarter_frame(packet[2])),
+            // SysEx start or continue
+            0x4 => {
+                if packet[1] == 0xF0 {
+                    self.sysex_len = Some(0);
+                }
+                self.sysex_bytes(&packet[1..4]);
+                None
+            }
+            // SysEx end with one, two or three bytes
+            0x5 if packet[1] == 0xF7 => self.sysex_end(&packet[1..2]),
+            0x6 => self.sysex_end(&packet[1..3]),
+            0x7 => self.sysex_end(&packet[1..4]),
+            _ => None,
+        }
+    }
+
+    fn sysex_bytes(&mut self, bytes: &[u8]) {
+        if let Some(len) = self.sysex_len {
+            if len + bytes.len() > FULL_FRAME_LEN {
+                self.sysex_len = None;
+            } else {
+                self.sysex[len..len + bytes.len()].copy_from_slice(bytes);
+                self.sysex_len = Some(len + bytes.len());
+            }
+        }
+    }
+
+    fn sysex_end(&mut self, bytes: &[u8]) -> Option<MtcEvent> {
+        self.sysex_bytes(bytes);
+        let len = self.sysex_len.take()?;
+        match self.sysex[..len] {
+            [0xF0, 0x7F, _, 0x01, 0x01, hours, minutes, seconds, frames, 0xF7] => {
+                let timecode = Timecode {
+                    hours: hours & 0x1F,
+                    minutes: minutes & 0x3F,
+                    seconds: seconds & 0x3F,
+                    frames: frames & 0x1F,
+                    rate: FrameRate::from_bits(hours >> 5),
+                };
+                self.timecode = Some(timecode);
+                // quarter frames start over from the new position
+                self.next_piece = 0;
+                self.received = 0;
+                Some(MtcEvent::Located(timecode))
+            }
+            _ => None,
+        }
+    }
+
+    // Eight quarter frames spread a timecode over two frames. Pieces 0 and 4
+    // start a frame, so the timecode moves on at pieces 3 and 7.
+    fn quarter_frame(&mut self, data: u8) -> MtcEvent {
+        let piece = (data >> 4) & 0x7;
+        if piece != self.next_piece {
+            // out of sequence, or playing backwards
+            self.received = 0;
+            self.timecode = None;
+        }
+        self.pieces[piece as usize] = data & 0x0F;
+        self.received |= 1 << piece;
+        self.next_piece = (piece + 1) & 0x7;
+
+        match piece {
+            3 => {
+                if let Some(timecode) = &mut self.timecode {
+                    timecode.advance();
+                    return MtcEvent::Frame(*timecode);
+                }
+            }
+            7 if self.received == 0xFF => {
+                // the pieces describe the frame they started in, two frames ago
+                let mut timecode = self.assemble();
+                timecode.advance();
+                timecode.advance();
+                self.timecode = Some(timecode);
+                return MtcEvent::Frame(timecode);
+            }
+            _ => {}
+        }
+        MtcEvent::QuarterFrame
+    }
+
+    fn assemble(&self) -> Timecode {
+        let p = &self.pieces;
+        Timecode {
+            hours: p[6] | (p[7] & 0x1) << 4,
+            minutes: p[4] | (p[5] & 0x3) << 4,
+            seconds: p[2] | (p[3] & 0x3) << 4,
+            frames: p[0] | (p[1] & 0x1) << 4,
+            rate: FrameRate::from_bits(p[7] >> 1),
+        }
+    }
+}
+
+impl Default for MtcDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timecode(hours: u8, minutes: u8, seconds: u8, frames: u8, rate: FrameRate) -> Timecode {
+        Timecode {
+            hours,
+            minutes,
+            seconds,
+            frames,
+            rate,
+        }
+    }
+
+    // The eight quarter frames describing a timecode, as USB-MIDI packets
+    fn quarter_frames(timecode: Timecode) -> [[u8; 4]; 8] {
+        let rate = match timecode.rate {
+            FrameRate::Fps24 => 0,
+            FrameRate::Fps25 => 1,
+            FrameRate::Fps30Drop => 2,
+            FrameRate::Fps30 => 3,
+        };
+        let nibbles = [
+            timecode.frames & 0x0F,
+            timecode.frames >> 4,
+            timecode.seconds & 0x0F,
+            timecode.seconds >> 4,
+            timecode.minutes & 0x0F,
+            timecode.minutes >> 4,
+            timecode.hours & 0x0F,
+            timecode.hours >> 4 | rate << 1,
+        ];
+        let mut packets = [[0; 4]; 8];
+        for (piece, (packet, nibble)) in packets.iter_mut().zip(nibbles.iter()).enumerate() {
+            *packet = [0x02, 0xF1, (piece as u8) << 4 | nibble, 0];
+        }
+        packets
+    }
+
+    // Feeds quarter frames, returning the last event
+    fn feed(decoder: &mut MtcDecoder, packets: &[[u8; 4]]) -> Option<MtcEvent> {
+        packets
+            .iter()
+            .map(|packet| decoder.packet(*packet))
+            .last()?
+    }
+
+    #[test]
+    fn assembles_quarter_frames_two_frames_on() {
+        let mut decoder = MtcDecoder::new();
+        let packets = quarter_frames(timecode(1, 2, 3, 4, FrameRate::Fps25));
+        assert_eq!(
+            feed(&mut decoder, &packets[..7]),
+            Some(MtcEvent::QuarterFrame)
+        );
+        assert_eq!(decoder.timecode(), None);
+        let located = timecode(1, 2, 3, 6, FrameRate::Fps25);
+        assert_eq!(decoder.packet(packets[7]), Some(MtcEvent::Frame(located)));
+        assert_eq!(decoder.timecode(), Some(located));
+
+        // the next frame follows at piece 3
+        assert_eq!(
+            feed(&mut decoder, &packets[..4]),
+            Some(MtcEvent::Frame(timecode(1, 2, 3, 7, FrameRate::Fps25)))
+        );
+    }
+
+    #[test]
+    fn compensation_carries_into_the_next_second() {
+        let mut decoder = MtcDecoder::new();
+        let packets = quarter_frames(timecode(0, 59, 59, 23, FrameRate::Fps25));
+        assert_eq!(
+            feed(&mut decoder, &packets),
+            Some(MtcEvent::Frame(timecode(1, 0, 0, 0, FrameRate::Fps25)))
+        );
+
+        let packets = quarter_frames(timecode(0, 0, 10, 29, FrameRate::Fps30));
+        assert_eq!(
+            feed(&mut decoder, &packets),
+            Some(MtcEvent::Frame(timecode(0, 0, 11, 1, FrameRate::Fps30)))
+        );
+    }
+
+    #[test]
+    fn drop_frame_skips_the_first_two_frames() {
+        let mut decoder = MtcDecoder::new();
+        let packets = quarter_frames(timecode(0, 0, 59, 28, FrameRate::Fps30Drop));
+        let dropped = timecode(0, 1, 0, 2, FrameRate::Fps30Drop);
+        assert_eq!(feed(&mut decoder, &packets), Some(MtcEvent::Frame(dropped)));
+        assert_eq!(format!("{}", dropped), "00:01:00;02");
+
+        // but not every tenth minute
+        let packets = quarter_frames(timecode(0, 9, 59, 28, FrameRate::Fps30Drop));
+        assert_eq!(
+            feed(&mut decoder, &packets),
+            Some(MtcEvent::Frame(timecode(0, 10, 0, 0, FrameRate::Fps30Drop)))
+        );
+    }
+
+    #[test]
+    fn out_of_sequence_pieces_drop_the_timecode() {
+        let mut decoder = MtcDecoder::new();
+        let packets = quarter_frames(timecode(0, 0, 1, 0, FrameRate::Fps30));
+        feed(&mut decoder, &packets);
+        assert!(decoder.timecode().is_some());
+        assert_eq!(decoder.packet(packets[2]), Some(MtcEvent::QuarterFrame));
+        assert_eq!(decoder.timecode(), None);
+    }
+
+    #[test]
+    fn full_frames_locate() {
+        let mut decoder = MtcDecoder::new();
+        let packets = quarter_frames(timecode(0, 0, 1, 0, FrameRate::Fps24));
+        // partway through a frame when the host jumps
+        feed(&mut decoder, &packets[..5]);
+
+        // F0 7F 7F 01 01 hr mn sc fr F7, 30fps, over four packets
+        let located = timecode(2, 3, 4, 5, FrameRate::Fps30);
+        assert_eq!(decoder.packet([0x04, 0xF0, 0x7F, 0x7F]), None);
+        assert_eq!(decoder.packet([0x04, 0x01, 0x01, 0x62]), None);
+        assert_eq!(decoder.packet([0x04, 0x03, 0x04, 0x05]), None);
+        assert_eq!(
+            decoder.packet([0x05, 0xF7, 0, 0]),
+            Some(MtcEvent::Located(located))
+        );
+        assert_eq!(decoder.timecode(), Some(located));
+
+        // quarter frames carry on from the located position
+        let packets = quarter_frames(located);
+        assert_eq!(
+            feed(&mut decoder, &packets[..4]),
+            Some(MtcEvent::Frame(timecode(2, 3, 4, 6, FrameRate::Fps30)))
+        );
+    }
+}
@@ -0,0 +1,462 @@
+use crate::instrument::VOICES;
+use crate::pitch::{Pitch, SEMITONE};
+use crate::routing::Output;
+use crate::volts::Millivolts;
+
+pub const SLOTS: usize = 16;
+
+// Fixed point one. Unipolar sources run 0..=ONE, bipolar ones -ONE..=ONE,
+// and amounts -ONE..=ONE.
+pub const ONE: i32 = 1 << 14;
+
+// Offsets at full amount, fine tune is in cents
+const OFFSET_MV: i32 = 10_000;
+const FINE_TUNE_CENTS: i32 = 100;
+
+// NRPN editing, the MSB picks the slot and the LSB one of these
+const NRPN_SOURCE: u16 = 0;
+const NRPN_SOURCE_PARAM: u16 = 1;
+const NRPN_VIA: u16 = 2;
+const NRPN_VIA_PARAM: u16 = 3;
+const NRPN_DESTINATION: u16 = 4;
+const NRPN_OUTPUT: u16 = 5;
+const NRPN_OUTPUT_INDEX: u16 = 6;
+const NRPN_AMOUNT: u16 = 7;
+// as a source or via, empties it
+const NRPN_NONE: u8 = 127;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ModSource {
+    Velocity,
+    Control(u8),
+    // channel pressure or the note's poly pressure, whichever is higher
+    Aftertouch,
+    PitchBend,
+    // note 0 to note 120, so a full amount offset tracks 1V/octave
+    KeyTrack,
+    Lfo(u8),
+    Envelope,
+    // picked afresh for every note
+    Random,
+}
+
+impl ModSource {
+    pub fn is_bipolar(self) -> bool {
+        matches!(
+            self,
+            ModSource::PitchBend | ModSource::Lfo(_) | ModSource::Random
+        )
+    }
+
+    pub fn to_parts(self) -> (u8, u8) {
+        match self {
+            ModSource::Velocity => (0, 0),
+            ModSource::Control(control) => (1, control),
+            ModSource::Aftertouch => (2, 0),
+            ModSource::PitchBend => (3, 0),
+            ModSource::KeyTrack => (4, 0),
+            ModSource::Lfo(lfo) => (5, lfo),
+            ModSource::Envelope => (6, 0),
+            ModSource::Random => (7, 0),
+        }
+    }
+
+    pub fn from_parts(tag: u8, param: u8) -> Option<Self> {
+        match tag {
+            0 => Some(ModSource::Velocity),
+            1 => Some(ModSource::Control(param & 0x7F)),
+            2 => Some(ModSource::Aftertouch),
+            3 => Some(ModSource::PitchBend),
+            4 => Some(ModSource::KeyTrack),
+            5 => Some(ModSource::Lfo(param)),
+            6 => Some(ModSource::Envelope),
+            7 => Some(ModSource::Random),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Destination {
+    Level(Output),
+    Offset(Output),
+    FineTune,
+}
+
+impl Destination {
+    pub fn to_parts(self) -> (u8, u8, u8) {
+        match self {
+            Destination::Level(output) => {
+                let (output, index) = output.to_parts();
+                (0, output, index)
+            }
+            Destination::Offset(output) => {
+                let (output, index) = output.to_parts();
+                (1, output, index)
+            }
+            Destination::FineTune => (2, 0, 0),
+        }
+    }
+
+    pub fn from_parts(tag: u8, output: u8, index: u8) -> Option<Self> {
+        match tag {
+            0 => Some(Destination::Level(Output::from_parts(output, index)?)),
+            1 => Some(Destination::Offset(Output::from_parts(output, index)?)),
+            2 => Some(Destination::FineTune),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Slot {
+    pub source: ModSource,
+    // scales the source, for things like mod wheel depth on an LFO
+    pub via: Option<ModSource>,
+    pub destination: Destination,
+    pub amount: i16,
+}
+
+impl Slot {
+    pub fn new(source: ModSource, destination: Destination, amount: i16) -> Self {
+        Self {
+            source,
+            via: None,
+            destination,
+            amount,
+        }
+    }
+
+    fn is_bipolar(&self) -> bool {
+        self.source.is_bipolar() || matches!(self.via, Some(via) if via.is_bipolar())
+    }
+}
+
+// Voice whose sources feed an output, anything not part of a voice follows
+// the first
+fn voice_for(output: Output) -> usize {
+    let voice = match output {
+        Output::Gate(voice) | Output::Pitch(voice) => voice as usize,
+        Output::Aux(aux) => aux as usize / 2,
+        _ => 0,
+    };
+    voice.min(VOICES - 1)
+}
+
+// Routes modulation sources to output levels and offsets and to the pitch.
+// The sources are sampled per voice at the control rate, everything else is
+// worked out from those samples as outputs are rendered.
+#[derive(Clone, Copy)]
+pub struct ModMatrix {
+    slots: [Option<Slot>; SLOTS],
+    // each slot's source after the via, per voice
+    values: [[i32; VOICES]; SLOTS],
+}
+
+impl ModMatrix {
+    pub fn new() -> Self {
+        Self {
+            slots: [None; SLOTS],
+            values: [[0; VOICES]; SLOTS],
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<Slot> {
+        self.slots[index]
+    }
+
+    pub fn set(&mut self, index: usize, slot: Option<Slot>) {
+        self.slots[index] = slot;
+        self.values[index] = [0; VOICES];
+    }
+
+    fn active(&self) -> impl Iterator<Item = (&Slot, &[i32; VOICES])> {
+        self.slots
+            .iter()
+            .zip(self.values.iter())
+            .filter_map(|(slot, values)| slot.as_ref().map(|slot| (slot, values)))
+    }
+
+    // One control tick, source gives a source's value for a voice
+    pub fn evaluate<F: FnMut(usize, ModSource) -> i32>(&mut self, mut source: F) {
+        for (slot, values) in self.slots.iter().zip(self.values.iter_mut()) {
+            let slot = match slot {
+                Some(slot) => slot,
+                None => continue,
+            };
+            for (voice, value) in values.iter_mut().enumerate() {
+                *value = source(voice, slot.source).clamp(-ONE, ONE);
+                if let Some(via) = slot.via {
+                    *value = *value * source(voice, via).clamp(-ONE, ONE) / ONE;
+                }
+            }
+        }
+    }
+
+    // Each level slot pulls the gain down from one by its amount, as far as
+    // its source is from full. A negative amount works the other way up.
+    pub fn gain(&self, output: Output) -> i32 {
+        let voice = voice_for(output);
+        let mut gain = ONE;
+        for (slot, values) in self.active() {
+            if slot.destination != Destination::Level(output) {
+                continue;
+            }
+            let mut value = values[voice];
+            if slot.is_bipolar() {
+                value = (value + ONE) / 2;
+            }
+            let amount = (slot.amount as i32).clamp(-ONE, ONE);
+            let missing = if amount >= 0 { ONE - value } else { value };
+            gain = gain * (ONE - amount.abs() * missing / ONE) / ONE;
+        }
+        gain
+    }
+
+    pub fn offset(&self, output: Output) -> Millivolts {
+        let voice = voice_for(output);
+        let mut offset = 0;
+        for (slot, values) in self.active() {
+            if slot.destination == Destination::Offset(output) {
+                offset += slot.amount as i32 * values[voice] / ONE;
+            }
+        }
+        Millivolts(offset * OFFSET_MV / ONE)
+    }
+
+    pub fn fine_tune(&self, voice: usize) -> Pitch {
+        let mut fine_tune = 0;
+        for (slot, values) in self.active() {
+            if slot.destination == Destination::FineTune {
+                fine_tune += slot.amount as i32 * values[voice] / ONE;
+            }
+        }
+        Pitch(fine_tune * FINE_TUNE_CENTS * SEMITONE / (100 * ONE))
+    }
+
+    // Level then offset, for a voltage on its way to an output
+    pub fn modulate(&self, output: Output, voltage: Millivolts) -> Millivolts {
+        Millivolts(voltage.0 * self.gain(output) / ONE + self.offset(output).0)
+    }
+
+    // Outputs with an offset, which the matrix can drive on its own
+    pub fn offset_outputs(&self) -> impl Iterator<Item = Output> + '_ {
+        self.active()
+            .filter_map(|(slot, _)| match slot.destination {
+                Destination::Offset(output) => Some(output),
+                _ => None,
+            })
+    }
+
+    // Edits a slot one field at a time. Most fields take the 7-bit data MSB,
+    // the amount is the full 14 bits centred on zero.
+    pub fn nrpn(&mut self, parameter: u16, value: u16) {
+        let index = (parameter >> 7) as usize;
+        if index >= SLOTS {
+            return;
+        }
+        let data = (value >> 7) as u8;
+        let mut slot = self.slots[index]
+            .unwrap_or_else(|| Slot::new(ModSource::Velocity, Destination::FineTune, 0));
+        let (source, source_param) = slot.source.to_parts();
+        let via = slot.via.map_or((NRPN_NONE, 0), ModSource::to_parts);
+        let (destination, output, output_index) = slot.destination.to_parts();
+        match parameter & 0x7F {
+            NRPN_SOURCE if data == NRPN_NONE => {
+                self.set(index, None);
+                return;
+            }
+            NRPN_SOURCE => {
+                slot.source = ModSource::from_parts(data, source_param).unwrap_or(slot.source)
+            }
+            NRPN_SOURCE_PARAM => {
+                slot.source = ModSource::from_parts(source, data).unwrap_or(slot.source)
+            }
+            NRPN_VIA => slot.via = ModSource::from_parts(data, via.1),
+            NRPN_VIA_PARAM => slot.via = ModSource::from_parts(via.0, data),
+            NRPN_DESTINATION => {
+                slot.destination =
+                    Destination::from_parts(data, output, output_index).unwrap_or(slot.destination)
+            }
+            NRPN_OUTPUT => {
+                slot.destination = Destination::from_parts(destination, data, output_index)
+                    .unwrap_or(slot.destination)
+            }
+            NRPN_OUTPUT_INDEX => {
+                slot.destination =
+                    Destination::from_parts(destination, output, data).unwrap_or(slot.destination)
+            }
+            NRPN_AMOUNT => slot.amount = ((value as i32 - 8192) * 2).clamp(-ONE, ONE) as i16,
+            _ => return,
+        }
+        self.slots[index] = Some(slot);
+    }
+}
+
+impl Default for ModMatrix {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matrix(slots: &[Slot], source: fn(usize, ModSource) -> i32) -> ModMatrix {
+        let mut matrix = ModMatrix::new();
+        for (index, slot) in slots.iter().enumerate() {
+            matrix.set(index, Some(*slot));
+        }
+        matrix.evaluate(source);
+        matrix
+    }
+
+    #[test]
+    fn offsets_sum_per_output() {
+        let aux = Output::Aux(0);
+        let matrix = matrix(
+            &[
+                Slot::new(
+                    ModSource::Velocity,
+                    Destination::Offset(aux),
+                    ONE as i16 / 2,
+                ),
+                Slot::new(
+                    ModSource::Envelope,
+                    Destination::Offset(aux),
+                    ONE as i16 / 4,
+                ),
+                Slot::new(
+                    ModSource::Velocity,
+                    Destination::Offset(Output::Aux(1)),
+                    100,
+                ),
+            ],
+            |_, _| ONE,
+        );
+        assert_eq!(matrix.offset(aux), Millivolts(7_500));
+        assert_eq!(matrix.offset_outputs().filter(|o| *o == aux).count(), 2);
+        assert_eq!(matrix.offset(Output::Aux(2)), Millivolts(0));
+    }
+
+    #[test]
+    fn sources_follow_their_voice() {
+        let matrix = matrix(
+            &[Slot::new(
+                ModSource::KeyTrack,
+                Destination::Offset(Output::Pitch(2)),
+                ONE as i16,
+            )],
+            |voice, _| voice as i32 * ONE / 4,
+        );
+        assert_eq!(matrix.offset(Output::Pitch(2)), Millivolts(5_000));
+    }
+
+    #[test]
+    fn sources_and_amounts_clamp() {
+        let aux = Output::Aux(0);
+        let mut matrix = matrix(
+            &[Slot::new(
+                ModSource::Control(1),
+                Destination::Offset(aux),
+                ONE as i16,
+            )],
+            |_, _| 4 * ONE,
+        );
+        assert_eq!(matrix.offset(aux), Millivolts(10_000));
+        matrix.evaluate(|_, _| -4 * ONE);
+        assert_eq!(matrix.offset(aux), Millivolts(-10_000));
+
+        // the amount NRPN is centred, and can't go past full
+        matrix.nrpn(NRPN_AMOUNT, 0x2000);
+        assert_eq!(matrix.get(0).unwrap().amount, 0);
+        matrix.nrpn(NRPN_AMOUNT, 0);
+        assert_eq!(matrix.get(0).unwrap().amount, -ONE as i16);
+    }
+
+    #[test]
+    fn via_scales_the_source() {
+        let aux = Output::Aux(0);
+        let mut slot = Slot::new(ModSource::Lfo(0), Destination::Offset(aux), ONE as i16);
+        slot.via = Some(ModSource::Control(1));
+        let matrix = matrix(&[slot], |_, source| match source {
+            ModSource::Lfo(_) => -ONE,
+            _ => ONE / 2,
+        });
+        assert_eq!(matrix.offset(aux), Millivolts(-5_000));
+    }
+
+    #[test]
+    fn level_pulls_gain_down() {
+        let aux = Output::Aux(0);
+        let level = |amount: i16, source: fn(usize, ModSource) -> i32| {
+            matrix(
+                &[Slot::new(
+                    ModSource::Velocity,
+                    Destination::Level(aux),
+                    amount,
+                )],
+                source,
+            )
+            .modulate(aux, Millivolts(8_000))
+        };
+        assert_eq!(level(ONE as i16, |_, _| ONE), Millivolts(8_000));
+        assert_eq!(level(ONE as i16, |_, _| ONE / 2), Millivolts(4_000));
+        assert_eq!(level(ONE as i16 / 2, |_, _| 0), Millivolts(4_000));
+        // negative amounts close as the source opens
+        assert_eq!(level(-ONE as i16, |_, _| ONE), Millivolts(0));
+        assert_eq!(level(-ONE as i16, |_, _| 0), Millivolts(8_000));
+    }
+
+    #[test]
+    fn bipolar_levels_centre() {
+        let aux = Output::Aux(0);
+        let matrix = matrix(
+            &[Slot::new(
+                ModSource::Lfo(0),
+                Destination::Level(aux),
+                ONE as i16,
+            )],
+            |_, _| 0,
+        );
+        assert_eq!(matrix.gain(aux), ONE / 2);
+    }
+
+    #[test]
+    fn fine_tune_spans_a_semitone() {
+        let fine_tune = |amount: i16, value: i32| {
+            let mut matrix = ModMatrix::new();
+            matrix.set(
+                0,
+                Some(Slot::new(
+                    ModSource::PitchBend,
+                    Destination::FineTune,
+                    amount,
+                )),
+            );
+            matrix.evaluate(|_, _| value);
+            matrix.fine_tune(0)
+        };
+        assert_eq!(fine_tune(ONE as i16, ONE), Pitch(SEMITONE));
+        assert_eq!(fine_tune(ONE as i16, -ONE), Pitch(-SEMITONE));
+        assert_eq!(fine_tune(ONE as i16 / 2, ONE / 2), Pitch(SEMITONE / 4));
+        assert_eq!(fine_tune(-(ONE as i16), 2 * ONE), Pitch(-SEMITONE));
+    }
+
+    #[test]
+    fn emptied_slots_stop_modulating() {
+        let aux = Output::Aux(0);
+        let mut matrix = matrix(
+            &[Slot::new(
+                ModSource::Velocity,
+                Destination::Offset(aux),
+                ONE as i16,
+            )],
+            |_, _| ONE,
+        );
+        matrix.nrpn(NRPN_SOURCE, (NRPN_NONE as u16) << 7);
+        assert_eq!(matrix.get(0), None);
+        assert_eq!(matrix.offset(aux), Millivolts(0));
+    }
+}
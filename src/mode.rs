@@ -0,0 +1,116 @@
+pub const ZONES: usize = 4;
+
+// A range of notes on one channel played by a single voice
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Zone {
+    pub channel: u8,
+    pub low: u8,
+    pub high: u8,
+    pub voice: u8,
+    // semitones added to every note in the zone
+    pub transpose: i8,
+}
+
+impl Zone {
+    pub const fn new(channel: u8, low: u8, high: u8, voice: u8) -> Self {
+        Self {
+            channel,
+            low,
+            high,
+            voice,
+            transpose: 0,
+        }
+    }
+
+    pub fn contains(&self, channel: u8, note: u8) -> bool {
+        self.channel == channel && (self.low..=self.high).contains(&note)
+    }
+}
+
+// How incoming channels and notes are spread over the voices
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mode {
+    // every channel plays all voices polyphonically
+    Omni,
+    // one channel plays all voices polyphonically
+    Single(u8),
+    // voice n is a monophonic voice on channel base + n
+    PerVoice { base: u8 },
+    // overlapping zones layer their voices
+    Split([Option<Zone>; ZONES]),
+    // MPE lower and upper zones, given as their number of member channels.
+    // Channel 1 and 16 are the master channels. Every note is allocated
+    // polyphonically, so plain single channel input still plays.
+    Mpe { lower: u8, upper: u8 },
+}
+
+// Where a note should go once the mode has been applied
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Target {
+    // any free voice, stealing if needed
+    Poly,
+    // always this voice, last note priority
+    Voice { voice: usize, transpose: i8 },
+}
+
+// Modes the encoder steps through, splitting channel 1 at middle C
+pub const PRESETS: [Mode; 5] = [
+    Mode::Omni,
+    Mode::Single(0),
+    Mode::PerVoice { base: 0 },
+    Mode::Split([
+        Some(Zone::new(0, 0, 59, 0)),
+        Some(Zone::new(0, 60, 127, 1)),
+        None,
+        None,
+    ]),
+    Mode::Mpe {
+        lower: 15,
+        upper: 0,
+    },
+];
+
+impl Mode {
+    // Moves through the presets, a custom mode starts from the first
+    pub fn step(self, steps: i32) -> Mode {
+        let index = PRESETS.iter().position(|mode| *mode == self).unwrap_or(0) as i32;
+        PRESETS[(index + steps).rem_euclid(PRESETS.len() as i32) as usize]
+    }
+
+    // Master channel of the MPE zone a member channel belongs to
+    pub fn master(&self, channel: u8) -> Option<u8> {
+        match *self {
+            Mode::Mpe { lower, .. } if channel >= 1 && channel <= lower => Some(0),
+            Mode::Mpe { upper, .. } if channel <= 14 && channel + upper >= 15 => Some(15),
+            _ => None,
+        }
+    }
+
+    // Calls f for every target of a note, nothing if the mode ignores it
+    pub fn targets<F: FnMut(Target)>(&self, channel: u8, note: u8, voices: usize, mut f: F) {
+        match *self {
+            Mode::Omni | Mode::Mpe { .. } => f(Target::Poly),
+            Mode::Single(listen) if listen == channel => f(Target::Poly),
+            Mode::Single(_) => {}
+            Mode::PerVoice { base } => {
+                let voice = channel.wrapping_sub(base) as usize;
+                if voice < voices {
+                    f(Target::Voice {
+                        voice,
+                        transpose: 0,
+                    });
+                }
+            }
+            Mode::Split(zones) => {
+                for zone in zones.iter().flatten() {
+                    if zone.contains(channel, note) && (zone.voice as usize) < voices {
+                        f(Target::Voice {
+                            voice: zone.voice as usize,
+                            transpose: zone.transpose,
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,75 @@
+// Fixed point pitch in 1/256ths of a semitone, where 0 is MIDI note 0
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Pitch(pub i32);
+
+pub const SEMITONE: i32 = 256;
+
+impl Pitch {
+    pub fn from_note(note: u8) -> Self {
+        Pitch(note as i32 * SEMITONE)
+    }
+
+    pub fn from_cents(cents: i32) -> Self {
+        Pitch(cents * SEMITONE / 100)
+    }
+
+    pub fn offset(self, by: Pitch) -> Self {
+        Pitch(self.0 + by.0)
+    }
+}
+
+pub const CALIBRATION_POINTS: usize = 11;
+const OCTAVE: i32 = 12 * SEMITONE;
+
+// Measured DAC codes for every C from note 0 up to note 120. Pitches in
+// between are interpolated linearly within their octave, so fixing one
+// octave's tracking doesn't disturb the others.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Calibration {
+    points: [u16; CALIBRATION_POINTS],
+}
+
+impl Calibration {
+    // Nominal panel tracking: note 24 at code 800 and 34 codes per semitone
+    pub fn new() -> Self {
+        let mut points = [0; CALIBRATION_POINTS];
+        for (octave, point) in points.iter_mut().enumerate() {
+            *point = (800 + (octave as i32 * 12 - 24) * 34).max(0) as u16;
+        }
+        Self { points }
+    }
+
+    pub fn point(&self, octave: usize) -> u16 {
+        self.points[octave]
+    }
+
+    pub fn set_point(&mut self, octave: usize, code: u16) {
+        self.points[octave] = code.min(4095);
+    }
+
+    // One DAC code per detent, tuned by ear or against a tuner while the
+    // octave's C is held
+    pub fn adjust(&mut self, octave: usize, steps: i32) {
+        let code = self.points[octave] as i32 + steps;
+        self.set_point(octave, code.max(0) as u16);
+    }
+
+    pub fn code(&self, pitch: Pitch) -> u16 {
+        // pitches past either end extrapolate along the outer octaves
+        let octave = pitch
+            .0
+            .div_euclid(OCTAVE)
+            .clamp(0, CALIBRATION_POINTS as i32 - 2) as usize;
+        let low = self.points[octave] as i32;
+        let high = self.points[octave + 1] as i32;
+        let within = pitch.0 - octave as i32 * OCTAVE;
+        let code = low + (high - low) * within / OCTAVE;
+        code.clamp(0, 4095) as u16
+    }
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,231 @@
+use crate::scale::{Scale, SCALES};
+
+// Undefined controllers, the choices spread evenly over each
+const QUANTIZE: u8 = 106;
+const SCALE: u8 = 107;
+const ROOT: u8 = 108;
+// centred on 64
+const TRANSPOSE: u8 = 109;
+const OCTAVE: u8 = 110;
+// a user scale, six degrees on each
+const SCALE_LOW: u8 = 111;
+const SCALE_HIGH: u8 = 112;
+
+const MAX_TRANSPOSE: i8 = 24;
+const MAX_OCTAVE: i8 = 4;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum QuantizerParam {
+    Quantize,
+    Scale,
+    Root,
+    Transpose,
+    Octave,
+    // flips one degree of the scale, making it a user scale
+    Degree(u8),
+}
+
+impl QuantizerParam {
+    pub fn next(self) -> Self {
+        match self {
+            QuantizerParam::Quantize => QuantizerParam::Scale,
+            QuantizerParam::Scale => QuantizerParam::Root,
+            QuantizerParam::Root => QuantizerParam::Transpose,
+            QuantizerParam::Transpose => QuantizerParam::Octave,
+            QuantizerParam::Octave => QuantizerParam::Degree(0),
+            QuantizerParam::Degree(degree) if degree < 11 => QuantizerParam::Degree(degree + 1),
+            QuantizerParam::Degree(_) => QuantizerParam::Quantize,
+        }
+    }
+}
+
+// The last stage before the voices, whatever the note came from: played,
+// chorded, arpeggiated or sequenced. The scale and root are also the key
+// chords are built in.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Quantizer {
+    pub quantize: bool,
+    pub scale: Scale,
+    pub root: u8,
+    // semitones
+    pub transpose: i8,
+    pub octave: i8,
+}
+
+impl Quantizer {
+    pub fn new() -> Self {
+        Self {
+            quantize: false,
+            scale: Scale::MAJOR,
+            root: 0,
+            transpose: 0,
+            octave: 0,
+        }
+    }
+
+    // Into the scale first, so a transpose moves the key along with it
+    pub fn process(&self, note: u8) -> u8 {
+        let note = if self.quantize {
+            self.scale.nearest(note, self.root)
+        } else {
+            note
+        };
+        let shift = self.transpose as i32 + 12 * self.octave as i32;
+        (note as i32 + shift).clamp(0, 127) as u8
+    }
+
+    // Encoder turns, switches flip on any turn
+    pub fn adjust(&mut self, param: QuantizerParam, steps: i32) {
+        match param {
+            QuantizerParam::Quantize => self.quantize = !self.quantize,
+            QuantizerParam::Scale => self.scale = self.scale.step(steps),
+            QuantizerParam::Root => self.root = (self.root as i32 + steps).rem_euclid(12) as u8,
+            QuantizerParam::Transpose => {
+                let transpose = self.transpose as i32 + steps;
+                self.transpose = transpose.clamp(-MAX_TRANSPOSE as i32, MAX_TRANSPOSE as i32) as i8
+            }
+            QuantizerParam::Octave => {
+                let octave = self.octave as i32 + steps;
+                self.octave = octave.clamp(-MAX_OCTAVE as i32, MAX_OCTAVE as i32) as i8
+            }
+            QuantizerParam::Degree(degree) => self.scale = Scale(self.scale.0 ^ 1 << degree),
+        }
+    }
+
+    // Any channel's controllers set the quantizer
+    pub fn control(&mut self, control: u8, value: u16) {
+        let value = (value >> 7) as i32;
+        match control {
+            QUANTIZE => self.quantize = value >= 64,
+            SCALE => self.scale = SCALES[value as usize * SCALES.len() / 128],
+            ROOT => self.root = (value * 12 / 128) as u8,
+            TRANSPOSE => {
+                self.transpose =
+                    (value - 64).clamp(-MAX_TRANSPOSE as i32, MAX_TRANSPOSE as i32) as i8
+            }
+            OCTAVE => {
+                self.octave = (value * (2 * MAX_OCTAVE as i32 + 1) / 128 - MAX_OCTAVE as i32) as i8
+            }
+            SCALE_LOW => self.scale = Scale(self.scale.0 & 0xFC0 | value as u16 & 0x3F),
+            SCALE_HIGH => self.scale = Scale(self.scale.0 & 0x3F | (value as u16 & 0x3F) << 6),
+            _ => {}
+        }
+    }
+}
+
+impl Default for Quantizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quantizer(scale: Scale, root: u8) -> Quantizer {
+        Quantizer {
+            quantize: true,
+            scale,
+            root,
+            ..Quantizer::new()
+        }
+    }
+
+    #[test]
+    fn off_passes_notes_through() {
+        let quantizer = Quantizer::new();
+        for note in 0..128 {
+            assert_eq!(quantizer.process(note), note);
+        }
+    }
+
+    #[test]
+    fn snaps_to_the_nearest_degree() {
+        let quantizer = quantizer(Scale::MAJOR, 0);
+        assert_eq!(quantizer.process(60), 60);
+        assert_eq!(quantizer.process(61), 60);
+        assert_eq!(quantizer.process(66), 65);
+        assert_eq!(quantizer.process(70), 69);
+        // D major, C# is in and C goes down to B
+        let quantizer = self::quantizer(Scale::MAJOR, 2);
+        assert_eq!(quantizer.process(61), 61);
+        assert_eq!(quantizer.process(60), 59);
+    }
+
+    #[test]
+    fn snaps_at_the_ends_of_the_range() {
+        // A minor pentatonic has C at the bottom and G at the top, F# is
+        // nearer G than E
+        let quantizer = quantizer(Scale::MINOR_PENTATONIC, 9);
+        assert_eq!(quantizer.process(0), 0);
+        assert_eq!(quantizer.process(1), 0);
+        assert_eq!(quantizer.process(127), 127);
+        assert_eq!(quantizer.process(126), 127);
+        // with nothing below or above, notes can only go the other way
+        let quantizer = self::quantizer(Scale(1 << 1), 0);
+        assert_eq!(quantizer.process(0), 1);
+        assert_eq!(quantizer.process(127), 121);
+        let quantizer = self::quantizer(Scale(1 << 11), 0);
+        assert_eq!(quantizer.process(127), 119);
+    }
+
+    #[test]
+    fn empty_scales_leave_notes_be() {
+        let quantizer = quantizer(Scale(0), 0);
+        assert_eq!(quantizer.process(61), 61);
+    }
+
+    #[test]
+    fn transpose_moves_the_key() {
+        let mut quantizer = quantizer(Scale::MAJOR, 0);
+        quantizer.transpose = 2;
+        // into C major first, then up a tone
+        assert_eq!(quantizer.process(61), 62);
+        assert_eq!(quantizer.process(64), 66);
+        quantizer.octave = -1;
+        assert_eq!(quantizer.process(64), 54);
+    }
+
+    #[test]
+    fn transpose_clamps_to_midi_notes() {
+        let mut quantizer = Quantizer::new();
+        quantizer.octave = MAX_OCTAVE;
+        assert_eq!(quantizer.process(100), 127);
+        quantizer.octave = -MAX_OCTAVE;
+        quantizer.transpose = -MAX_TRANSPOSE;
+        assert_eq!(quantizer.process(20), 0);
+    }
+
+    #[test]
+    fn adjust_limits_transpose_and_octave() {
+        let mut quantizer = Quantizer::new();
+        quantizer.adjust(QuantizerParam::Transpose, 30);
+        assert_eq!(quantizer.transpose, MAX_TRANSPOSE);
+        quantizer.adjust(QuantizerParam::Octave, -9);
+        assert_eq!(quantizer.octave, -MAX_OCTAVE);
+        quantizer.adjust(QuantizerParam::Root, -1);
+        assert_eq!(quantizer.root, 11);
+        quantizer.adjust(QuantizerParam::Degree(1), 1);
+        assert_eq!(quantizer.scale, Scale(Scale::MAJOR.0 | 1 << 1));
+    }
+
+    #[test]
+    fn controllers_spread_over_the_choices() {
+        let mut quantizer = Quantizer::new();
+        quantizer.control(TRANSPOSE, 64 << 7);
+        assert_eq!(quantizer.transpose, 0);
+        quantizer.control(TRANSPOSE, 0);
+        assert_eq!(quantizer.transpose, -MAX_TRANSPOSE);
+        quantizer.control(TRANSPOSE, 127 << 7);
+        assert_eq!(quantizer.transpose, MAX_TRANSPOSE);
+        quantizer.control(OCTAVE, 0);
+        assert_eq!(quantizer.octave, -MAX_OCTAVE);
+        quantizer.control(OCTAVE, 127 << 7);
+        assert_eq!(quantizer.octave, MAX_OCTAVE);
+        quantizer.control(SCALE, 127 << 7);
+        assert_eq!(quantizer.scale, SCALES[SCALES.len() - 1]);
+        quantizer.control(ROOT, 127 << 7);
+        assert_eq!(quantizer.root, 11);
+    }
+}
@@ -0,0 +1,239 @@
+use crate::frame::{Priority, OUTPUTS};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Output {
+    Gate(u8),
+    Pitch(u8),
+    Aux(u8),
+    // divided clock triggers and the transport state
+    Clock(u8),
+    Run,
+    Reset,
+    // follow MIDI time code
+    TimecodeRun,
+    TimecodeTrigger(u8),
+}
+
+impl Output {
+    // Tag and index, for settings and NRPN addressing
+    pub fn to_parts(self) -> (u8, u8) {
+        match self {
+            Output::Gate(index) => (0, index),
+            Output::Pitch(index) => (1, index),
+            Output::Aux(index) => (2, index),
+            Output::Clock(index) => (3, index),
+            Output::Run => (4, 0),
+            Output::Reset => (5, 0),
+            Output::TimecodeRun => (6, 0),
+            Output::TimecodeTrigger(index) => (7, index),
+        }
+    }
+
+    pub fn from_parts(tag: u8, index: u8) -> Option<Self> {
+        match tag {
+            0 => Some(Output::Gate(index)),
+            1 => Some(Output::Pitch(index)),
+            2 => Some(Output::Aux(index)),
+            3 => Some(Output::Clock(index)),
+            4 => Some(Output::Run),
+            5 => Some(Output::Reset),
+            6 => Some(Output::TimecodeRun),
+            7 => Some(Output::TimecodeTrigger(index)),
+            _ => None,
+        }
+    }
+
+    pub fn priority(&self) -> Priority {
+        match self {
+            Output::Pitch(_) => Priority::Pitch,
+            Output::Aux(_) => Priority::Aux,
+            // the gates and triggers
+            _ => Priority::Gate,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Route {
+    pub dac: u8,
+    pub channel: u8,
+}
+
+impl Route {
+    pub fn new(dac: u8, channel: u8) -> Self {
+        assert!(dac <= 3 && channel <= 3);
+        Self { dac, channel }
+    }
+
+    pub fn slot(&self) -> usize {
+        self.dac as usize * 4 + self.channel as usize
+    }
+}
+
+// Maps each physical DAC channel to the logical output it carries. A logical
+// output can feed several channels, and unassigned channels are left alone.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RoutingTable {
+    slots: [Option<Output>; OUTPUTS],
+}
+
+impl RoutingTable {
+    pub fn empty() -> Self {
+        Self {
+            slots: [None; OUTPUTS],
+        }
+    }
+
+    // The panel as built: each DAC is one voice with gate, pitch and two aux
+    // outputs, which is four pitch/gate pairs plus eight modulation outputs
+    pub fn voices() -> Self {
+        let mut table = Self::empty();
+        for voice in 0..4 {
+            table.assign(Route::new(voice, 3), Some(Output::Gate(voice)));
+            table.assign(Route::new(voice, 2), Some(Output::Pitch(voice)));
+            table.assign(Route::new(voice, 1), Some(Output::Aux(voice * 2)));
+            table.assign(Route::new(voice, 0), Some(Output::Aux(voice * 2 + 1)));
+        }
+        table
+    }
+
+    // Three voices, with the fourth DAC carrying run, reset and two clocks
+    pub fn clocked() -> Self {
+        let mut table = Self::voices();
+        table.assign(Route::new(3, 3), Some(Output::Run));
+        table.assign(Route::new(3, 2), Some(Output::Reset));
+        table.assign(Route::new(3, 1), Some(Output::Clock(0)));
+        table.assign(Route::new(3, 0), Some(Output::Clock(1)));
+        table
+    }
+
+    // Three voices, with the fourth DAC following time code
+    pub fn timecode() -> Self {
+        let mut table = Self::voices();
+        table.assign(Route::new(3, 3), Some(Output::TimecodeRun));
+        table.assign(Route::new(3, 2), Some(Output::TimecodeTrigger(0)));
+        table.assign(Route::new(3, 1), Some(Output::TimecodeTrigger(1)));
+        table
+    }
+
+    pub fn drums() -> Self {
+        let mut table = Self::empty();
+        for slot in 0..OUTPUTS {
+            table.slots[slot] = Some(Output::Gate(slot as u8));
+        }
+        table
+    }
+
+    // Through the tables the outputs page offers
+    pub fn step(self, steps: i32) -> Self {
+        let presets = [
+            Self::voices(),
+            Self::clocked(),
+            Self::timecode(),
+            Self::drums(),
+        ];
+        let index = presets.iter().position(|table| *table == self).unwrap_or(0) as i32;
+        presets[(index + steps).clamp(0, presets.len() as i32 - 1) as usize]
+    }
+
+    pub fn assign(&mut self, route: Route, output: Option<Output>) {
+        self.slots[route.slot()] = output;
+    }
+
+    pub fn output(&self, slot: usize) -> Option<Output> {
+        self.slots[slot]
+    }
+
+    pub fn slots_for(&self, output: Output) -> impl Iterator<Item = usize> + '_ {
+        (0..OUTPUTS).filter(move |slot| self.slots[*slot] == Some(output))
+    }
+}
+
+impl Default for RoutingTable {
+    fn default() -> Self {
+        Self::voices()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slots(table: &RoutingTable, output: Output) -> [Option<usize>; 2] {
+        let mut slots = table.slots_for(output);
+        [slots.next(), slots.next()]
+    }
+
+    #[test]
+    fn default_is_a_voice_per_dac() {
+        let table = RoutingTable::default();
+        assert_eq!(table, RoutingTable::voices());
+        for voice in 0..4u8 {
+            let dac = voice as usize * 4;
+            assert_eq!(slots(&table, Output::Gate(voice)), [Some(dac + 3), None]);
+            assert_eq!(slots(&table, Output::Pitch(voice)), [Some(dac + 2), None]);
+            assert_eq!(slots(&table, Output::Aux(voice * 2)), [Some(dac + 1), None]);
+            assert_eq!(slots(&table, Output::Aux(voice * 2 + 1)), [Some(dac), None]);
+        }
+        assert!((0..OUTPUTS).all(|slot| table.output(slot).is_some()));
+        assert_eq!(slots(&table, Output::Run), [None, None]);
+    }
+
+    #[test]
+    fn default_priorities_match_the_frame() {
+        let table = RoutingTable::default();
+        for dac in 0..4 {
+            assert_eq!(
+                table.output(dac * 4 + 3).unwrap().priority(),
+                Priority::Gate
+            );
+            assert_eq!(
+                table.output(dac * 4 + 2).unwrap().priority(),
+                Priority::Pitch
+            );
+            assert_eq!(table.output(dac * 4 + 1).unwrap().priority(), Priority::Aux);
+        }
+    }
+
+    #[test]
+    fn outputs_can_feed_several_channels() {
+        let mut table = RoutingTable::empty();
+        table.assign(Route::new(0, 3), Some(Output::Gate(0)));
+        table.assign(Route::new(2, 0), Some(Output::Gate(0)));
+        assert_eq!(slots(&table, Output::Gate(0)), [Some(3), Some(8)]);
+        table.assign(Route::new(0, 3), None);
+        assert_eq!(slots(&table, Output::Gate(0)), [Some(8), None]);
+    }
+
+    #[test]
+    fn stepping_through_presets() {
+        let table = RoutingTable::default();
+        assert_eq!(table.step(1), RoutingTable::clocked());
+        assert_eq!(table.step(2), RoutingTable::timecode());
+        assert_eq!(table.step(5), RoutingTable::drums());
+        assert_eq!(RoutingTable::drums().step(-2), RoutingTable::clocked());
+        // an edited table starts over from the first preset
+        let mut edited = RoutingTable::voices();
+        edited.assign(Route::new(0, 0), None);
+        assert_eq!(edited.step(0), RoutingTable::voices());
+    }
+
+    #[test]
+    fn parts_round_trip() {
+        let outputs = [
+            Output::Gate(1),
+            Output::Pitch(3),
+            Output::Aux(7),
+            Output::Clock(2),
+            Output::Run,
+            Output::Reset,
+            Output::TimecodeRun,
+            Output::TimecodeTrigger(1),
+        ];
+        for output in outputs.iter() {
+            let (tag, index) = output.to_parts();
+            assert_eq!(Output::from_parts(tag, index), Some(*output));
+        }
+        assert_eq!(Output::from_parts(8, 0), None);
+    }
+}
@@ -0,0 +1,136 @@
+// Twelve bits, one per semitone up from the root
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Scale(pub u16);
+
+impl Scale {
+    pub const CHROMATIC: Scale = Scale(0xFFF);
+    pub const MAJOR: Scale = Scale(0b1010_1011_0101);
+    pub const MINOR: Scale = Scale(0b0101_1010_1101);
+    pub const DORIAN: Scale = Scale(0b0110_1010_1101);
+    pub const PHRYGIAN: Scale = Scale(0b0101_1010_1011);
+    pub const LYDIAN: Scale = Scale(0b1010_1101_0101);
+    pub const MIXOLYDIAN: Scale = Scale(0b0110_1011_0101);
+    pub const LOCRIAN: Scale = Scale(0b0101_0110_1011);
+    pub const MAJOR_PENTATONIC: Scale = Scale(0b0010_1001_0101);
+    pub const MINOR_PENTATONIC: Scale = Scale(0b0100_1010_1001);
+
+    // Through the scales the encoder and controller offer, a user mask
+    // starts back at the first
+    pub fn step(self, steps: i32) -> Self {
+        let index = SCALES.iter().position(|scale| *scale == self).unwrap_or(0) as i32;
+        SCALES[(index + steps).rem_euclid(SCALES.len() as i32) as usize]
+    }
+
+    pub fn contains(self, note: u8, root: u8) -> bool {
+        self.has_degree(note as i32 - root as i32)
+    }
+
+    // Semitones from the root, in any octave
+    fn has_degree(self, semitones: i32) -> bool {
+        self.0 & 1 << semitones.rem_euclid(12) != 0
+    }
+
+    // Nearest note of the scale at or below, or the lowest one if there's
+    // none below. An empty scale leaves notes be.
+    pub fn snap_down(self, note: u8, root: u8) -> u8 {
+        if self.0 & 0xFFF == 0 {
+            return note;
+        }
+        let mut snapped = note as i32;
+        while snapped >= 0 && !self.has_degree(snapped - root as i32) {
+            snapped -= 1;
+        }
+        if snapped < 0 {
+            snapped = 0;
+            while !self.has_degree(snapped - root as i32) {
+                snapped += 1;
+            }
+        }
+        snapped as u8
+    }
+
+    // Nearest note of the scale either way, ties going down
+    pub fn nearest(self, note: u8, root: u8) -> u8 {
+        if self.0 & 0xFFF == 0 {
+            return note;
+        }
+        for distance in 0..12 {
+            let below = note as i32 - distance;
+            let above = note as i32 + distance;
+            if below >= 0 && self.contains(below as u8, root) {
+                return below as u8;
+            }
+            if above <= 127 && self.contains(above as u8, root) {
+                return above as u8;
+            }
+        }
+        note
+    }
+}
+
+pub const SCALES: [Scale; 10] = [
+    Scale::CHROMATIC,
+    Scale::MAJOR,
+    Scale::MINOR,
+    Scale::DORIAN,
+    Scale::PHRYGIAN,
+    Scale::LYDIAN,
+    Scale::MIXOLYDIAN,
+    Scale::LOCRIAN,
+    Scale::MAJOR_PENTATONIC,
+    Scale::MINOR_PENTATONIC,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn degrees_are_from_the_root() {
+        assert!(Scale::MAJOR.contains(60, 0));
+        assert!(!Scale::MAJOR.contains(61, 0));
+        // E major has G# but not G
+        assert!(Scale::MAJOR.contains(68, 4));
+        assert!(!Scale::MAJOR.contains(67, 4));
+        // below the root in the lowest octave
+        assert!(Scale::MAJOR.contains(1, 2));
+    }
+
+    #[test]
+    fn snap_down_stays_in_the_scale() {
+        for root in 0..12 {
+            for scale in SCALES.iter() {
+                for note in 0..128 {
+                    let snapped = scale.snap_down(note, root);
+                    assert!(scale.contains(snapped, root));
+                    if snapped <= note {
+                        assert!((snapped + 1..=note).all(|n| !scale.contains(n, root)));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn snap_down_goes_up_off_the_bottom() {
+        // D major, nothing below C# at the bottom
+        assert_eq!(Scale::MAJOR.snap_down(0, 2), 1);
+        assert_eq!(Scale::MAJOR.snap_down(1, 2), 1);
+        assert_eq!(Scale::MAJOR.snap_down(3, 2), 2);
+        assert_eq!(Scale(1 << 11).snap_down(5, 0), 11);
+    }
+
+    #[test]
+    fn empty_scales_leave_notes_be() {
+        assert_eq!(Scale(0).snap_down(61, 0), 61);
+        assert_eq!(Scale(0).nearest(61, 0), 61);
+    }
+
+    #[test]
+    fn step_wraps_through_the_scales() {
+        assert_eq!(Scale::CHROMATIC.step(-1), Scale::MINOR_PENTATONIC);
+        assert_eq!(Scale::MINOR_PENTATONIC.step(1), Scale::CHROMATIC);
+        // a user scale starts back at the first
+        assert_eq!(Scale(0b101).step(1), Scale::MAJOR);
+    }
+}
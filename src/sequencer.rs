@@ -0,0 +1,499 @@
+use crate::clock::{ClockSync, Division, PPQN};
+use crate::control::CONTROL_HZ;
+use crate::instrument::VOICES;
+use crate::lfo::Random;
+use crate::midi::message::MidiMessage;
+
+pub const STEPS: usize = 16;
+pub const PATTERNS: usize = 4;
+const MAX_RATCHETS: u8 = 4;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Step {
+    // None rests
+    pub note: Option<u8>,
+    // percent of the step, or of each ratchet's share of it
+    pub gate: u8,
+    // 7-bit values for the track's two aux outputs
+    pub aux: [u8; 2],
+    // holds the gate into the next step, which then moves the pitch without
+    // a retrigger
+    pub tie: bool,
+    // glides into the pitch
+    pub slide: bool,
+    // percent chance the step plays
+    pub probability: u8,
+    // notes squeezed into the step
+    pub ratchets: u8,
+}
+
+impl Step {
+    pub const REST: Step = Step {
+        note: None,
+        gate: 50,
+        aux: [0; 2],
+        tie: false,
+        slide: false,
+        probability: 100,
+        ratchets: 1,
+    };
+}
+
+// One per voice, playing its pitch, gate and aux outputs
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Track {
+    pub steps: [Step; STEPS],
+    pub length: u8,
+}
+
+impl Track {
+    pub const fn new() -> Self {
+        Self {
+            steps: [Step::REST; STEPS],
+            length: STEPS as u8,
+        }
+    }
+
+    fn length(&self) -> usize {
+        (self.length as usize).clamp(1, STEPS)
+    }
+}
+
+impl Default for Track {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Pattern {
+    pub tracks: [Track; VOICES],
+    pub division: Division,
+}
+
+impl Pattern {
+    pub const fn new() -> Self {
+        Self {
+            tracks: [Track::new(); VOICES],
+            division: Division::PPQN_4,
+        }
+    }
+}
+
+impl Default for Pattern {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// What the encoder edits on the sequencer page, a press moves on to the next
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SeqField {
+    Play,
+    Record,
+    Pattern,
+    Track,
+    Step,
+    Note,
+    Gate,
+    Aux1,
+    Aux2,
+    Tie,
+    Slide,
+    Probability,
+    Ratchets,
+    Length,
+    Division,
+}
+
+impl SeqField {
+    fn next(self) -> Self {
+        match self {
+            SeqField::Play => SeqField::Record,
+            SeqField::Record => SeqField::Pattern,
+            SeqField::Pattern => SeqField::Track,
+            SeqField::Track => SeqField::Step,
+            SeqField::Step => SeqField::Note,
+            SeqField::Note => SeqField::Gate,
+            SeqField::Gate => SeqField::Aux1,
+            SeqField::Aux1 => SeqField::Aux2,
+            SeqField::Aux2 => SeqField::Tie,
+            SeqField::Tie => SeqField::Slide,
+            SeqField::Slide => SeqField::Probability,
+            SeqField::Probability => SeqField::Ratchets,
+            SeqField::Ratchets => SeqField::Length,
+            SeqField::Length => SeqField::Division,
+            SeqField::Division => SeqField::Play,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SeqNote {
+    pub note: u8,
+    pub aux: [u8; 2],
+    pub slide: bool,
+    // the previous step was tied, so the gate is already up
+    pub legato: bool,
+}
+
+// What a control tick asks of a track's voice, the gate drops before any
+// new note
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct SeqEvent {
+    pub off: bool,
+    pub on: Option<SeqNote>,
+}
+
+#[derive(Clone, Copy)]
+struct TrackState {
+    // None until the first step after a start
+    step: Option<usize>,
+    // the step's note, if it played
+    note: Option<u8>,
+    gate: bool,
+    tie: bool,
+    // ratchets started so far in the step
+    ratchet: u8,
+}
+
+impl TrackState {
+    const fn new() -> Self {
+        Self {
+            step: None,
+            note: None,
+            gate: false,
+            tie: false,
+            ratchet: 0,
+        }
+    }
+}
+
+// Plays a pattern on the voices, one track each, stepping on a division of
+// the followed clock. Turned on, a track owns its voice as long as it has a
+// note anywhere.
+pub struct Sequencer {
+    patterns: [Pattern; PATTERNS],
+    current: usize,
+    playing: bool,
+    recording: bool,
+    // clock started and not stopped
+    running: bool,
+    // the edit cursor, also where stopped recording writes
+    field: SeqField,
+    track: usize,
+    step: usize,
+    states: [TrackState; VOICES],
+    position: u32,
+    // control ticks since the last step
+    since_step: u32,
+    random: Random,
+}
+
+impl Sequencer {
+    pub fn new() -> Self {
+        Self {
+            patterns: [Pattern::new(); PATTERNS],
+            current: 0,
+            playing: false,
+            recording: false,
+            running: false,
+            field: SeqField::Play,
+            track: 0,
+            step: 0,
+            states: [TrackState::new(); VOICES],
+            position: 0,
+            since_step: 0,
+            random: Random::new(7),
+        }
+    }
+
+    pub fn pattern(&self, index: usize) -> &Pattern {
+        &self.patterns[index]
+    }
+
+    pub fn set_pattern(&mut self, index: usize, pattern: Pattern) {
+        self.patterns[index] = pattern;
+    }
+
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    pub fn set_current(&mut self, current: usize) {
+        self.current = current.min(PATTERNS - 1);
+    }
+
+    pub fn owns(&self, voice: usize) -> bool {
+        let track = &self.patterns[self.current].tracks[voice];
+        self.playing
+            && track.steps[..track.length()]
+                .iter()
+                .any(|step| step.note.is_some())
+    }
+
+    // The transport runs the pattern from wherever the clock's position puts
+    // it, Start also forgets the steps that were playing
+    pub fn transport(&mut self, message: MidiMessage) {
+        match message {
+            MidiMessage::Start => {
+                self.running = true;
+                for state in self.states.iter_mut() {
+                    state.step = None;
+                }
+            }
+            MidiMessage::Continue => self.running = true,
+            MidiMessage::Stop => self.running = false,
+            _ => {}
+        }
+    }
+
+    // Incoming notes while armed. Running, they land on the track's current
+    // step, stopped they fill in from the cursor one step at a time.
+    pub fn record(&mut self, note: u8, velocity: u8) {
+        if !self.recording {
+            return;
+        }
+        let track = &mut self.patterns[self.current].tracks[self.track];
+        let length = track.length();
+        let step = match self.states[self.track].step {
+            Some(step) if self.running && self.playing => step,
+            _ => {
+                let step = self.step.min(length - 1);
+                self.step = (step + 1) % length;
+                step
+            }
+        };
+        let step = &mut track.steps[step];
+        step.note = Some(note);
+        step.aux[0] = velocity;
+    }
+
+    pub fn next_field(&mut self) {
+        self.field = self.field.next();
+    }
+
+    // Encoder turns on the current field, switches flip on any turn
+    pub fn adjust(&mut self, steps: i32) {
+        let pattern = &mut self.patterns[self.current];
+        let track = &mut pattern.tracks[self.track];
+        let length = track.length() as i32;
+        let step = &mut track.steps[self.step];
+        let add = |value: u8, min: i32, max: i32| (value as i32 + steps).clamp(min, max) as u8;
+        match self.field {
+            SeqField::Play => self.playing = !self.playing,
+            SeqField::Record => self.recording = !self.recording,
+            SeqField::Pattern => {
+                self.current = (self.current as i32 + steps).rem_euclid(PATTERNS as i32) as usize
+            }
+            SeqField::Track => {
+                self.track = (self.track as i32 + steps).rem_euclid(VOICES as i32) as usize
+            }
+            SeqField::Step => self.step = (self.step as i32 + steps).rem_euclid(length) as usize,
+            // turning down from the lowest note rests
+            SeqField::Note => {
+                step.note = match step.note {
+                    None if steps > 0 => Some(60),
+                    None => None,
+                    Some(note) if note as i32 + steps < 0 => None,
+                    Some(note) => Some(add(note, 0, 127)),
+                }
+            }
+            SeqField::Gate => step.gate = (step.gate as i32 + steps * 5).clamp(5, 100) as u8,
+            SeqField::Aux1 => step.aux[0] = add(step.aux[0], 0, 127),
+            SeqField::Aux2 => step.aux[1] = add(step.aux[1], 0, 127),
+            SeqField::Tie => step.tie = !step.tie,
+            SeqField::Slide => step.slide = !step.slide,
+            SeqField::Probability => {
+                step.probability = (step.probability as i32 + steps * 5).clamp(0, 100) as u8
+            }
+            SeqField::Ratchets => step.ratchets = add(step.ratchets, 1, MAX_RATCHETS as i32),
+            SeqField::Length => {
+                track.length = add(track.length, 1, STEPS as i32);
+                self.step = self.step.min(track.length() - 1);
+            }
+            SeqField::Division => pattern.division = pattern.division.step(steps),
+        }
+    }
+
+    fn chance(&mut self, percent: u8) -> bool {
+        self.random.next_u32() % 100 < percent as u32
+    }
+
+    // One control tick
+    pub fn update(&mut self, sync: &ClockSync) -> [SeqEvent; VOICES] {
+        let mut events = [SeqEvent::default(); VOICES];
+        let pattern = self.patterns[self.current];
+        let division = pattern.division.0.max(1);
+        let ticked = sync.step(self.position, pattern.division);
+        self.position = sync.position;
+
+        if !(self.playing && self.running) {
+            for (state, event) in self.states.iter_mut().zip(events.iter_mut()) {
+                event.off = state.gate;
+                *state = TrackState {
+                    step: state.step,
+                    ..TrackState::new()
+                };
+            }
+            return events;
+        }
+        self.since_step = self.since_step.saturating_add(1);
+
+        // tracks step from the top of the song, so they line up again after
+        // a relocate or loop
+        if let Some(song_step) = ticked {
+            self.since_step = 0;
+            for (index, event) in events.iter_mut().enumerate() {
+                let track = &pattern.tracks[index];
+                let mut state = self.states[index];
+                let position = song_step as usize % track.length();
+                let step = track.steps[position];
+                let plays = step.note.is_some() && self.chance(step.probability);
+                let tied = state.tie && state.gate;
+                state.step = Some(position);
+                match step.note {
+                    Some(note) if plays => {
+                        event.off = state.gate && !tied;
+                        event.on = Some(SeqNote {
+                            note,
+                            aux: step.aux,
+                            slide: step.slide,
+                            legato: tied,
+                        });
+                        state.note = Some(note);
+                        state.gate = true;
+                        state.tie = step.tie;
+                        state.ratchet = 1;
+                    }
+                    _ => {
+                        event.off = state.gate;
+                        state = TrackState {
+                            step: state.step,
+                            ..TrackState::new()
+                        };
+                    }
+                }
+                self.states[index] = state;
+            }
+            return events;
+        }
+
+        // gate lengths and ratchets need a tempo, without one the gate
+        // stays up until the next step
+        let quarter_us = match sync.quarter_us {
+            Some(quarter_us) => quarter_us as u64,
+            None => return events,
+        };
+        let step_us = quarter_us * division as u64 / PPQN as u64;
+        let elapsed_us = self.since_step as u64 * 1_000_000 / CONTROL_HZ as u64;
+        for (index, (state, event)) in self.states.iter_mut().zip(events.iter_mut()).enumerate() {
+            let (note, position) = match (state.note, state.step) {
+                (Some(note), Some(position)) => (note, position),
+                _ => continue,
+            };
+            let step = pattern.tracks[index].steps[position];
+            let ratchets = step.ratchets.clamp(1, MAX_RATCHETS);
+            let share_us = (step_us / ratchets as u64).max(1);
+            let ratchet = (elapsed_us / share_us).min(ratchets as u64 - 1) as u8;
+            if ratchet >= state.ratchet {
+                event.off = state.gate;
+                event.on = Some(SeqNote {
+                    note,
+                    aux: step.aux,
+                    slide: false,
+                    legato: false,
+                });
+                state.gate = true;
+                state.ratchet = ratchet + 1;
+            } else if state.gate && !(state.tie && ratchet == ratchets - 1) {
+                let gate_us = share_us * step.gate as u64 / 100;
+                if elapsed_us - ratchet as u64 * share_us >= gate_us {
+                    event.off = true;
+                    state.gate = false;
+                }
+            }
+        }
+        events
+    }
+}
+
+impl Default for Sequencer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A rising run on the first track, playing and started
+    fn sequencer(length: u8) -> Sequencer {
+        let mut sequencer = Sequencer::new();
+        let mut pattern = Pattern::new();
+        for (index, step) in pattern.tracks[0].steps.iter_mut().enumerate() {
+            step.note = Some(60 + index as u8);
+        }
+        pattern.tracks[0].length = length;
+        sequencer.set_pattern(0, pattern);
+        sequencer.adjust(1);
+        sequencer.transport(MidiMessage::Start);
+        sequencer
+    }
+
+    // The first track's note after the clock gets to a position
+    fn note_at(sequencer: &mut Sequencer, position: u32) -> Option<u8> {
+        let sync = ClockSync {
+            position,
+            quarter_us: None,
+        };
+        sequencer.update(&sync)[0].on.map(|on| on.note)
+    }
+
+    #[test]
+    fn steps_on_the_division() {
+        let mut sequencer = sequencer(16);
+        assert_eq!(note_at(&mut sequencer, 0), None);
+        assert_eq!(note_at(&mut sequencer, 1), Some(60));
+        assert_eq!(note_at(&mut sequencer, 2), None);
+        assert_eq!(note_at(&mut sequencer, 7), Some(61));
+        // the same position again is the same tick
+        assert_eq!(note_at(&mut sequencer, 7), None);
+        assert_eq!(note_at(&mut sequencer, 13), Some(62));
+    }
+
+    #[test]
+    fn relocating_while_running_realigns() {
+        let mut sequencer = sequencer(16);
+        note_at(&mut sequencer, 1);
+        note_at(&mut sequencer, 7);
+        // a song position of eight sixteenths, then the next tick
+        assert_eq!(note_at(&mut sequencer, 48), None);
+        assert_eq!(note_at(&mut sequencer, 49), Some(68));
+        // off the step grid, nothing plays until the next step
+        assert_eq!(note_at(&mut sequencer, 53), None);
+        assert_eq!(note_at(&mut sequencer, 55), Some(69));
+    }
+
+    #[test]
+    fn relocating_while_stopped_realigns() {
+        let mut sequencer = sequencer(16);
+        note_at(&mut sequencer, 1);
+        sequencer.transport(MidiMessage::Stop);
+        note_at(&mut sequencer, 90);
+        sequencer.transport(MidiMessage::Continue);
+        assert_eq!(note_at(&mut sequencer, 91), Some(75));
+    }
+
+    #[test]
+    fn short_tracks_wrap_with_the_song() {
+        let mut sequencer = sequencer(3);
+        let notes: Vec<_> = (0..8)
+            .map(|step| note_at(&mut sequencer, step * 6 + 1).unwrap())
+            .collect();
+        assert_eq!(notes, [60, 61, 62, 60, 61, 62, 60, 61]);
+        // looping back to the second bar lands where the song is
+        assert_eq!(note_at(&mut sequencer, 97), Some(61));
+    }
+}
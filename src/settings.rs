@@ -0,0 +1,938 @@
+use crate::arpeggiator::{ArpMode, ArpParams};
+use crate::chord::{ChordParams, ChordShape};
+use crate::clock::{Clock, Division, MasterParams, CLOCK_OUTPUTS};
+use crate::cv::CvPanel;
+use crate::envelope::EnvelopeParams;
+use crate::frame::OUTPUTS;
+use crate::glide::GlideMode;
+use crate::instrument::Instrument;
+use crate::lfo::{LfoParams, Rate, Retrigger, Shape, LFOS, LFO_DEFAULTS};
+use crate::mapping::{Mapping, MappingTable, Source, MAPPINGS};
+use crate::mod_matrix::{Destination, ModMatrix, ModSource, Slot, SLOTS};
+use crate::mode::{Mode, Zone, ZONES};
+use crate::pitch::{Calibration, CALIBRATION_POINTS};
+use crate::quantizer::Quantizer;
+use crate::routing::{Output, Route, RoutingTable};
+use crate::scale::Scale;
+use crate::sequencer::{Pattern, Step, PATTERNS, STEPS};
+use crate::timecode::{Interval, TimecodeFollower, TIMECODE_TRIGGERS};
+use crate::volts::{Millivolts, OutputRange};
+
+// Bump whenever the layout changes, older records are then ignored
+const VERSION: u8 = 15;
+
+// Mostly the sequencer's patterns
+pub const MAX_SIZE: usize = 3 * 1024;
+
+pub struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, len: 0 }
+    }
+
+    pub fn written(&self) -> usize {
+        self.len
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.buf[self.len] = value;
+        self.len += 1;
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        for byte in value.to_le_bytes().iter() {
+            self.u8(*byte);
+        }
+    }
+
+    pub fn i32(&mut self, value: i32) {
+        for byte in value.to_le_bytes().iter() {
+            self.u8(*byte);
+        }
+    }
+}
+
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        let value = *self.buf.get(self.pos)?;
+        self.pos += 1;
+        Some(value)
+    }
+
+    pub fn bool(&mut self) -> Option<bool> {
+        Some(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes([self.u8()?, self.u8()?]))
+    }
+
+    pub fn i32(&mut self) -> Option<i32> {
+        Some(i32::from_le_bytes([
+            self.u8()?,
+            self.u8()?,
+            self.u8()?,
+            self.u8()?,
+        ]))
+    }
+}
+
+fn write_output(writer: &mut Writer, output: Output) {
+    let (tag, index) = output.to_parts();
+    writer.u8(tag);
+    writer.u8(index);
+}
+
+fn read_output(reader: &mut Reader) -> Option<Output> {
+    let tag = reader.u8()?;
+    let index = reader.u8()?;
+    Output::from_parts(tag, index)
+}
+
+fn write_routing(writer: &mut Writer, routing: &RoutingTable) {
+    for slot in 0..OUTPUTS {
+        match routing.output(slot) {
+            Some(output) => {
+                writer.bool(true);
+                write_output(writer, output);
+            }
+            None => writer.bool(false),
+        }
+    }
+}
+
+fn read_routing(reader: &mut Reader) -> Option<RoutingTable> {
+    let mut routing = RoutingTable::empty();
+    for slot in 0..OUTPUTS {
+        if reader.bool()? {
+            let route = Route::new(slot as u8 / 4, slot as u8 % 4);
+            routing.assign(route, Some(read_output(reader)?));
+        }
+    }
+    Some(routing)
+}
+
+fn write_range(writer: &mut Writer, range: OutputRange) {
+    writer.i32(range.zero().0);
+    writer.i32(range.full_scale().0);
+}
+
+fn read_range(reader: &mut Reader) -> Option<OutputRange> {
+    Some(OutputRange::new(
+        Millivolts(reader.i32()?),
+        Millivolts(reader.i32()?),
+    ))
+}
+
+fn write_interval(writer: &mut Writer, interval: Interval) {
+    writer.u8(match interval {
+        Interval::Frame => 0,
+        Interval::Second => 1,
+        Interval::Minute => 2,
+    });
+}
+
+fn read_interval(reader: &mut Reader) -> Option<Interval> {
+    match reader.u8()? {
+        0 => Some(Interval::Frame),
+        1 => Some(Interval::Second),
+        2 => Some(Interval::Minute),
+        _ => None,
+    }
+}
+
+fn write_mapping(writer: &mut Writer, mapping: &Mapping) {
+    let (tag, control) = match mapping.source {
+        Source::Control(control) => (0, control),
+        Source::ChannelPressure => (1, 0),
+        Source::PolyPressure => (2, 0),
+        Source::Velocity => (3, 0),
+        Source::ReleaseVelocity => (4, 0),
+        Source::PitchBend => (5, 0),
+        Source::Envelope(voice) => (6, voice),
+        Source::Lfo(lfo) => (7, lfo),
+    };
+    writer.u8(tag);
+    writer.u8(control);
+    writer.u8(mapping.channel);
+    write_output(writer, mapping.output);
+    writer.i32(mapping.min.0);
+    writer.i32(mapping.max.0);
+    writer.bool(mapping.invert);
+}
+
+fn read_mapping(reader: &mut Reader) -> Option<Mapping> {
+    let tag = reader.u8()?;
+    let control = reader.u8()?;
+    let source = match tag {
+        0 => Source::Control(control & 0x7F),
+        1 => Source::ChannelPressure,
+        2 => Source::PolyPressure,
+        3 => Source::Velocity,
+        4 => Source::ReleaseVelocity,
+        5 => Source::PitchBend,
+        6 => Source::Envelope(control),
+        7 => Source::Lfo(control),
+        _ => return None,
+    };
+    Some(Mapping {
+        source,
+        channel: reader.u8()? & 0x0F,
+        output: read_output(reader)?,
+        min: Millivolts(reader.i32()?),
+        max: Millivolts(reader.i32()?),
+        invert: reader.bool()?,
+    })
+}
+
+fn write_mode(writer: &mut Writer, mode: &Mode) {
+    match mode {
+        Mode::Omni => writer.u8(0),
+        Mode::Single(channel) => {
+            writer.u8(1);
+            writer.u8(*channel);
+        }
+        Mode::PerVoice { base } => {
+            writer.u8(2);
+            writer.u8(*base);
+        }
+        Mode::Split(zones) => {
+            writer.u8(3);
+            for zone in zones.iter() {
+                writer.bool(zone.is_some());
+                if let Some(zone) = zone {
+                    writer.u8(zone.channel);
+                    writer.u8(zone.low);
+                    writer.u8(zone.high);
+                    writer.u8(zone.voice);
+                    writer.u8(zone.transpose as u8);
+                }
+            }
+        }
+        Mode::Mpe { lower, upper } => {
+            writer.u8(4);
+            writer.u8(*lower);
+            writer.u8(*upper);
+        }
+    }
+}
+
+fn read_mode(reader: &mut Reader) -> Option<Mode> {
+    match reader.u8()? {
+        0 => Some(Mode::Omni),
+        1 => Some(Mode::Single(reader.u8()? & 0x0F)),
+        2 => Some(Mode::PerVoice {
+            base: reader.u8()? & 0x0F,
+        }),
+        3 => {
+            let mut zones = [None; ZONES];
+            for zone in zones.iter_mut() {
+                if reader.bool()? {
+                    *zone = Some(Zone {
+                        channel: reader.u8()? & 0x0F,
+                        low: reader.u8()? & 0x7F,
+                        high: reader.u8()? & 0x7F,
+                        voice: reader.u8()?,
+                        transpose: reader.u8()? as i8,
+                    });
+                }
+            }
+            Some(Mode::Split(zones))
+        }
+        4 => Some(Mode::Mpe {
+            lower: reader.u8()?.min(15),
+            upper: reader.u8()?.min(15),
+        }),
+        _ => None,
+    }
+}
+
+fn write_glide_mode(writer: &mut Writer, glide_mode: GlideMode) {
+    writer.u8(match glide_mode {
+        GlideMode::ConstantTime => 0,
+        GlideMode::ConstantRate => 1,
+        GlideMode::Legato => 2,
+    });
+}
+
+fn read_glide_mode(reader: &mut Reader) -> Option<GlideMode> {
+    match reader.u8()? {
+        0 => Some(GlideMode::ConstantTime),
+        1 => Some(GlideMode::ConstantRate),
+        2 => Some(GlideMode::Legato),
+        _ => None,
+    }
+}
+
+fn write_envelope(writer: &mut Writer, envelope: &EnvelopeParams) {
+    writer.u16(envelope.attack);
+    writer.u16(envelope.decay);
+    writer.u16(envelope.sustain);
+    writer.u16(envelope.release);
+    writer.u16(envelope.velocity);
+    writer.u16(envelope.curve);
+}
+
+fn read_envelope(reader: &mut Reader) -> Option<EnvelopeParams> {
+    Some(EnvelopeParams::new(
+        reader.u16()?,
+        reader.u16()?,
+        reader.u16()?,
+        reader.u16()?,
+        reader.u16()?,
+        reader.u16()?,
+    ))
+}
+
+fn write_arp(writer: &mut Writer, arp: &ArpParams) {
+    writer.u8(match arp.mode {
+        ArpMode::Off => 0,
+        ArpMode::Up => 1,
+        ArpMode::Down => 2,
+        ArpMode::UpDown => 3,
+        ArpMode::AsPlayed => 4,
+        ArpMode::Random => 5,
+    });
+    writer.u8(arp.octaves);
+    writer.u16(arp.division.0);
+    writer.u8(arp.gate);
+    writer.bool(arp.latch);
+    writer.bool(arp.spread);
+}
+
+fn read_arp(reader: &mut Reader) -> Option<ArpParams> {
+    let mode = match reader.u8()? {
+        0 => ArpMode::Off,
+        1 => ArpMode::Up,
+        2 => ArpMode::Down,
+        3 => ArpMode::UpDown,
+        4 => ArpMode::AsPlayed,
+        5 => ArpMode::Random,
+        _ => return None,
+    };
+    Some(ArpParams {
+        mode,
+        octaves: reader.u8()?.clamp(1, 4),
+        division: Division(reader.u16()?.max(1)),
+        gate: reader.u8()?.min(100),
+        latch: reader.bool()?,
+        spread: reader.bool()?,
+    })
+}
+
+fn write_lfo(writer: &mut Writer, lfo: &LfoParams) {
+    writer.u8(match lfo.shape {
+        Shape::Sine => 0,
+        Shape::Triangle => 1,
+        Shape::Saw => 2,
+        Shape::Square => 3,
+        Shape::SampleHold => 4,
+        Shape::SmoothRandom => 5,
+    });
+    match lfo.rate {
+        Rate::Hz(centihertz) => {
+            writer.u8(0);
+            writer.u16(centihertz);
+        }
+        Rate::Sync(division) => {
+            writer.u8(1);
+            writer.u16(division.0);
+        }
+    }
+    writer.u8(match lfo.retrigger {
+        Retrigger::Free => 0,
+        Retrigger::Note => 1,
+        Retrigger::Start => 2,
+    });
+    writer.u16(lfo.vibrato as u16);
+    writer.bool(lfo.mod_wheel);
+    writer.u8(lfo.depth);
+}
+
+fn read_lfo(reader: &mut Reader) -> Option<LfoParams> {
+    let shape = match reader.u8()? {
+        0 => Shape::Sine,
+        1 => Shape::Triangle,
+        2 => Shape::Saw,
+        3 => Shape::Square,
+        4 => Shape::SampleHold,
+        5 => Shape::SmoothRandom,
+        _ => return None,
+    };
+    let rate = match reader.u8()? {
+        0 => Rate::Hz(reader.u16()?),
+        1 => Rate::Sync(Division(reader.u16()?.max(1))),
+        _ => return None,
+    };
+    let retrigger = match reader.u8()? {
+        0 => Retrigger::Free,
+        1 => Retrigger::Note,
+        2 => Retrigger::Start,
+        _ => return None,
+    };
+    Some(LfoParams {
+        vibrato: reader.u16()? as i16,
+        mod_wheel: reader.bool()?,
+        depth: reader.u8()?.min(100),
+        ..LfoParams::new(shape, rate, retrigger)
+    })
+}
+
+fn write_chord(writer: &mut Writer, chord: &ChordParams) {
+    writer.u8(match chord.shape {
+        ChordShape::Off => 0,
+        ChordShape::Major => 1,
+        ChordShape::Minor => 2,
+        ChordShape::Diminished => 3,
+        ChordShape::Augmented => 4,
+        ChordShape::Sus2 => 5,
+        ChordShape::Sus4 => 6,
+        ChordShape::Major7 => 7,
+        ChordShape::Minor7 => 8,
+        ChordShape::Dominant7 => 9,
+        ChordShape::HalfDiminished7 => 10,
+        ChordShape::Diminished7 => 11,
+        ChordShape::Custom => 12,
+    });
+    writer.u8(chord.inversion);
+    writer.u8(chord.spread);
+    for interval in chord.custom.iter() {
+        writer.u8(*interval);
+    }
+    writer.bool(chord.quantize);
+}
+
+fn read_chord(reader: &mut Reader) -> Option<ChordParams> {
+    let shape = match reader.u8()? {
+        0 => ChordShape::Off,
+        1 => ChordShape::Major,
+        2 => ChordShape::Minor,
+        3 => ChordShape::Diminished,
+        4 => ChordShape::Augmented,
+        5 => ChordShape::Sus2,
+        6 => ChordShape::Sus4,
+        7 => ChordShape::Major7,
+        8 => ChordShape::Minor7,
+        9 => ChordShape::Dominant7,
+        10 => ChordShape::HalfDiminished7,
+        11 => ChordShape::Diminished7,
+        12 => ChordShape::Custom,
+        _ => return None,
+    };
+    let inversion = reader.u8()?;
+    let spread = reader.u8()?;
+    let mut custom = [0; 3];
+    for interval in custom.iter_mut() {
+        *interval = reader.u8()?.min(24);
+    }
+    Some(ChordParams {
+        shape,
+        inversion,
+        spread,
+        custom,
+        quantize: reader.bool()?,
+    })
+}
+
+fn write_quantizer(writer: &mut Writer, quantizer: &Quantizer) {
+    writer.bool(quantizer.quantize);
+    writer.u16(quantizer.scale.0);
+    writer.u8(quantizer.root);
+    writer.u8(quantizer.transpose as u8);
+    writer.u8(quantizer.octave as u8);
+}
+
+fn read_quantizer(reader: &mut Reader) -> Option<Quantizer> {
+    Some(Quantizer {
+        quantize: reader.bool()?,
+        scale: Scale(reader.u16()? & 0xFFF),
+        root: reader.u8()? % 12,
+        transpose: (reader.u8()? as i8).clamp(-24, 24),
+        octave: (reader.u8()? as i8).clamp(-4, 4),
+    })
+}
+
+fn write_mod_source(writer: &mut Writer, source: ModSource) {
+    let (tag, param) = source.to_parts();
+    writer.u8(tag);
+    writer.u8(param);
+}
+
+fn read_mod_source(reader: &mut Reader) -> Option<ModSource> {
+    let tag = reader.u8()?;
+    let param = reader.u8()?;
+    ModSource::from_parts(tag, param)
+}
+
+fn write_slot(writer: &mut Writer, slot: &Slot) {
+    write_mod_source(writer, slot.source);
+    writer.bool(slot.via.is_some());
+    if let Some(via) = slot.via {
+        write_mod_source(writer, via);
+    }
+    let (tag, output, index) = slot.destination.to_parts();
+    writer.u8(tag);
+    writer.u8(output);
+    writer.u8(index);
+    writer.u16(slot.amount as u16);
+}
+
+fn read_slot(reader: &mut Reader) -> Option<Slot> {
+    let source = read_mod_source(reader)?;
+    let via = if reader.bool()? {
+        Some(read_mod_source(reader)?)
+    } else {
+        None
+    };
+    let destination = Destination::from_parts(reader.u8()?, reader.u8()?, reader.u8()?)?;
+    Some(Slot {
+        source,
+        via,
+        destination,
+        amount: reader.u16()? as i16,
+    })
+}
+
+// A rest is a note out of MIDI's range. Tie, slide and the ratchets share
+// a byte.
+fn write_step(writer: &mut Writer, step: &Step) {
+    writer.u8(step.note.unwrap_or(0xFF));
+    writer.u8(step.gate);
+    writer.u8(step.aux[0]);
+    writer.u8(step.aux[1]);
+    writer.u8(step.tie as u8 | (step.slide as u8) << 1 | (step.ratchets.max(1) - 1) << 2);
+    writer.u8(step.probability);
+}
+
+fn read_step(reader: &mut Reader) -> Option<Step> {
+    let note = reader.u8()?;
+    let gate = reader.u8()?.clamp(1, 100);
+    let aux = [reader.u8()? & 0x7F, reader.u8()? & 0x7F];
+    let flags = reader.u8()?;
+    Some(Step {
+        note: if note < 0x80 { Some(note) } else { None },
+        gate,
+        aux,
+        tie: flags & 1 != 0,
+        slide: flags & 2 != 0,
+        probability: reader.u8()?.min(100),
+        ratchets: (flags >> 2 & 3) + 1,
+    })
+}
+
+fn write_pattern(writer: &mut Writer, pattern: &Pattern) {
+    writer.u16(pattern.division.0);
+    for track in pattern.tracks.iter() {
+        writer.u8(track.length);
+        for step in track.steps.iter() {
+            write_step(writer, step);
+        }
+    }
+}
+
+fn read_pattern(reader: &mut Reader) -> Option<Pattern> {
+    let mut pattern = Pattern::new();
+    pattern.division = Division(reader.u16()?.max(1));
+    for track in pattern.tracks.iter_mut() {
+        track.length = reader.u8()?.clamp(1, STEPS as u8);
+        for step in track.steps.iter_mut() {
+            *step = read_step(reader)?;
+        }
+    }
+    Some(pattern)
+}
+
+// Everything that survives a power cycle
+pub struct Settings {
+    pub mode: Mode,
+    pub glide_mode: GlideMode,
+    pub envelope: EnvelopeParams,
+    pub quantizer: Quantizer,
+    pub chord: ChordParams,
+    pub arp: ArpParams,
+    pub lfos: [LfoParams; LFOS],
+    pub matrix: ModMatrix,
+    pub patterns: [Pattern; PATTERNS],
+    pub pattern: usize,
+    pub mappings: MappingTable,
+    pub routing: RoutingTable,
+    pub ranges: [OutputRange; OUTPUTS],
+    pub divisions: [Division; CLOCK_OUTPUTS],
+    pub master: MasterParams,
+    pub intervals: [Interval; TIMECODE_TRIGGERS],
+    pub run_timeout: u32,
+    pub calibration: Calibration,
+}
+
+impl Settings {
+    pub fn new(
+        instrument: &Instrument,
+        cv_panel: &CvPanel,
+        clock: &Clock,
+        master: MasterParams,
+        timecode: &TimecodeFollower,
+    ) -> Self {
+        let mut patterns = [Pattern::new(); PATTERNS];
+        for (index, pattern) in patterns.iter_mut().enumerate() {
+            *pattern = *instrument.sequencer().pattern(index);
+        }
+        let mut lfos = LFO_DEFAULTS;
+        for (index, lfo) in lfos.iter_mut().enumerate() {
+            *lfo = instrument.lfo(index).params();
+        }
+        let mut ranges = [OutputRange::default(); OUTPUTS];
+        for (slot, range) in ranges.iter_mut().enumerate() {
+            *range = cv_panel.range(slot);
+        }
+        let mut divisions = [Division::QUARTER; CLOCK_OUTPUTS];
+        for (output, division) in divisions.iter_mut().enumerate() {
+            *division = clock.division(output);
+        }
+        let mut intervals = [Interval::Frame; TIMECODE_TRIGGERS];
+        for (trigger, interval) in intervals.iter_mut().enumerate() {
+            *interval = timecode.interval(trigger);
+        }
+        Self {
+            mode: instrument.mode(),
+            glide_mode: instrument.glide_mode(),
+            envelope: instrument.envelope(),
+            quantizer: instrument.quantizer(),
+            chord: instrument.chord(),
+            arp: instrument.arp(),
+            lfos,
+            matrix: *instrument.matrix(),
+            patterns,
+            pattern: instrument.sequencer().current(),
+            mappings: *instrument.mappings(),
+            routing: *cv_panel.routing(),
+            ranges,
+            divisions,
+            master,
+            intervals,
+            run_timeout: timecode.run_timeout(),
+            calibration: instrument.calibration(),
+        }
+    }
+
+    pub fn apply(
+        &self,
+        instrument: &mut Instrument,
+        cv_panel: &mut CvPanel,
+        clock: &mut Clock,
+        timecode: &mut TimecodeFollower,
+    ) {
+        instrument.set_mode(self.mode);
+        instrument.set_glide_mode(self.glide_mode);
+        instrument.set_envelope(self.envelope);
+        instrument.set_quantizer(self.quantizer);
+        instrument.set_chord(self.chord);
+        instrument.set_arp(self.arp);
+        for (index, lfo) in self.lfos.iter().enumerate() {
+            instrument.lfo_mut(index).set_params(*lfo);
+        }
+        *instrument.matrix_mut() = self.matrix;
+        let sequencer = instrument.sequencer_mut();
+        for (index, pattern) in self.patterns.iter().enumerate() {
+            sequencer.set_pattern(index, *pattern);
+        }
+        sequencer.set_current(self.pattern);
+        *instrument.mappings_mut() = self.mappings;
+        instrument.set_calibration(self.calibration);
+        // ranges first, so channels the routing moves rest at their new 0V
+        for (slot, range) in self.ranges.iter().enumerate() {
+            cv_panel.set_range(slot, *range);
+        }
+        cv_panel.set_routing(self.routing);
+        for (output, division) in self.divisions.iter().enumerate() {
+            clock.set_division(output, *division);
+        }
+        for (trigger, interval) in self.intervals.iter().enumerate() {
+            timecode.set_interval(trigger, *interval);
+        }
+        timecode.set_run_timeout(self.run_timeout);
+    }
+
+    pub fn encode(&self, buf: &mut [u8; MAX_SIZE]) -> usize {
+        let mut writer = Writer::new(buf);
+        writer.u8(VERSION);
+        write_mode(&mut writer, &self.mode);
+        write_glide_mode(&mut writer, self.glide_mode);
+        write_envelope(&mut writer, &self.envelope);
+        write_quantizer(&mut writer, &self.quantizer);
+        write_chord(&mut writer, &self.chord);
+        write_arp(&mut writer, &self.arp);
+        for lfo in self.lfos.iter() {
+            write_lfo(&mut writer, lfo);
+        }
+        for index in 0..SLOTS {
+            match self.matrix.get(index) {
+                Some(slot) => {
+                    writer.bool(true);
+                    write_slot(&mut writer, &slot);
+                }
+                None => writer.bool(false),
+            }
+        }
+        for pattern in self.patterns.iter() {
+            write_pattern(&mut writer, pattern);
+        }
+        writer.u8(self.pattern as u8);
+        for index in 0..MAPPINGS {
+            match self.mappings.get(index) {
+                Some(mapping) => {
+                    writer.bool(true);
+                    write_mapping(&mut writer, &mapping);
+                }
+                None => writer.bool(false),
+            }
+        }
+        write_routing(&mut writer, &self.routing);
+        for range in self.ranges.iter() {
+            write_range(&mut writer, *range);
+        }
+        for division in self.divisions.iter() {
+            writer.u16(division.0);
+        }
+        writer.u16(self.master.tempo as u16);
+        writer.u8(self.master.swing);
+        writer.bool(self.master.send_to_host);
+        for interval in self.intervals.iter() {
+            write_interval(&mut writer, *interval);
+        }
+        writer.u16(self.run_timeout as u16);
+        for octave in 0..CALIBRATION_POINTS {
+            writer.u16(self.calibration.point(octave));
+        }
+        writer.written()
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        let mut reader = Reader::new(buf);
+        if reader.u8()? != VERSION {
+            return None;
+        }
+        let mode = read_mode(&mut reader)?;
+        let glide_mode = read_glide_mode(&mut reader)?;
+        let envelope = read_envelope(&mut reader)?;
+        let quantizer = read_quantizer(&mut reader)?;
+        let chord = read_chord(&mut reader)?;
+        let arp = read_arp(&mut reader)?;
+        let mut lfos = LFO_DEFAULTS;
+        for lfo in lfos.iter_mut() {
+            *lfo = read_lfo(&mut reader)?;
+        }
+        let mut matrix = ModMatrix::new();
+        for index in 0..SLOTS {
+            if reader.bool()? {
+                matrix.set(index, Some(read_slot(&mut reader)?));
+            }
+        }
+        let mut patterns = [Pattern::new(); PATTERNS];
+        for pattern in patterns.iter_mut() {
+            *pattern = read_pattern(&mut reader)?;
+        }
+        let pattern = reader.u8()? as usize;
+        let mut mappings = MappingTable::empty();
+        for index in 0..MAPPINGS {
+            if reader.bool()? {
+                mappings.set(index, Some(read_mapping(&mut reader)?));
+            }
+        }
+        let routing = read_routing(&mut reader)?;
+        let mut ranges = [OutputRange::default(); OUTPUTS];
+        for range in ranges.iter_mut() {
+            *range = read_range(&mut reader)?;
+        }
+        let mut divisions = [Division::QUARTER; CLOCK_OUTPUTS];
+        for division in divisions.iter_mut() {
+            *division = Division(reader.u16()?.max(1));
+        }
+        // the master clock clamps these as it takes them
+        let master = MasterParams {
+            tempo: reader.u16()? as u32,
+            swing: reader.u8()?,
+            send_to_host: reader.bool()?,
+        };
+        let mut intervals = [Interval::Frame; TIMECODE_TRIGGERS];
+        for interval in intervals.iter_mut() {
+            *interval = read_interval(&mut reader)?;
+        }
+        let run_timeout = reader.u16()? as u32;
+        let mut calibration = Calibration::new();
+        for octave in 0..CALIBRATION_POINTS {
+            calibration.set_point(octave, reader.u16()?);
+        }
+        Some(Self {
+            mode,
+            glide_mode,
+            envelope,
+            quantizer,
+            chord,
+            arp,
+            lfos,
+            matrix,
+            patterns,
+            pattern,
+            mappings,
+            routing,
+            ranges,
+            divisions,
+            master,
+            intervals,
+            run_timeout,
+            calibration,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lfo::LfoParam;
+
+    #[test]
+    fn outputs_round_trip() {
+        let instrument = Instrument::new();
+        let mut cv_panel = CvPanel::new();
+        cv_panel.set_routing(RoutingTable::clocked());
+        cv_panel.set_range(2, OutputRange::BIPOLAR_5V);
+        cv_panel.set_range(15, OutputRange::UNIPOLAR_5V.inverted());
+        let mut clock = Clock::new();
+        clock.set_division(1, Division::BAR);
+        clock.set_division(3, Division::PPQN_8);
+        let mut timecode = TimecodeFollower::new();
+        timecode.set_interval(0, Interval::Minute);
+        timecode.set_run_timeout(400);
+        let master = MasterParams {
+            tempo: 1335,
+            swing: 62,
+            send_to_host: true,
+        };
+
+        let mut buf = [0; MAX_SIZE];
+        let len = Settings::new(&instrument, &cv_panel, &clock, master, &timecode).encode(&mut buf);
+        let settings = Settings::decode(&buf[..len]).unwrap();
+        assert_eq!(settings.master, master);
+
+        let mut restored = CvPanel::new();
+        let mut restored_clock = Clock::new();
+        let mut restored_timecode = TimecodeFollower::new();
+        settings.apply(
+            &mut Instrument::new(),
+            &mut restored,
+            &mut restored_clock,
+            &mut restored_timecode,
+        );
+        assert_eq!(*restored.routing(), RoutingTable::clocked());
+        for slot in 0..OUTPUTS {
+            assert_eq!(restored.range(slot), cv_panel.range(slot));
+        }
+        for output in 0..CLOCK_OUTPUTS {
+            assert_eq!(restored_clock.division(output), clock.division(output));
+        }
+        assert_eq!(restored_timecode.interval(0), Interval::Minute);
+        assert_eq!(restored_timecode.interval(1), timecode.interval(1));
+        assert_eq!(restored_timecode.run_timeout(), 400);
+    }
+
+    #[test]
+    fn calibration_round_trips() {
+        let mut instrument = Instrument::new();
+        let mut calibration = instrument.calibration();
+        calibration.adjust(4, -7);
+        calibration.set_point(10, 4000);
+        instrument.set_calibration(calibration);
+
+        let mut buf = [0; MAX_SIZE];
+        let len = Settings::new(
+            &instrument,
+            &CvPanel::new(),
+            &Clock::new(),
+            MasterParams::new(),
+            &TimecodeFollower::new(),
+        )
+        .encode(&mut buf);
+        let mut restored = Instrument::new();
+        Settings::decode(&buf[..len]).unwrap().apply(
+            &mut restored,
+            &mut CvPanel::new(),
+            &mut Clock::new(),
+            &mut TimecodeFollower::new(),
+        );
+        assert_eq!(restored.calibration(), calibration);
+        assert_eq!(
+            restored.calibration().point(4),
+            Calibration::new().point(4) - 7
+        );
+    }
+
+    #[test]
+    fn lfos_round_trip() {
+        let mut instrument = Instrument::new();
+        let mut params = instrument.lfo(1).params();
+        params.adjust(LfoParam::Shape(1), 2);
+        params.adjust(LfoParam::Rate(1), -3);
+        params.adjust(LfoParam::Depth(1), -4);
+        instrument.lfo_mut(1).set_params(params);
+        let free = LfoParams {
+            vibrato: -25,
+            ..LfoParams::new(Shape::Square, Rate::Hz(1234), Retrigger::Note)
+        };
+        instrument.lfo_mut(3).set_params(free);
+
+        let mut buf = [0; MAX_SIZE];
+        let len = Settings::new(
+            &instrument,
+            &CvPanel::new(),
+            &Clock::new(),
+            MasterParams::new(),
+            &TimecodeFollower::new(),
+        )
+        .encode(&mut buf);
+        let mut restored = Instrument::new();
+        Settings::decode(&buf[..len]).unwrap().apply(
+            &mut restored,
+            &mut CvPanel::new(),
+            &mut Clock::new(),
+            &mut TimecodeFollower::new(),
+        );
+        assert_eq!(restored.lfo(1).params(), params);
+        assert_eq!(params.rate, Rate::Sync(Division(384)));
+        assert_eq!(params.depth, 80);
+        assert_eq!(restored.lfo(3).params(), free);
+        assert_eq!(restored.lfo(0).params(), LFO_DEFAULTS[0]);
+    }
+
+    #[test]
+    fn short_or_older_records_are_ignored() {
+        let mut buf = [0; MAX_SIZE];
+        let settings = Settings::new(
+            &Instrument::new(),
+            &CvPanel::new(),
+            &Clock::new(),
+            MasterParams::new(),
+            &TimecodeFollower::new(),
+        );
+        let len = settings.encode(&mut buf);
+        assert!(Settings::decode(&buf[..len - 1]).is_none());
+        buf[0] = VERSION - 1;
+        assert!(Settings::decode(&buf[..len]).is_none());
+    }
+}
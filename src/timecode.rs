@@ -0,0 +1,215 @@
+use crate::clock::TRIGGER_MS;
+use crate::cv::CvPanel;
+use crate::instrument::{GATE_OFF, GATE_ON};
+use crate::midi::mtc::{MtcDecoder, MtcEvent, Timecode};
+use crate::routing::Output;
+
+pub const TIMECODE_TRIGGERS: usize = 2;
+
+// The run gate's timeout is set in steps of this, up to a second
+const RUN_TIMEOUT_STEP_MS: u32 = 50;
+const MAX_RUN_TIMEOUT_MS: u32 = 1000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Interval {
+    Frame,
+    Second,
+    Minute,
+}
+
+impl Interval {
+    pub fn step(self, steps: i32) -> Self {
+        const INTERVALS: [Interval; 3] = [Interval::Frame, Interval::Second, Interval::Minute];
+        let index = INTERVALS
+            .iter()
+            .position(|interval| *interval == self)
+            .unwrap_or(0) as i32;
+        INTERVALS[(index + steps).clamp(0, INTERVALS.len() as i32 - 1) as usize]
+    }
+
+    fn elapsed(self, from: &Timecode, to: &Timecode) -> bool {
+        match self {
+            Interval::Frame => true,
+            Interval::Second => from.seconds != to.seconds,
+            Interval::Minute => from.minutes != to.minutes,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TimecodeParam {
+    Interval(u8),
+    RunTimeout,
+}
+
+impl TimecodeParam {
+    pub fn next(self) -> Self {
+        match self {
+            TimecodeParam::Interval(trigger) if (trigger as usize) < TIMECODE_TRIGGERS - 1 => {
+                TimecodeParam::Interval(trigger + 1)
+            }
+            TimecodeParam::Interval(_) => TimecodeParam::RunTimeout,
+            TimecodeParam::RunTimeout => TimecodeParam::Interval(0),
+        }
+    }
+}
+
+// Drives a run gate and time based triggers from MIDI time code. The run gate
+// stays high while quarter frames keep arriving.
+pub struct TimecodeFollower {
+    decoder: MtcDecoder,
+    last_quarter_frame: Option<u32>,
+    run_timeout_ms: u32,
+    intervals: [Interval; TIMECODE_TRIGGERS],
+    fired: [Option<u32>; TIMECODE_TRIGGERS],
+}
+
+impl TimecodeFollower {
+    pub fn new() -> Self {
+        Self {
+            decoder: MtcDecoder::new(),
+            last_quarter_frame: None,
+            // a few frames at the slowest rate
+            run_timeout_ms: 150,
+            intervals: [Interval::Second, Interval::Frame],
+            fired: [None; TIMECODE_TRIGGERS],
+        }
+    }
+
+    pub fn timecode(&self) -> Option<Timecode> {
+        self.decoder.timecode()
+    }
+
+    pub fn run_timeout(&self) -> u32 {
+        self.run_timeout_ms
+    }
+
+    pub fn set_run_timeout(&mut self, ms: u32) {
+        self.run_timeout_ms = ms.clamp(RUN_TIMEOUT_STEP_MS, MAX_RUN_TIMEOUT_MS);
+    }
+
+    pub fn interval(&self, trigger: usize) -> Interval {
+        self.intervals[trigger]
+    }
+
+    pub fn set_interval(&mut self, trigger: usize, interval: Interval) {
+        self.intervals[trigger] = interval;
+    }
+
+    pub fn adjust(&mut self, param: TimecodeParam, steps: i32) {
+        match param {
+            TimecodeParam::Interval(trigger) => {
+                let trigger = trigger as usize;
+                self.set_interval(trigger, self.intervals[trigger].step(steps));
+            }
+            TimecodeParam::RunTimeout => {
+                let timeout = self.run_timeout_ms as i32 + steps * RUN_TIMEOUT_STEP_MS as i32;
+                self.set_run_timeout(timeout.max(0) as u32);
+            }
+        }
+    }
+
+    pub fn is_running(&self, now: u32) -> bool {
+        matches!(self.last_quarter_frame, Some(at) if now.wrapping_sub(at) < self.run_timeout_ms)
+    }
+
+    // The timecode while it's being followed, None once quarter frames stop
+    pub fn status(&self, now: u32) -> Option<Timecode> {
+        self.timecode().filter(|_| self.is_running(now))
+    }
+
+    // Feeds every USB-MIDI packet
+    pub fn packet(&mut self, packet: [u8; 4], now: u32) {
+        let previous = self.decoder.timecode();
+        match self.decoder.packet(packet) {
+            Some(MtcEvent::QuarterFrame) => self.last_quarter_frame = Some(now),
+            Some(MtcEvent::Frame(timecode)) => {
+                self.last_quarter_frame = Some(now);
+                if let Some(previous) = previous {
+                    for (interval, fired) in self.intervals.iter().zip(self.fired.iter_mut()) {
+                        if interval.elapsed(&previous, &timecode) {
+                            *fired = Some(now);
+                        }
+                    }
+                }
+            }
+            Some(MtcEvent::Located(_)) | None => {}
+        }
+    }
+
+    pub fn render(&self, now: u32, cv_panel: &mut CvPanel) {
+        let level = |high| if high { GATE_ON } else { GATE_OFF };
+        for (index, fired) in self.fired.iter().enumerate() {
+            let triggered = matches!(fired, Some(at) if now.wrapping_sub(*at) < TRIGGER_MS);
+            cv_panel
+                .output(Output::TimecodeTrigger(index as u8))
+                .set_millivolts(level(triggered));
+        }
+        cv_panel
+            .output(Output::TimecodeRun)
+            .set_millivolts(level(self.is_running(now)));
+    }
+}
+
+impl Default for TimecodeFollower {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A quarter frame of the given piece, 0 to 7, as a USB-MIDI packet
+    fn quarter_frame(piece: u8, nibble: u8) -> [u8; 4] {
+        [0x02, 0xF1, piece << 4 | nibble, 0]
+    }
+
+    #[test]
+    fn adjusting_the_run_gate() {
+        let mut follower = TimecodeFollower::new();
+        follower.adjust(TimecodeParam::RunTimeout, 2);
+        assert_eq!(follower.run_timeout(), 250);
+        follower.adjust(TimecodeParam::RunTimeout, -100);
+        assert_eq!(follower.run_timeout(), RUN_TIMEOUT_STEP_MS);
+        follower.adjust(TimecodeParam::RunTimeout, 100);
+        assert_eq!(follower.run_timeout(), MAX_RUN_TIMEOUT_MS);
+
+        follower.set_run_timeout(100);
+        follower.packet(quarter_frame(0, 0), 10);
+        assert!(follower.is_running(109));
+        assert!(!follower.is_running(110));
+    }
+
+    #[test]
+    fn adjusting_the_triggers() {
+        let mut follower = TimecodeFollower::new();
+        let mut param = TimecodeParam::Interval(0);
+        follower.adjust(param, 1);
+        assert_eq!(follower.interval(0), Interval::Minute);
+        follower.adjust(param, 1);
+        assert_eq!(follower.interval(0), Interval::Minute);
+        param = param.next();
+        follower.adjust(param, -1);
+        assert_eq!(follower.interval(1), Interval::Frame);
+        assert_eq!(param.next(), TimecodeParam::RunTimeout);
+        assert_eq!(param.next().next(), TimecodeParam::Interval(0));
+    }
+
+    #[test]
+    fn status_clears_when_quarter_frames_stop() {
+        let mut follower = TimecodeFollower::new();
+        follower.set_run_timeout(100);
+        // 00:00:01:00 at 30fps
+        let nibbles = [0, 0, 1, 0, 0, 0, 0, 3 << 1];
+        for (piece, nibble) in nibbles.iter().enumerate() {
+            assert_eq!(follower.status(10), None);
+            follower.packet(quarter_frame(piece as u8, *nibble), 10);
+        }
+        let status = follower.timecode();
+        assert!(status.is_some());
+        assert_eq!(follower.status(109), status);
+        assert_eq!(follower.status(110), None);
+    }
+}
@@ -0,0 +1,280 @@
+use crate::learn::{Learn, LearnEvent};
+use crate::midi::message::MidiMessage;
+
+// Holding select this long without turning moves to the next page
+const LONG_PRESS_MS: u32 = 600;
+// Select has to sit still this long before a change counts
+const DEBOUNCE_MS: u32 = 10;
+// Edits wait this long after the last input before they're saved
+const SAVE_DELAY_MS: u32 = 2000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Page {
+    Mode,
+    Glide,
+    Envelope,
+    Scale,
+    Chord,
+    Arp,
+    Sequencer,
+    Lfo,
+    Tempo,
+    Swing,
+    Transport,
+    Clock,
+    Timecode,
+    Outputs,
+    Calibrate,
+}
+
+impl Page {
+    fn next(self) -> Self {
+        match self {
+            Page::Mode => Page::Glide,
+            Page::Glide => Page::Envelope,
+            Page::Envelope => Page::Scale,
+            Page::Scale => Page::Chord,
+            Page::Chord => Page::Arp,
+            Page::Arp => Page::Sequencer,
+            Page::Sequencer => Page::Lfo,
+            Page::Lfo => Page::Tempo,
+            Page::Tempo => Page::Swing,
+            Page::Swing => Page::Transport,
+            Page::Transport => Page::Clock,
+            Page::Clock => Page::Timecode,
+            Page::Timecode => Page::Outputs,
+            Page::Outputs => Page::Calibrate,
+            Page::Calibrate => Page::Mode,
+        }
+    }
+
+    // What the status display calls it, eleven characters at most
+    pub fn name(self) -> &'static str {
+        match self {
+            Page::Mode => "Mode",
+            Page::Glide => "Glide",
+            Page::Envelope => "Envelope",
+            Page::Scale => "Scale",
+            Page::Chord => "Chord",
+            Page::Arp => "Arp",
+            Page::Sequencer => "Sequencer",
+            Page::Lfo => "LFO",
+            Page::Tempo => "Tempo",
+            Page::Swing => "Swing",
+            Page::Transport => "Transport",
+            Page::Clock => "Clock",
+            Page::Timecode => "Timecode",
+            Page::Outputs => "Outputs",
+            Page::Calibrate => "Calibrate",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum UiEvent {
+    Learn(LearnEvent),
+    Page(Page),
+    // encoder turned with select up
+    Turn(Page, i32),
+    // select pressed and let go quickly, with when it went down
+    Press(Page, u32),
+}
+
+// The encoder is the whole front panel. Turning adjusts the current page, a
+// short press acts on it and a long press moves to the next page. Holding
+// select while turning is MIDI learn, which then owns the button until done.
+pub struct Ui {
+    learn: Learn,
+    page: Page,
+    // select as read, when it last changed, and once it settled
+    raw_select: bool,
+    raw_since: u32,
+    select: bool,
+    // when select went down, unless learn took the press
+    pressed_at: Option<u32>,
+    last_input: u32,
+    // the page with edits not yet saved
+    unsaved: Option<Page>,
+}
+
+impl Ui {
+    pub fn new() -> Self {
+        Self {
+            learn: Learn::new(),
+            page: Page::Mode,
+            raw_select: false,
+            raw_since: 0,
+            select: false,
+            pressed_at: None,
+            last_input: 0,
+            unsaved: None,
+        }
+    }
+
+    pub fn page(&self) -> Page {
+        self.page
+    }
+
+    pub fn capture(&mut self, message: MidiMessage) -> Option<LearnEvent> {
+        self.learn.capture(message)
+    }
+
+    // Every save programs flash, and an erase stalls everything, so a page's
+    // edits are saved once the panel is left alone or the page changes
+    // rather than on every detent
+    pub fn edited(&mut self) {
+        self.unsaved = Some(self.page);
+    }
+
+    pub fn save_due(&mut self, now: u32) -> bool {
+        let due = match self.unsaved {
+            Some(page) => page != self.page || now.wrapping_sub(self.last_input) >= SAVE_DELAY_MS,
+            None => false,
+        };
+        if due {
+            self.unsaved = None;
+        }
+        due
+    }
+
+    // Contacts bounce for a few milliseconds either way. A change only counts
+    // once it has settled, but dates from the edge that started it.
+    fn debounce(&mut self, pressed: bool, now: u32) -> bool {
+        if pressed != self.raw_select {
+            self.raw_select = pressed;
+            self.raw_since = now;
+        }
+        if self.raw_select != self.select && now.wrapping_sub(self.raw_since) >= DEBOUNCE_MS {
+            self.select = self.raw_select;
+        }
+        self.select
+    }
+
+    pub fn update(&mut self, pressed: bool, steps: i32, now: u32) -> Option<UiEvent> {
+        if pressed || steps != 0 {
+            self.last_input = now;
+        }
+        let pressed = self.debounce(pressed, now);
+        let was_idle = self.learn.is_idle();
+        if let Some(event) = self.learn.update(pressed, steps, now) {
+            self.pressed_at = None;
+            return Some(UiEvent::Learn(event));
+        }
+        // presses while armed belong to learn
+        if !was_idle && self.pressed_at.is_none() {
+            return None;
+        }
+
+        match self.pressed_at {
+            None if pressed => self.pressed_at = Some(self.raw_since),
+            Some(at) if !pressed => {
+                self.pressed_at = None;
+                if self.raw_since.wrapping_sub(at) >= LONG_PRESS_MS {
+                    self.page = self.page.next();
+                    return Some(UiEvent::Page(self.page));
+                }
+                return Some(UiEvent::Press(self.page, at));
+            }
+            _ => {}
+        }
+        if !pressed && steps != 0 {
+            return Some(UiEvent::Turn(self.page, steps));
+        }
+        None
+    }
+}
+
+impl Default for Ui {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Holds select from one time to another, returning what letting go did
+    fn hold(ui: &mut Ui, from: u32, to: u32) -> Option<UiEvent> {
+        assert_eq!(ui.update(true, 0, from), None);
+        assert_eq!(ui.update(true, 0, from + DEBOUNCE_MS), None);
+        assert_eq!(ui.update(false, 0, to), None);
+        ui.update(false, 0, to + DEBOUNCE_MS)
+    }
+
+    #[test]
+    fn edits_save_after_a_quiet_spell() {
+        let mut ui = Ui::new();
+        assert!(!ui.save_due(5_000));
+        for now in 1..100 {
+            assert_eq!(ui.update(false, 1, now), Some(UiEvent::Turn(Page::Mode, 1)));
+            ui.edited();
+            assert!(!ui.save_due(now));
+        }
+        assert!(!ui.save_due(99 + SAVE_DELAY_MS - 1));
+        assert!(ui.save_due(99 + SAVE_DELAY_MS));
+        // once
+        assert!(!ui.save_due(99 + SAVE_DELAY_MS + 1));
+    }
+
+    #[test]
+    fn input_holds_the_save_off() {
+        let mut ui = Ui::new();
+        ui.update(false, 1, 10);
+        ui.edited();
+        // pressing down counts, even before it's a press
+        ui.update(true, 0, 1_500);
+        assert!(!ui.save_due(2_500));
+        ui.update(false, 0, 1_600);
+        assert!(ui.save_due(3_500));
+    }
+
+    #[test]
+    fn changing_page_saves() {
+        let mut ui = Ui::new();
+        ui.update(false, -1, 10);
+        ui.edited();
+        assert_eq!(
+            hold(&mut ui, 20, 20 + LONG_PRESS_MS),
+            Some(UiEvent::Page(Page::Glide))
+        );
+        assert!(ui.save_due(21 + DEBOUNCE_MS + LONG_PRESS_MS));
+    }
+
+    #[test]
+    fn short_and_long_presses() {
+        let mut ui = Ui::new();
+        // a press is timed from when select went down
+        assert_eq!(
+            hold(&mut ui, 100, 200),
+            Some(UiEvent::Press(Page::Mode, 100))
+        );
+        assert_eq!(
+            hold(&mut ui, 300, 300 + LONG_PRESS_MS),
+            Some(UiEvent::Page(Page::Glide))
+        );
+    }
+
+    #[test]
+    fn select_is_debounced() {
+        let mut ui = Ui::new();
+        // bouncing on the way down and up
+        for now in 100..105 {
+            assert_eq!(ui.update(now % 2 == 0, 0, now), None);
+        }
+        assert_eq!(ui.update(true, 0, 105 + DEBOUNCE_MS - 1), None);
+        assert_eq!(ui.update(true, 0, 105 + DEBOUNCE_MS), None);
+        assert_eq!(ui.update(false, 0, 200), None);
+        assert_eq!(ui.update(true, 0, 201), None);
+        assert_eq!(ui.update(false, 0, 202), None);
+        assert_eq!(ui.update(false, 0, 202 + DEBOUNCE_MS - 1), None);
+        assert_eq!(
+            ui.update(false, 0, 202 + DEBOUNCE_MS),
+            Some(UiEvent::Press(Page::Mode, 104))
+        );
+        // a blip never becomes a press
+        ui.update(true, 0, 300);
+        ui.update(false, 0, 305);
+        assert_eq!(ui.update(false, 0, 400), None);
+    }
+}
@@ -19,6 +19,8 @@ pub const MIDI_OUT_JACK: u8 = 0x03;
 pub const MIDI_OUT_JACK_SIZE: usize = 9;
 pub const ELEMENT: u8 = 0x04;
 pub const MS_GENERAL: u8 = 0x01;
+pub const MS_ENDPOINT_SIZE: usize = 5;
+pub const ENDPOINT_SIZE: usize = 7;
 
 // MIDI jack types
 pub const EMBEDDED: u8 = 0x01;
@@ -6,6 +6,7 @@ pub struct MidiClass<'a, B: UsbBus> {
     audio_control_interface: InterfaceNumber,
     midi_streaming_interface: InterfaceNumber,
     midi_in: EndpointOut<'a, B>,
+    midi_out: EndpointIn<'a, B>,
 }
 
 impl<'a, B: UsbBus> MidiClass<'a, B> {
@@ -14,13 +15,17 @@ impl<'a, B: UsbBus> MidiClass<'a, B> {
             audio_control_interface: alloc.interface(),
             midi_streaming_interface: alloc.interface(),
             midi_in: alloc.bulk(64),
+            midi_out: alloc.bulk(64),
         }
     }
 
-    pub fn read_packet(&self) -> Result<[u8; 4]> {
-        let mut buf = [0; 4];
-        self.midi_in.read(&mut buf)?;
-        Ok(buf)
+    // A USB packet can carry up to sixteen 4-byte event packets
+    pub fn read_packets(&self, buf: &mut [u8; 64]) -> Result<usize> {
+        self.midi_in.read(buf)
+    }
+
+    pub fn write_packets(&self, packets: &[u8]) -> Result<usize> {
+        self.midi_out.write(packets)
     }
 }
 
@@ -53,7 +58,11 @@ impl<B: UsbBus> UsbClass<B> for MidiClass<'_, B> {
             0,
         )?;
 
-        let total_len = MS_HEADER_SIZE + MIDI_OUT_JACK_SIZE;
+        // header, jacks, and both endpoints with their class descriptors
+        let total_len = MS_HEADER_SIZE
+            + 2 * MIDI_IN_JACK_SIZE
+            + MIDI_OUT_JACK_SIZE
+            + 2 * (ENDPOINT_SIZE + MS_ENDPOINT_SIZE);
         writer.write(
             CS_INTERFACE,
             &[
@@ -75,6 +84,30 @@ impl<B: UsbBus> UsbClass<B> for MidiClass<'_, B> {
             ],
         )?;
 
+        // Messages to the host come from an external jack through an
+        // embedded out jack
+        writer.write(
+            CS_INTERFACE,
+            &[
+                MIDI_IN_JACK,
+                EXTERNAL,
+                0x03, // jack id
+                0x00, // unused
+            ],
+        )?;
+        writer.write(
+            CS_INTERFACE,
+            &[
+                MIDI_OUT_JACK,
+                EMBEDDED,
+                0x02, // jack id
+                0x01, // number of input pins
+                0x03, // source jack id
+                0x01, // source pin
+                0x00, // unused
+            ],
+        )?;
+
         writer.endpoint(&self.midi_in)?;
         writer.write(
             CS_ENDPOINT,
@@ -85,6 +118,16 @@ impl<B: UsbBus> UsbClass<B> for MidiClass<'_, B> {
             ],
         )?;
 
+        writer.endpoint(&self.midi_out)?;
+        writer.write(
+            CS_ENDPOINT,
+            &[
+                MS_GENERAL, // MIDI general endpoint
+                0x01,       // number of embedded jacks
+                0x02,       // id of embedded jack
+            ],
+        )?;
+
         rprintln!("Done with descriptors");
         Ok(())
     }
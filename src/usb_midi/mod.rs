@@ -0,0 +1,2 @@
+mod descriptors;
+pub mod device;
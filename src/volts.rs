@@ -0,0 +1,146 @@
+const FULL_SCALE_CODE: i64 = 4095;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Millivolts(pub i32);
+
+#[derive(Clone, Copy, PartialEq, PartialOrd, Debug)]
+pub struct Volts(pub f32);
+
+impl From<Volts> for Millivolts {
+    fn from(volts: Volts) -> Self {
+        let mv = volts.0 * 1000.0;
+        Millivolts(if mv < 0.0 { mv - 0.5 } else { mv + 0.5 } as i32)
+    }
+}
+
+// The voltage an output produces at DAC code 0 and at full scale, after any
+// op-amp scaling on the panel. An inverted range has zero above full scale.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct OutputRange {
+    zero: Millivolts,
+    full_scale: Millivolts,
+}
+
+impl OutputRange {
+    pub const UNIPOLAR_5V: Self = Self::new(Millivolts(0), Millivolts(5000));
+    pub const UNIPOLAR_10V: Self = Self::new(Millivolts(0), Millivolts(10000));
+    pub const BIPOLAR_5V: Self = Self::new(Millivolts(-5000), Millivolts(5000));
+
+    pub const fn new(zero: Millivolts, full_scale: Millivolts) -> Self {
+        Self { zero, full_scale }
+    }
+
+    pub const fn inverted(self) -> Self {
+        Self {
+            zero: self.full_scale,
+            full_scale: self.zero,
+        }
+    }
+
+    pub fn zero(self) -> Millivolts {
+        self.zero
+    }
+
+    pub fn full_scale(self) -> Millivolts {
+        self.full_scale
+    }
+
+    // Through the ranges the outputs page offers
+    pub fn step(self, steps: i32) -> Self {
+        const RANGES: [OutputRange; 5] = [
+            OutputRange::UNIPOLAR_10V,
+            OutputRange::UNIPOLAR_5V,
+            OutputRange::BIPOLAR_5V,
+            OutputRange::UNIPOLAR_10V.inverted(),
+            OutputRange::UNIPOLAR_5V.inverted(),
+        ];
+        let index = RANGES.iter().position(|range| *range == self).unwrap_or(0) as i32;
+        RANGES[(index + steps).clamp(0, RANGES.len() as i32 - 1) as usize]
+    }
+
+    pub fn min(self) -> Millivolts {
+        self.zero.min(self.full_scale)
+    }
+
+    pub fn max(self) -> Millivolts {
+        self.zero.max(self.full_scale)
+    }
+
+    // Voltages outside the range clamp to the nearest end
+    pub fn to_code(self, mv: Millivolts) -> u16 {
+        let span = (self.full_scale.0 - self.zero.0) as i64;
+        if span == 0 {
+            return 0;
+        }
+        let mv = mv.clamp(self.min(), self.max());
+        let scaled = (mv.0 - self.zero.0) as i64 * FULL_SCALE_CODE;
+        // round to nearest, works for either sign of span
+        let code = (scaled * 2 + span) / (span * 2);
+        code.clamp(0, FULL_SCALE_CODE) as u16
+    }
+
+    pub fn to_millivolts(self, code: u16) -> Millivolts {
+        let code = (code as i64).min(FULL_SCALE_CODE);
+        let span = (self.full_scale.0 - self.zero.0) as i64;
+        Millivolts(self.zero.0 + (code * span / FULL_SCALE_CODE) as i32)
+    }
+}
+
+impl Default for OutputRange {
+    fn default() -> Self {
+        Self::UNIPOLAR_10V
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_round_to_nearest() {
+        let range = OutputRange::UNIPOLAR_10V;
+        assert_eq!(range.to_code(Millivolts(0)), 0);
+        assert_eq!(range.to_code(Millivolts(10_000)), 4095);
+        assert_eq!(range.to_code(Millivolts(5_000)), 2048);
+        assert_eq!(range.to_millivolts(2048), Millivolts(5_001));
+    }
+
+    #[test]
+    fn out_of_range_clamps() {
+        let range = OutputRange::UNIPOLAR_5V;
+        assert_eq!(range.to_code(Millivolts(-1_000)), 0);
+        assert_eq!(range.to_code(Millivolts(6_000)), 4095);
+        assert_eq!(range.to_millivolts(5000), Millivolts(5_000));
+    }
+
+    #[test]
+    fn bipolar_and_inverted() {
+        let range = OutputRange::BIPOLAR_5V;
+        assert_eq!(range.to_code(Millivolts(-5_000)), 0);
+        assert_eq!(range.to_code(Millivolts(0)), 2048);
+        let inverted = OutputRange::UNIPOLAR_10V.inverted();
+        assert_eq!(inverted.to_code(Millivolts(10_000)), 0);
+        assert_eq!(inverted.to_code(Millivolts(0)), 4095);
+        assert_eq!(inverted.min(), Millivolts(0));
+        assert_eq!(inverted.max(), Millivolts(10_000));
+    }
+
+    #[test]
+    fn stepping_through_ranges() {
+        let range = OutputRange::default();
+        assert_eq!(range.step(2), OutputRange::BIPOLAR_5V);
+        assert_eq!(range.step(3), OutputRange::UNIPOLAR_10V.inverted());
+        // the ends hold
+        assert_eq!(range.step(-1), OutputRange::UNIPOLAR_10V);
+        assert_eq!(range.step(10), OutputRange::UNIPOLAR_5V.inverted());
+        // a range the page doesn't offer starts from the first
+        let odd = OutputRange::new(Millivolts(-3_000), Millivolts(3_000));
+        assert_eq!(odd.step(1), OutputRange::UNIPOLAR_5V);
+    }
+
+    #[test]
+    fn volts_round_to_millivolts() {
+        assert_eq!(Millivolts::from(Volts(1.2345)), Millivolts(1_235));
+        assert_eq!(Millivolts::from(Volts(-1.2345)), Millivolts(-1_235));
+    }
+}